@@ -33,6 +33,7 @@ global page_directory       ; Page directory (used by Rust memory subsystem)
 global page_table1          ; Boot page table (maps first 4 MB)
 global setup_paging         ; Called from start, also exported for reference
 global multiboot_ptr        ; Multiboot2 info pointer for use in rust
+global enable_pae_paging    ; Called from Rust to switch to PAE paging
 
 ; ---- Imports ----
 extern higher_half_start    ; Defined in boot.asm - runs in virtual space
@@ -139,6 +140,40 @@ setup_paging:
 	ret
 
 
+; -----------------------------------------------------------------------------
+; enable_pae_paging - Switch the running kernel to PAE paging
+;
+; cdecl: [esp + 4] = physical address of the new PDPT.
+;
+; CR4.PAE cannot change while paging is on, so paging goes off for a few
+; instructions.  That is why this lives in .boot: it runs through the
+; identity map, which the new tables must provide as well.  The stack is
+; not touched while paging is off (ESP holds a higher-half address).
+;
+; Called from Rust: extern "C" { fn enable_pae_paging(pdpt_phys: u32); }
+; -----------------------------------------------------------------------------
+enable_pae_paging:
+	mov edx, [esp + 4]                  ; EDX = PDPT physical address
+	pushfd
+	cli
+
+	mov eax, cr0
+	and eax, 0x7FFFFFFF                 ; Clear PG
+	mov cr0, eax
+
+	mov cr3, edx                        ; CR3 = PDPT
+	mov eax, cr4
+	or  eax, 0x20                       ; Set PAE
+	mov cr4, eax
+
+	mov eax, cr0
+	or  eax, 0x80000000                 ; Set PG - PAE tables live now
+	mov cr0, eax
+
+	popfd
+	ret
+
+
 ; =============================================================================
 ; .bss section - Zeroed at load globals
 ; =============================================================================
@@ -12,6 +12,7 @@ use define::*;
 use descriptor::SegmentDescriptor;
 use tss::TssSegment;
 
+use crate::memory::physmap::virt_to_phys;
 use crate::utils::memcpy;
 
 //WARNING : This is not portable for future x64
@@ -41,12 +42,12 @@ extern "C" {
 pub fn init() {
     let tss_addr: u32;
     let tss_limit: u32;
-    let stack_phys = (addr_of!(stack_top) as u32) - KERNEL_VIRTUAL_BASE;
+    let stack_phys = virt_to_phys(addr_of!(stack_top) as usize) as u32;
     unsafe {
         tss::TSS
             .init(stack_phys)
             .expect("Invalid TSS stack address");
-        tss_addr = core::ptr::addr_of!(tss::TSS) as *const TssSegment as u32 - KERNEL_VIRTUAL_BASE;
+        tss_addr = virt_to_phys(core::ptr::addr_of!(tss::TSS) as usize) as u32;
         tss_limit = tss_addr + size_of::<TssSegment>() as u32 - 1;
     }
     dbg_println!("Tss structure initialized...");
@@ -121,7 +122,7 @@ pub fn print() {
 }
 #[cfg(feature = "gdt_test")]
 fn verify_gdt_load_structure() {
-    let tss_addr = core::ptr::addr_of!(tss::TSS) as *const TssSegment as u32 - KERNEL_VIRTUAL_BASE;
+    let tss_addr = virt_to_phys(core::ptr::addr_of!(tss::TSS) as usize) as u32;
     let tss_limit = tss_addr + size_of::<TssSegment>() as u32 - 1;
     let correct_segments: [SegmentDescriptor; GDTSIZE] = [
         SegmentDescriptor::new(0, 0, 0, 0), //Null segment 0x0
@@ -198,7 +199,7 @@ pub fn verify_tss() {
         assert_eq!(tss::TSS.ss0, 0x10, "TSS SS0 not set correctly");
         assert_eq!(
             tss::TSS.esp0,
-            addr_of!(stack_top) as u32 - KERNEL_VIRTUAL_BASE,
+            virt_to_phys(addr_of!(stack_top) as usize) as u32,
             "TSS ESP0 not set correctly"
         );
 
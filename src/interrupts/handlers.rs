@@ -1,6 +1,7 @@
 use crate::{
     keyboard::handle_keyboard_interrupt,
     m_println,
+    memory::{sections, vma, vmm},
     panic::{self, CpuState},
     signals::{self, Signal},
     utils::{inb, send_eoi},
@@ -112,6 +113,17 @@ pub unsafe extern "x86-interrupt" fn page_fault(
     let faulting_address: u32;
     core::arch::asm!("mov {}, cr2", out(reg) faulting_address, options(nostack, nomem));
 
+    // Not-present fault inside a registered VMA: back the page and
+    // retry the faulting instruction.
+    if vma::handle_fault(faulting_address, error_code) {
+        return;
+    }
+
+    // Write to a copy-on-write page: give the writer its own copy.
+    if vmm::handle_cow_fault(faulting_address, error_code) {
+        return;
+    }
+
     // Decode the error code bits into human-readable strings
     let present = if error_code & (1 << 0) != 0 {
         "protection violation"
@@ -153,9 +165,10 @@ pub unsafe extern "x86-interrupt" fn page_fault(
     }
 
     // Show which PDE/PTE the fault maps to - helpful for debugging
-    let pde_index = (faulting_address >> 22) as usize;
-    let pte_index = ((faulting_address >> 12) & 0x3FF) as usize;
-    let page_offset = faulting_address & 0xFFF;
+    let virt = vmm::VirtAddr::new(faulting_address);
+    let pde_index = virt.pde_index();
+    let pte_index = virt.pte_index();
+    let page_offset = virt.page_offset();
     m_println!(
         "  PDE index: {}  PTE index: {}  Page offset: {:#05x}",
         pde_index,
@@ -163,6 +176,46 @@ pub unsafe extern "x86-interrupt" fn page_fault(
         page_offset
     );
 
+    // Kernel image: W^X turns stray writes to code/constants (and jumps
+    // into data) into faults - say which rule was broken.
+    if let Some(section) = sections::section_of(faulting_address as usize) {
+        let verdict = if error_code & (1 << 1) != 0 && !section.flags.is_writable() {
+            "write to read-only kernel section (W^X)"
+        } else if error_code & (1 << 4) != 0 && section.flags.is_no_execute() {
+            "execute from no-execute kernel section (W^X)"
+        } else {
+            "access to kernel section"
+        };
+        m_println!("  {}: {}", verdict, section.name);
+    }
+
+    match vma::find(faulting_address) {
+        Some(area) => m_println!(
+            "  Inside VMA '{}' ({:#010x}..{:#010x}, flags {})",
+            area.name,
+            area.start,
+            area.end,
+            area.flags
+        ),
+        None => m_println!("  Not inside any VMA"),
+    }
+
+    // Guard page next to a vmalloc region: name the allocation that ran
+    // off its end (or start).
+    if let Some((region, side)) = vmm::guard_hit(faulting_address) {
+        let what = match side {
+            vmm::GuardSide::Above => "overrun past the end of",
+            vmm::GuardSide::Below => "underrun before the start of",
+        };
+        m_println!(
+            "  Guard page hit: {} vmalloc region '{}' ({:#010x}..{:#010x})",
+            what,
+            region.owner,
+            region.start,
+            region.end()
+        );
+    }
+
     kernel_panic("Unrecoverable page fault", stack_frame);
 }
 
@@ -0,0 +1,239 @@
+// memory/addrspace.rs - Per-process address spaces
+//
+// An AddressSpace owns a page directory of its own.  Layout of every
+// directory:
+//
+//   PDE[0..768]     user half - private to this address space
+//   PDE[768..1022]  kernel half - copied from the boot page directory
+//   PDE[1022]       foreign window - empty at rest (see vmm::with_tables)
+//   PDE[1023]       recursive slot - points to this directory
+//
+// Under PAE it owns a PDPT frame plus four page directories, numbered
+// as one array of 2048 PDEs: user 0..1536, kernel 1536..2040, foreign
+// 2040..2044 and the recursive slots 2044..2048 (see vmm::Layout).
+//
+// The boot `page_directory` is the master copy of the kernel half.
+// vmm::map_page() records every new kernel page table there, and
+// activate() re-copies the kernel PDEs before loading CR3, so kernel
+// mappings created while another address space was active are never
+// missed.
+//
+// Page tables for the user half are allocated on demand by the
+// vmm::*_in() functions, which work whether or not the address space
+// is the active one.
+
+use super::define::PAGE_SIZE;
+use super::kmap::kmap;
+use super::pageflags::PageFlags;
+use super::paging::{self, PageEntry, PAE_DIRS};
+use super::physical::{FrameOwner, PhysFrame};
+use super::vmm::{
+    self, read_pde_at, read_pte_at, with_tables, write_pde_at, MapError, PhysAddr, VirtAddr,
+};
+use crate::{dbg_println, m_print, m_println};
+
+pub struct AddressSpace {
+    // The page directory, or the PDPT under PAE
+    pd_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    // Allocate a new page directory with an empty user half, the shared
+    // kernel half and its own recursive slot.
+    pub fn new() -> Result<Self, MapError> {
+        let l = vmm::layout();
+        let frame = vmm::alloc_frame(FrameOwner::PageTable)?;
+        let pd_phys = frame.start_address() as u32;
+
+        // Directories to fill: the root itself, or four more under PAE
+        let mut dirs = [pd_phys; PAE_DIRS];
+        let release = |dirs: &[u32]| {
+            for &dir in dirs.iter().filter(|&&d| d != pd_phys) {
+                vmm::free_frame(PhysAddr::new(dir));
+            }
+            vmm::free_frame(PhysAddr::new(pd_phys));
+        };
+        if paging::pae_enabled() {
+            for i in 0..PAE_DIRS {
+                match vmm::alloc_frame(FrameOwner::PageTable) {
+                    Ok(dir) => dirs[i] = dir.start_address() as u32,
+                    Err(e) => {
+                        release(&dirs[..i]);
+                        return Err(e);
+                    }
+                }
+            }
+        }
+
+        // Reach each new directory through kmap until its recursive
+        // slot exists.
+        for (d, &dir) in dirs.iter().take(l.slots()).enumerate() {
+            let view = kmap(PhysFrame::containing_address(dir as usize))
+                .inspect_err(|_| release(&dirs))?;
+            let pd = view.as_ptr();
+            for i in 0..l.table_entries {
+                let pde_idx = d * l.table_entries + i;
+                let entry = if pde_idx >= l.recursive_index {
+                    let target = dirs[pde_idx - l.recursive_index];
+                    PageEntry::new(target, PageFlags::PRESENT | PageFlags::WRITABLE)
+                } else if (l.kernel_pde_start..l.foreign_index).contains(&pde_idx) {
+                    super::paging().entry(pde_idx)
+                } else {
+                    PageEntry::empty()
+                };
+                unsafe { paging::write_entry(pd, i, entry) };
+            }
+            // The frame stays allocated - dropping `view` only unmaps it
+        }
+
+        // PDPT entries only take P - RW/US are reserved there
+        if paging::pae_enabled() {
+            let mut pdpt = kmap(frame).inspect_err(|_| release(&dirs))?;
+            pdpt.zero();
+            for (i, &dir) in dirs.iter().enumerate() {
+                unsafe {
+                    paging::write_entry(pdpt.as_ptr(), i, PageEntry::new(dir, PageFlags::PRESENT))
+                };
+            }
+        }
+
+        dbg_println!("AddressSpace: new page directory at {:#x}", pd_phys);
+
+        Ok(AddressSpace { pd_frame: frame })
+    }
+
+    // Physical address of this address space's page directory (the
+    // value loaded into CR3, so the PDPT under PAE).
+    #[inline]
+    pub fn pd_phys(&self) -> u32 {
+        self.pd_frame.start_address() as u32
+    }
+
+    // Is this the address space currently loaded in CR3?
+    #[inline]
+    pub fn is_active(&self) -> bool {
+        vmm::current_pd_phys() == self.pd_phys()
+    }
+
+    // Switch to this address space.  Kernel PDEs are refreshed from the
+    // boot directory first so the kernel half is identical everywhere.
+    pub fn activate(&self) {
+        self.sync_kernel_pdes();
+        unsafe {
+            core::arch::asm!("mov cr3, {}", in(reg) self.pd_phys(), options(nostack));
+        }
+    }
+
+    // Copy the kernel PDEs (768..1022) from the boot page directory.
+    fn sync_kernel_pdes(&self) {
+        let l = vmm::layout();
+        with_tables(self.pd_phys(), |t| unsafe {
+            for i in l.kernel_pde_start..l.foreign_index {
+                write_pde_at(t, i, super::paging().entry(i));
+            }
+        });
+    }
+
+    // Release every user page, every user page table and the directory
+    // itself.  Must not be called on the active address space.
+    pub fn destroy(self) {
+        assert!(
+            !self.is_active(),
+            "AddressSpace: cannot destroy the active address space"
+        );
+
+        let l = vmm::layout();
+        let pd_phys = self.pd_phys();
+        let mut pages = 0;
+        let mut tables = 0;
+
+        with_tables(pd_phys, |t| unsafe {
+            for pde_idx in 0..l.kernel_pde_start {
+                let pde = read_pde_at(t, pde_idx);
+                if !pde.present() {
+                    continue;
+                }
+                if pde.page_size_4mb() {
+                    // Large page: no table behind it, just its frames
+                    write_pde_at(t, pde_idx, PageEntry::empty());
+                    vmm::free_frames(PhysAddr::new(pde.address()), l.large_page_frames());
+                    pages += l.large_page_frames();
+                    continue;
+                }
+                for pte_idx in 0..l.table_entries {
+                    let pte = read_pte_at(t, pde_idx, pte_idx);
+                    if pte.present() {
+                        vmm::free_frame(PhysAddr::new(pte.address()));
+                        pages += 1;
+                    }
+                }
+                write_pde_at(t, pde_idx, PageEntry::empty());
+                vmm::free_frame(PhysAddr::new(pde.address()));
+                tables += 1;
+            }
+        });
+
+        if paging::pae_enabled() {
+            for dir in vmm::pdpt_dirs(pd_phys) {
+                vmm::free_frame(PhysAddr::new(dir));
+            }
+        }
+        vmm::free_frame(PhysAddr::new(pd_phys));
+
+        dbg_println!(
+            "AddressSpace: destroyed {:#x} ({} pages, {} page tables)",
+            pd_phys,
+            pages,
+            tables
+        );
+    }
+}
+
+// Switch back to the boot (kernel-only) page directory.
+pub fn activate_kernel() {
+    let pd_phys = super::paging().physical_address();
+    unsafe {
+        core::arch::asm!("mov cr3, {}", in(reg) pd_phys, options(nostack));
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Self-test
+// ---------------------------------------------------------------------------
+
+pub fn test_address_space() {
+    m_print!("[AddressSpace test] map in inactive space, switch, isolate ... ");
+
+    let user_virt = VirtAddr::new(0x4000_0000);
+    let flags = PageFlags::PRESENT | PageFlags::WRITABLE | PageFlags::USER;
+
+    let space = AddressSpace::new().expect("AddressSpace::new failed");
+    assert!(!space.is_active());
+
+    // Map while the boot directory is active
+    let phys = vmm::map_alloc_in(&space, user_virt, flags).expect("map_alloc_in failed");
+    assert_eq!(vmm::translate_in(&space, user_virt), Some(phys));
+    assert!(
+        !vmm::is_mapped(user_virt),
+        "mapping leaked into the boot address space"
+    );
+
+    // Switch, write through the new mapping, switch back
+    space.activate();
+    assert!(space.is_active());
+    unsafe {
+        (user_virt.0 as *mut u32).write_volatile(0x5EED_CAFE);
+        assert_eq!((user_virt.0 as *const u32).read_volatile(), 0x5EED_CAFE);
+    }
+    // Kernel half must still be reachable from here
+    assert!(vmm::is_mapped(VirtAddr::new(
+        super::define::KERNEL_HEAP_START as u32
+    )));
+    activate_kernel();
+
+    assert!(!vmm::is_mapped(user_virt));
+    assert_eq!(vmm::unmap_range_in(&space, user_virt, PAGE_SIZE), Ok(1));
+    space.destroy();
+
+    m_println!("OK");
+}
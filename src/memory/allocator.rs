@@ -1,7 +1,11 @@
 // memory/allocator.rs - GlobalAlloc implementation for the kernel heap
 //
 // Bridges Rust's `alloc` crate (Box, Vec, String, etc.) to our
-// kmalloc/kfree free-list allocator.
+// kmalloc/kfree free-list allocator.  Layouts up to KMALLOC_MAX_SIZE go
+// to the general slab caches instead (kmalloc-8 .. kmalloc-1024), which
+// keeps small, short-lived objects off the first-fit list.
+// realloc() resizes heap blocks in place where heap::krealloc() can and
+// only falls back to allocate-copy-free when it can't.
 // Forward-compatibility for user space:
 //   This module only handles kernel-side allocation.  When user-space
 //   processes arrive, each process will have its own heap region and
@@ -12,6 +16,9 @@
 //   in a process-aware allocator later is straightforward.
 
 use super::heap;
+use super::slab;
+#[cfg(feature = "alloc_track")]
+use super::tracking;
 use core::alloc::{GlobalAlloc, Layout};
 
 // ---------------------------------------------------------------------------
@@ -30,6 +37,38 @@ pub struct KernelAllocator;
 
 unsafe impl GlobalAlloc for KernelAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.alloc_untracked(layout);
+        #[cfg(feature = "alloc_track")]
+        if !ptr.is_null() && layout.size() != 0 {
+            tracking::record_alloc(ptr, layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "alloc_track")]
+        if layout.size() != 0 {
+            tracking::record_free(ptr);
+        }
+        self.dealloc_untracked(ptr, layout);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = self.realloc_untracked(ptr, layout, new_size);
+        #[cfg(feature = "alloc_track")]
+        if !new.is_null() {
+            if layout.size() == 0 {
+                tracking::record_alloc(new, new_size);
+            } else {
+                tracking::record_realloc(ptr, new, new_size);
+            }
+        }
+        new
+    }
+}
+
+impl KernelAllocator {
+    unsafe fn alloc_untracked(&self, layout: Layout) -> *mut u8 {
         let size = layout.size();
         let align = layout.align();
 
@@ -40,6 +79,15 @@ unsafe impl GlobalAlloc for KernelAllocator {
             return align as *mut u8;
         }
 
+        if size <= slab::KMALLOC_MAX_SIZE && align <= slab::KMALLOC_MAX_SIZE {
+            let ptr = slab::kmem_alloc(size, align);
+            if !ptr.is_null() {
+                return ptr;
+            }
+            // Caches not set up yet, or no frame for a new slab: the
+            // heap may still have room.
+        }
+
         if align <= KMALLOC_ALIGN {
             return heap::kmalloc(size);
         }
@@ -74,12 +122,17 @@ unsafe impl GlobalAlloc for KernelAllocator {
         aligned_addr as *mut u8
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    unsafe fn dealloc_untracked(&self, ptr: *mut u8, layout: Layout) {
         if layout.size() == 0 {
             // Matches the zero-size sentinel from alloc() - nothing to free.
             return;
         }
 
+        if slab::is_slab_object(ptr) {
+            slab::kmem_free(ptr);
+            return;
+        }
+
         if layout.align() <= KMALLOC_ALIGN {
             // Fast path: ptr came directly from kmalloc.
             heap::kfree(ptr);
@@ -92,6 +145,40 @@ unsafe impl GlobalAlloc for KernelAllocator {
         let original = stash.read() as *mut u8;
         heap::kfree(original);
     }
+
+    unsafe fn realloc_untracked(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        if layout.size() == 0 {
+            // Zero-size sentinel: there is nothing to resize
+            return self.alloc_untracked(new_layout);
+        }
+
+        if slab::is_slab_object(ptr) {
+            // Objects don't change cache; keep it while it still fits
+            if new_size <= slab::kmem_size(ptr) {
+                return ptr;
+            }
+        } else if layout.align() <= KMALLOC_ALIGN {
+            return heap::krealloc(ptr, new_size);
+        } else {
+            // The aligned pointer sits `offset` bytes into the kmalloc
+            // block; resizing that block in place keeps it aligned.
+            let stash = (ptr as usize - core::mem::size_of::<usize>()) as *const usize;
+            let original = stash.read();
+            let offset = ptr as usize - original;
+            if heap::kresize(original as *mut u8, offset + new_size) {
+                return ptr;
+            }
+        }
+
+        // Can't resize in place: move to a new allocation
+        let new = self.alloc_untracked(new_layout);
+        if !new.is_null() {
+            core::ptr::copy_nonoverlapping(ptr, new, layout.size().min(new_size));
+            self.dealloc_untracked(ptr, layout);
+        }
+        new
+    }
 }
 
 // ---------------------------------------------------------------------------
@@ -118,7 +205,6 @@ pub fn test_global_alloc() {
     extern crate alloc;
     use alloc::boxed::Box;
     use alloc::string::String;
-    use alloc::vec;
     use alloc::vec::Vec;
 
     println!("\n=== GlobalAlloc Self-Test ===\n");
@@ -156,10 +242,10 @@ pub fn test_global_alloc() {
     }
     println!("OK");
 
-    // Test 4: vec! macro (uses alloc internally)
-    print!("[Alloc test 4] vec! macro ... ");
+    // Test 4: Vec built from an array (copied into a heap buffer)
+    print!("[Alloc test 4] Vec from an array ... ");
     {
-        let v = vec![1u8, 2, 3, 4, 5];
+        let v = Vec::from([1u8, 2, 3, 4, 5]);
         assert_eq!(v.len(), 5);
         assert_eq!(v.iter().sum::<u8>(), 15);
     }
@@ -196,5 +282,35 @@ pub fn test_global_alloc() {
     }
     println!("OK");
 
+    // Test 7: realloc of a slab object and of an over-aligned block
+    print!("[Alloc test 7] realloc keeps data and alignment ... ");
+    unsafe {
+        // 24 bytes come from kmalloc-32: growing to 30 stays put
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        let ptr = alloc::alloc::alloc(layout);
+        assert!(!ptr.is_null());
+        ptr.write_bytes(0x5A, 24);
+        assert_eq!(alloc::alloc::realloc(ptr, layout, 30), ptr);
+        let layout = Layout::from_size_align(30, 8).unwrap();
+        let moved = alloc::alloc::realloc(ptr, layout, 2000);
+        assert!(!moved.is_null());
+        assert!((0..24).all(|i| *moved.add(i) == 0x5A));
+        alloc::alloc::dealloc(moved, Layout::from_size_align(2000, 8).unwrap());
+
+        // Page alignment is past the slab caches: stash-pointer block
+        let layout = Layout::from_size_align(256, 4096).unwrap();
+        let ptr = alloc::alloc::alloc(layout);
+        assert!(!ptr.is_null());
+        ptr.write_bytes(0xA5, 256);
+        let grown = alloc::alloc::realloc(ptr, layout, 8192);
+        assert!(!grown.is_null());
+        assert_eq!(grown as usize % 4096, 0, "realloc lost the alignment");
+        assert!((0..256).all(|i| *grown.add(i) == 0xA5));
+        let layout = Layout::from_size_align(8192, 4096).unwrap();
+        assert_eq!(alloc::alloc::realloc(grown, layout, 64), grown);
+        alloc::alloc::dealloc(grown, Layout::from_size_align(64, 4096).unwrap());
+    }
+    println!("OK");
+
     println!("\n=== GlobalAlloc Self-Test PASSED ===\n");
 }
@@ -10,19 +10,68 @@ pub const PAGE_TABLE_ENTRIES: usize = 1024;
 pub const PAGE_DIRECTORY_ENTRIES: usize = 1024;
 pub const KERNEL_OFFSET: usize = 0xC0000000; // Higher half kernel offset
 
+// The first 4 MB of RAM, mapped at KERNEL_OFFSET by bootstrap.asm.
+pub const BOOT_MAPPED_LIMIT: usize = 0x0040_0000;
+
+// ---------------------------------------------------------------------------
+// Physical memory direct map (physmap)
+//
+// RAM below PHYSMAP_SIZE is mapped linearly at KERNEL_OFFSET + phys (see
+// physmap.rs).  It runs up to the heap window, so the heap and vmalloc
+// windows sit above the largest RAM the physmap can cover.
+// ---------------------------------------------------------------------------
+pub const PHYSMAP_SIZE: usize = KERNEL_HEAP_START - KERNEL_OFFSET; // 240 MB
+
 // ---------------------------------------------------------------------------
 // Kernel heap region
 //
-// Placed at 0xC1000000 - well above the kernel image and frame-allocator
-// bitmap, but below the recursive mapping region (0xFFC00000).
+// Placed at 0xCF000000 - just above the physmap and right below the
+// vmalloc window (whose first page is a guard).
 //
 // 16 MB is generous for the kernel heap; the initial mapping only covers
 // KERNEL_HEAP_INITIAL_SIZE and the allocator can grow on demand up to
 // KERNEL_HEAP_END by calling vmm::map_alloc().
+//
+// The global allocator relies on this layout: slab objects are the only
+// thing it hands out from the physmap, so slab::is_slab_object() tells a
+// slab object from a heap block by address alone.  Keep the heap (and
+// any future allocator backend) outside the physmap.
 // ---------------------------------------------------------------------------
-pub const KERNEL_HEAP_START: usize = 0xC100_0000;
-pub const KERNEL_HEAP_END: usize = 0xC200_0000; // 16 MB total capacity
+pub const KERNEL_HEAP_START: usize = 0xCF00_0000;
+pub const KERNEL_HEAP_END: usize = 0xD000_0000; // 16 MB total capacity
 pub const KERNEL_HEAP_SIZE: usize = KERNEL_HEAP_END - KERNEL_HEAP_START;
 // How much of the heap to pre-map at boot (128 KB - 32 pages).
 // The rest is mapped lazily as the allocator grows.
 pub const KERNEL_HEAP_INITIAL_SIZE: usize = 128 * 1024;
+
+// ---------------------------------------------------------------------------
+// vmalloc window
+//
+// vmm::vmalloc() hands out kernel virtual ranges from here, each one
+// fenced by an unmapped guard page on both sides.  512 MB, well clear of
+// the heap below and the kmap/foreign/recursive slots at the top.
+// ---------------------------------------------------------------------------
+pub const VMALLOC_START: usize = 0xD000_0000;
+pub const VMALLOC_END: usize = 0xF000_0000;
+
+// Pages in the kmap window (see kmap.rs) - at most 32, one bit each.
+pub const KMAP_SLOTS: usize = 16;
+
+// ---------------------------------------------------------------------------
+// Physical memory zones
+//
+// ZONE_DMA covers what legacy ISA DMA controllers can address (24-bit,
+// below 16 MB).  ZONE_NORMAL runs up to the end of the physmap, the
+// physical range the kernel reaches linearly through KERNEL_OFFSET.
+// Anything above that is ZONE_HIGH and must be mapped explicitly (kmap)
+// before the kernel can touch it.
+// ---------------------------------------------------------------------------
+pub const ZONE_DMA_END: usize = 0x0100_0000; // 16 MB
+
+// The direct-mapped limit: Zone::Normal is exactly the RAM physmap.rs
+// maps, so a Normal frame is always reachable via phys_to_virt().
+pub const ZONE_NORMAL_END: usize = PHYSMAP_SIZE;
+
+// Normal must be a real zone above DMA, and never reach past the physmap
+const _: () = assert!(ZONE_DMA_END < ZONE_NORMAL_END);
+const _: () = assert!(ZONE_NORMAL_END <= PHYSMAP_SIZE);
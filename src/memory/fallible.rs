@@ -0,0 +1,111 @@
+// memory/fallible.rs - Box and Vec helpers that report out-of-memory
+//
+// Box::new, Vec::push and the rest call the alloc error handler when
+// the allocator returns null, and that panics.  A subsystem that can
+// recover (drop a packet, refuse a command, retry later) builds its
+// buffers with these helpers instead and gets Err(OutOfMemory) back.
+//
+// They go through the global allocator like the infallible versions,
+// so the memory comes from the slab caches or the heap as usual and is
+// released with a plain drop.
+
+use alloc::alloc::{alloc, Layout};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use crate::{m_print, m_println};
+
+// An allocation of `size` bytes failed (usize::MAX: the size itself
+// overflowed).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutOfMemory {
+    pub size: usize,
+}
+
+// Box::new(value), or Err if there is no memory for it.
+pub fn try_box<T>(value: T) -> Result<Box<T>, OutOfMemory> {
+    let layout = Layout::new::<T>();
+    if layout.size() == 0 {
+        // Zero-sized: Box::new doesn't allocate
+        return Ok(Box::new(value));
+    }
+
+    let ptr = unsafe { alloc(layout) } as *mut T;
+    if ptr.is_null() {
+        return Err(OutOfMemory {
+            size: layout.size(),
+        });
+    }
+    unsafe {
+        ptr.write(value);
+        Ok(Box::from_raw(ptr))
+    }
+}
+
+// Vec::with_capacity(capacity), or Err if there is no memory for it.
+pub fn try_vec<T>(capacity: usize) -> Result<Vec<T>, OutOfMemory> {
+    let mut vec = Vec::new();
+    try_reserve(&mut vec, capacity)?;
+    Ok(vec)
+}
+
+// Make room for `additional` more elements, like Vec::reserve.
+pub fn try_reserve<T>(vec: &mut Vec<T>, additional: usize) -> Result<(), OutOfMemory> {
+    vec.try_reserve(additional).map_err(|_| OutOfMemory {
+        size: vec
+            .len()
+            .checked_add(additional)
+            .and_then(|n| n.checked_mul(core::mem::size_of::<T>()))
+            .unwrap_or(usize::MAX),
+    })
+}
+
+// vec.push(value), or Err if the Vec is full and can't grow.  `value`
+// is dropped on failure.
+pub fn try_push<T>(vec: &mut Vec<T>, value: T) -> Result<(), OutOfMemory> {
+    try_reserve(vec, 1)?;
+    vec.push(value);
+    Ok(())
+}
+
+// vec.extend_from_slice(items), or Err (leaving `vec` unchanged) if it
+// can't grow.
+pub fn try_extend_from_slice<T: Clone>(vec: &mut Vec<T>, items: &[T]) -> Result<(), OutOfMemory> {
+    try_reserve(vec, items.len())?;
+    vec.extend_from_slice(items);
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------
+// Self-test
+// ---------------------------------------------------------------------------
+
+pub fn test_fallible() {
+    m_print!("[fallible test] Box/Vec helpers, OOM without panic ... ");
+
+    let boxed = try_box([7u32; 16]).expect("small try_box failed");
+    assert_eq!(boxed[15], 7);
+    assert!(try_box(()).is_ok());
+
+    let mut vec = try_vec::<u16>(8).expect("small try_vec failed");
+    assert!(vec.capacity() >= 8);
+    for i in 0..100 {
+        try_push(&mut vec, i).expect("try_push failed");
+    }
+    try_extend_from_slice(&mut vec, &[1, 2, 3]).expect("try_extend_from_slice failed");
+    assert_eq!((vec.len(), vec[99], vec[102]), (103, 99, 3));
+
+    // Bigger than the whole heap region: Err, and the kernel lives on
+    let huge = super::heap::heap_max() - super::heap::heap_start();
+    assert_eq!(try_vec::<u8>(huge).err(), Some(OutOfMemory { size: huge }));
+    let len = vec.len();
+    assert!(try_reserve(&mut vec, huge).is_err());
+    assert_eq!(vec.len(), len);
+
+    // Overflowing the size is an error too, not a panic
+    assert_eq!(
+        try_vec::<u64>(usize::MAX / 4).err(),
+        Some(OutOfMemory { size: usize::MAX })
+    );
+    m_println!("OK");
+}
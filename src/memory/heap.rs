@@ -1,25 +1,57 @@
-// memory/heap.rs - Kernel heap: region management + linked-list free-list allocator
+// memory/heap.rs - Kernel heap: region management + segregated free-list allocator
 //
-// Memory layout of an allocated block:
+// Memory layout of a block:
 //
-//   ┌──────────────────────┐  ◄─ block start (what the free list tracks)
+//   ┌──────────────────────┐  ◄─ block start
 //   │  BlockHeader         │     size: usable bytes after header
-//   │    size: usize       │     is_free: false for allocated blocks
-//   │    is_free: bool     │     next: unused while allocated
-//   │    next: *mut Header │
+//   │    size: usize       │     is_free: block is in a bin
+//   │    is_free: bool     │     prev_free: the block physically before
+//   │    prev_free: bool   │       this one is free
+//   │    next, prev        │     bin links (only while free)
 //   ├──────────────────────┤  ◄─ pointer returned by kmalloc()
 //   │                      │
 //   │  usable memory       │     `size` bytes
-//   │                      │
+//   │            [footer]  │     free blocks: last word = header address
 //   └──────────────────────┘
 //
-// Free blocks are identical but is_free=true and `next` points to the
-// next free block (or null).  The free list is sorted by address so
-// that adjacent-block merging in kfree() is straightforward.
+// Free blocks are kept in segregated bins: bin k holds the blocks whose
+// size lies in [8 << k, 16 << k).  A bitmap records which bins are
+// non-empty, so kmalloc() finds a block in O(1): any block in a bin
+// above the request's own bin is large enough.  The request's own bin
+// is only walked when nothing larger is free.
+//
+// kfree() coalesces in O(1) with boundary tags: the next block is found
+// from the header's size, the previous one through the footer every
+// free block keeps in its last word (prev_free says whether it's there).
+//
+// krealloc() resizes in place when it can: a shrink splits the excess
+// off as a free block, a grow absorbs the free block that follows (or
+// new pages, for the last block).  Only otherwise does it copy.
+//
+// The mapped region always ends with a zero-size, allocated "epilogue"
+// header, so the last real block has a successor to carry prev_free.
+// Growing the heap turns the old epilogue into the header of the new
+// free block, writes a fresh epilogue at the new end, and merges the
+// new block with a free tail block if there is one.
+//
+// Shrinking is the reverse: when kfree() leaves a free tail block of at
+// least SHRINK_THRESHOLD bytes, the pages past SHRINK_KEEP bytes of it
+// are unmapped (frames go back to the frame allocator) and a new
+// epilogue is written below them.  The gap between the two constants is
+// the hysteresis: right after a shrink the tail holds SHRINK_KEEP bytes,
+// so a free/alloc cycle around the boundary neither unmaps nor maps.
+// The heap never shrinks below KERNEL_HEAP_INITIAL_SIZE.
 //
-// When no free block is large enough, the allocator automatically grows
-// the heap by mapping more pages, creates a new free block in the fresh
-// region, and merges it with the last block if adjacent.
+// Hardened mode (`heap_harden` feature) adds a magic to every header
+// and a red zone at the end of every allocated block; kfree() panics if
+// either was overwritten.  verify() walks the whole heap in both modes
+// and reports the first block that breaks an invariant.
+//
+// Every entry point runs with interrupts off, so interrupt handlers may
+// allocate too.  Growing the heap maps pages, which they must not do,
+// so kmalloc_flags() treats any call made with interrupts already off
+// as AllocFlags::ATOMIC.  For the same reason a kfree() that runs with
+// interrupts already off never unmaps the tail; a later kfree() will.
 //
 // Alignment: all allocations are aligned to ALLOC_ALIGN (8 bytes on
 // 32-bit).  The header size is rounded up to ALLOC_ALIGN so the
@@ -27,10 +59,13 @@
 
 use crate::dbg_println;
 use crate::m_println;
+use crate::utils::{interrupts_restore, interrupts_save, interrupts_were_enabled};
 
 use super::define::{KERNEL_HEAP_END, KERNEL_HEAP_INITIAL_SIZE, KERNEL_HEAP_START, PAGE_SIZE};
 use super::pageflags::PageFlags;
+use super::physical::FrameOwner;
 use super::vmm::{self, MapError, VirtAddr};
+use core::ops::BitOr;
 
 // ---------------------------------------------------------------------------
 // Constants
@@ -41,7 +76,8 @@ use super::vmm::{self, MapError, VirtAddr};
 const ALLOC_ALIGN: usize = 8;
 
 // Minimum usable block size.  Blocks smaller than this aren't worth
-// splitting - they'd just become unfindable fragments.
+// splitting - they'd just become unfindable fragments.  A free block
+// must also have room for its footer.
 const MIN_BLOCK_SIZE: usize = ALLOC_ALIGN;
 
 // How much to grow the heap when we run out of space (minimum).
@@ -49,6 +85,35 @@ const MIN_BLOCK_SIZE: usize = ALLOC_ALIGN;
 // map_range calls for tight loops of small allocations.
 const GROW_INCREMENT: usize = PAGE_SIZE;
 
+// Give pages back once the free tail reaches SHRINK_THRESHOLD bytes,
+// keeping SHRINK_KEEP bytes of it mapped for the next allocations.
+const SHRINK_THRESHOLD: usize = 16 * PAGE_SIZE;
+const SHRINK_KEEP: usize = 4 * PAGE_SIZE;
+
+const _: () = assert!(SHRINK_KEEP + PAGE_SIZE < SHRINK_THRESHOLD);
+
+// Bin 0 holds blocks of 8..15 bytes; the last bin is open-ended.
+const MIN_BIN_SHIFT: u32 = 3;
+const BIN_COUNT: usize = 24;
+
+const _: () = assert!(BIN_COUNT < u32::BITS as usize);
+
+// Hardened mode (`heap_harden` feature): header magics, and a red zone
+// of REDZONE_BYTE filling the last REDZONE bytes of every allocated
+// block.  kfree() checks both; without the feature REDZONE is 0 and
+// the checks compile away.
+#[cfg(feature = "heap_harden")]
+const MAGIC_USED: u32 = 0xA110_CA7E;
+#[cfg(feature = "heap_harden")]
+const MAGIC_FREE: u32 = 0xF4EE_B10C;
+const REDZONE: usize = if cfg!(feature = "heap_harden") {
+    ALLOC_ALIGN
+} else {
+    0
+};
+const REDZONE_BYTE: u8 = 0xCB;
+const _: () = assert!(MIN_BLOCK_SIZE >= core::mem::size_of::<usize>());
+
 // ---------------------------------------------------------------------------
 // Block header
 // ---------------------------------------------------------------------------
@@ -60,12 +125,51 @@ const GROW_INCREMENT: usize = PAGE_SIZE;
 // It must have a stable layout - hence #[repr(C)].
 #[repr(C)]
 struct BlockHeader {
+    // MAGIC_USED or MAGIC_FREE, kept in step with is_free.
+    #[cfg(feature = "heap_harden")]
+    magic: u32,
     // Size of the usable region *after* this header, in bytes.
     size: usize,
-    // True if this block is on the free list.
+    // True if this block is in a bin.
     is_free: bool,
-    // Next free block (only meaningful when is_free == true).
+    // True if the block right before this one in memory is free, i.e.
+    // the word before this header is that block's footer.
+    prev_free: bool,
+    // Neighbours in the bin (only meaningful when is_free == true).
     next: *mut BlockHeader,
+    prev: *mut BlockHeader,
+}
+
+impl BlockHeader {
+    const fn new(size: usize, is_free: bool) -> Self {
+        BlockHeader {
+            #[cfg(feature = "heap_harden")]
+            magic: if is_free { MAGIC_FREE } else { MAGIC_USED },
+            size,
+            is_free,
+            prev_free: false,
+            next: core::ptr::null_mut(),
+            prev: core::ptr::null_mut(),
+        }
+    }
+
+    fn set_free(&mut self, is_free: bool) {
+        self.is_free = is_free;
+        #[cfg(feature = "heap_harden")]
+        {
+            self.magic = if is_free { MAGIC_FREE } else { MAGIC_USED };
+        }
+    }
+
+    // Does the magic agree with is_free?  Always true without hardening.
+    fn magic_ok(&self) -> bool {
+        #[cfg(feature = "heap_harden")]
+        {
+            self.magic == if self.is_free { MAGIC_FREE } else { MAGIC_USED }
+        }
+        #[cfg(not(feature = "heap_harden"))]
+        true
+    }
 }
 
 // Header size, rounded up to ALLOC_ALIGN so the usable region that
@@ -77,19 +181,35 @@ const HEADER_SIZE: usize =
 // Global state
 // ---------------------------------------------------------------------------
 
-// Head of the free list (sorted by address, lowest first).
-static mut FREE_LIST: *mut BlockHeader = core::ptr::null_mut();
+// Head of each bin's free list (unordered).
+static mut BINS: [*mut BlockHeader; BIN_COUNT] = [core::ptr::null_mut(); BIN_COUNT];
+
+// Bit k set while BINS[k] is non-empty.
+static mut BIN_MAP: u32 = 0;
+
+// Benchmark switch: walk every free block in turn instead of using the
+// bin map (see set_first_fit()).
+static mut FIRST_FIT: bool = false;
 
 // Current end of the mapped heap region.  Everything in
 // [KERNEL_HEAP_START .. HEAP_MAPPED_END) is backed by physical frames.
 static mut HEAP_MAPPED_END: usize = KERNEL_HEAP_START;
 
+// Highest HEAP_MAPPED_END ever reached.
+static mut HEAP_HIGH_WATER: usize = KERNEL_HEAP_START;
+
 // Simple statistics - not required for correctness but useful for
 // debugging and the print_stats() diagnostic.
 static mut STATS: HeapStats = HeapStats {
     total_allocs: 0,
     total_frees: 0,
     current_used_bytes: 0,
+    shrinks: 0,
+    pages_returned: 0,
+    resized_in_place: 0,
+    realloc_moves: 0,
+    failed_allocs: 0,
+    double_frees: 0,
 };
 
 struct HeapStats {
@@ -97,14 +217,25 @@ struct HeapStats {
     total_frees: usize,
     // Sum of usable sizes of currently-allocated blocks.
     current_used_bytes: usize,
+    // Tail trims and the pages they unmapped.
+    shrinks: usize,
+    pages_returned: usize,
+    // krealloc() calls served without moving, and those that copied.
+    resized_in_place: usize,
+    realloc_moves: usize,
+    // kmalloc() calls that returned null, and frees or resizes of a
+    // block that was already free.  Counted rather than logged: both
+    // can happen in an interrupt handler.
+    failed_allocs: usize,
+    double_frees: usize,
 }
 
 // ---------------------------------------------------------------------------
 // Initialisation
 // ---------------------------------------------------------------------------
 
-// Initialise the kernel heap: map the initial pages and set up the free
-// list with a single large free block spanning the entire region.
+// Initialise the kernel heap: map the initial pages and file a single
+// free block spanning the region, followed by the epilogue.
 //
 // Must be called after vmm::init() and the physical frame allocator.
 pub fn init() {
@@ -114,11 +245,11 @@ pub fn init() {
         "Initial heap size exceeds heap region"
     );
     assert!(
-        initial % PAGE_SIZE == 0,
+        initial.is_multiple_of(PAGE_SIZE),
         "Initial heap size must be page-aligned"
     );
     assert!(
-        initial > HEADER_SIZE,
+        initial >= 2 * HEADER_SIZE + MIN_BLOCK_SIZE,
         "Initial heap too small for even one block"
     );
     dbg_println!(
@@ -128,28 +259,65 @@ pub fn init() {
         initial / 1024
     );
 
-    let flags = PageFlags::PRESENT | PageFlags::WRITABLE;
+    let flags = PageFlags::PRESENT | PageFlags::WRITABLE | PageFlags::NO_EXECUTE;
     let _pages: usize = vmm::map_range(VirtAddr::new(KERNEL_HEAP_START as u32), initial, flags)
         .expect("Heap: failed to map initial region");
+    vmm::set_range_owner(
+        VirtAddr::new(KERNEL_HEAP_START as u32),
+        initial,
+        FrameOwner::Heap,
+    );
 
     unsafe {
         HEAP_MAPPED_END = KERNEL_HEAP_START + initial;
+        HEAP_HIGH_WATER = HEAP_MAPPED_END;
 
-        // Create a single free block covering the entire mapped region.
+        // One block covering everything up to the epilogue
         let first_block = KERNEL_HEAP_START as *mut BlockHeader;
-        (*first_block).size = initial - HEADER_SIZE;
-        (*first_block).is_free = true;
-        (*first_block).next = core::ptr::null_mut();
-        FREE_LIST = first_block;
+        first_block.write(BlockHeader::new(initial - 2 * HEADER_SIZE, false));
+        write_epilogue(HEAP_MAPPED_END);
+        release_block(first_block);
     }
 
     dbg_println!(
         "Heap: {} pages mapped, allocator ready ({} KB usable)",
         _pages,
-        (initial - HEADER_SIZE) / 1024
+        (initial - 2 * HEADER_SIZE) / 1024
     );
 }
 
+// ---------------------------------------------------------------------------
+// Allocation flags
+// ---------------------------------------------------------------------------
+
+// Modifiers for kmalloc_flags(), combined with `|`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct AllocFlags(u32);
+
+impl AllocFlags {
+    pub const NONE: AllocFlags = AllocFlags(0);
+    // Clear the returned memory.
+    pub const ZERO: AllocFlags = AllocFlags(1 << 0);
+    // Only use blocks that are already free: never grow the heap, so no
+    // page-table changes.  Required in interrupt handlers.
+    pub const ATOMIC: AllocFlags = AllocFlags(1 << 1);
+    // The caller can't handle failure: panic instead of returning null.
+    pub const NOFAIL: AllocFlags = AllocFlags(1 << 2);
+
+    #[inline]
+    pub const fn contains(self, other: AllocFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for AllocFlags {
+    type Output = AllocFlags;
+    #[inline]
+    fn bitor(self, rhs: AllocFlags) -> AllocFlags {
+        AllocFlags(self.0 | rhs.0)
+    }
+}
+
 // ---------------------------------------------------------------------------
 // Public API
 // ---------------------------------------------------------------------------
@@ -161,37 +329,56 @@ pub fn init() {
 //
 // The returned pointer is aligned to ALLOC_ALIGN (8 bytes).
 pub fn kmalloc(size: usize) -> *mut u8 {
+    kmalloc_flags(size, AllocFlags::NONE)
+}
+
+// kmalloc() with modifiers, see AllocFlags.  Without NOFAIL, returns
+// null when the request can't be met (with ATOMIC: from the blocks
+// already free).  Called with interrupts off, it implies ATOMIC.
+pub fn kmalloc_flags(size: usize, flags: AllocFlags) -> *mut u8 {
     if size == 0 {
         return core::ptr::null_mut();
     }
 
-    // Round up to alignment so all blocks stay aligned
-    let aligned_size = align_up(size, ALLOC_ALIGN);
+    // Round up to alignment so all blocks stay aligned, then leave
+    // room for the red zone
+    let aligned_size = align_up(size, ALLOC_ALIGN) + REDZONE;
 
-    unsafe {
-        // First-fit search through the free list
-        if let Some(block) = find_free_block(aligned_size) {
-            return allocate_block(block, aligned_size);
-        }
+    let irq = interrupts_save();
+    let flags = if interrupts_were_enabled(irq) {
+        flags
+    } else {
+        flags | AllocFlags::ATOMIC
+    };
+    let ptr = unsafe { alloc_block(aligned_size, flags) };
+    if ptr.is_null() {
+        unsafe { STATS.failed_allocs += 1 };
+    }
+    interrupts_restore(irq);
 
-        // No block large enough - try to grow the heap
-        if try_grow_for(aligned_size).is_ok() {
-            // Retry after growing
-            if let Some(block) = find_free_block(aligned_size) {
-                return allocate_block(block, aligned_size);
-            }
+    if ptr.is_null() {
+        // Truly out of memory
+        if flags.contains(AllocFlags::NOFAIL) {
+            panic!("kmalloc: out of memory for NOFAIL request ({} bytes)", size);
+        }
+        // Printing takes the console locks, which an interrupt handler
+        // may already hold
+        if !flags.contains(AllocFlags::ATOMIC) && interrupts_were_enabled(irq) {
+            m_println!("kmalloc: out of memory (requested {} bytes)", size);
         }
+        return ptr;
+    }
 
-        // Truly out of memory
-        m_println!("kmalloc: out of memory (requested {} bytes)", size);
-        core::ptr::null_mut()
+    if flags.contains(AllocFlags::ZERO) {
+        unsafe { core::ptr::write_bytes(ptr, 0, size) };
     }
+    ptr
 }
 
 // Free a previously allocated block.
 //
 // `ptr` must be a pointer returned by kmalloc().  Passing null is a
-// safe no-op.  Double-free is detected and logged.
+// safe no-op.  Double-free is detected and counted (see print_stats()).
 pub fn kfree(ptr: *mut u8) {
     if ptr.is_null() {
         return;
@@ -200,32 +387,92 @@ pub fn kfree(ptr: *mut u8) {
     unsafe {
         // The header sits immediately before the usable region
         let header = (ptr as usize - HEADER_SIZE) as *mut BlockHeader;
+        let irq = interrupts_save();
+        if check_live(header, "kfree") {
+            STATS.total_frees += 1;
+            STATS.current_used_bytes -= (*header).size;
+
+            // Merge with free neighbours and file the result in its bin,
+            // then trim the heap if that left a large free tail (only
+            // when we may touch page tables).
+            if interrupts_were_enabled(irq) {
+                release_and_trim(header);
+            } else {
+                release_block(header);
+            }
+        }
+        interrupts_restore(irq);
+    }
+}
 
-        // Sanity checks
-        debug_assert!(
-            (header as usize) >= KERNEL_HEAP_START && (header as usize) < HEAP_MAPPED_END,
-            "kfree: pointer {:#x} outside heap region",
-            ptr as usize
-        );
+// Resize the allocation at `ptr` to `new_size` bytes without moving it.
+// Returns false, leaving the block untouched, when that isn't possible.
+pub fn kresize(ptr: *mut u8, new_size: usize) -> bool {
+    if ptr.is_null() || new_size == 0 {
+        return false;
+    }
 
-        if (*header).is_free {
-            println!("kfree: double free detected at {:#x}", ptr as usize);
-            return;
-        }
+    let irq = interrupts_save();
+    let resized = unsafe {
+        let header = (ptr as usize - HEADER_SIZE) as *mut BlockHeader;
+        check_live(header, "kresize")
+            && resize_block(header, new_size, interrupts_were_enabled(irq))
+    };
+    interrupts_restore(irq);
+    resized
+}
+
+// Resize the allocation at `ptr` to `new_size` bytes, in place when
+// possible, otherwise by moving it to a new block.  Behaves like C
+// realloc(): a null `ptr` allocates, a zero `new_size` frees and
+// returns null, and on failure null is returned and `ptr` stays valid.
+pub fn krealloc(ptr: *mut u8, new_size: usize) -> *mut u8 {
+    if ptr.is_null() {
+        return kmalloc(new_size);
+    }
+    if new_size == 0 {
+        kfree(ptr);
+        return core::ptr::null_mut();
+    }
 
-        // Mark as free and update stats
-        (*header).is_free = true;
-        STATS.total_frees += 1;
-        STATS.current_used_bytes -= (*header).size;
+    unsafe {
+        let header = (ptr as usize - HEADER_SIZE) as *mut BlockHeader;
+        let irq = interrupts_save();
+        let may_map = interrupts_were_enabled(irq);
+        let live = check_live(header, "krealloc");
+        let resized = live && resize_block(header, new_size, may_map);
+        interrupts_restore(irq);
+        if !live {
+            return core::ptr::null_mut();
+        }
+        if resized {
+            return ptr;
+        }
 
-        // Re-insert into the free list (sorted by address) and merge
-        // with adjacent free blocks to reduce fragmentation.
-        insert_free_block(header);
+        // Called with interrupts off, the move must not grow the heap
+        // either
+        let flags = if may_map {
+            AllocFlags::NONE
+        } else {
+            AllocFlags::ATOMIC
+        };
+        let new = kmalloc_flags(new_size, flags);
+        if !new.is_null() {
+            let old = (header as usize + HEADER_SIZE) as *const u8;
+            let len = ((*header).size - REDZONE).min(new_size);
+            core::ptr::copy_nonoverlapping(old, new, len);
+            let irq = interrupts_save();
+            STATS.realloc_moves += 1;
+            interrupts_restore(irq);
+            kfree(ptr);
+        }
+        new
     }
 }
 
 // Return the usable size of an allocation (the size that was rounded
-// up to alignment, which is >= the size originally requested).
+// up to alignment, which is >= the size originally requested).  The
+// red zone, if any, starts right after it.
 //
 // `ptr` must be a pointer returned by kmalloc().
 pub fn ksize(ptr: *mut u8) -> usize {
@@ -234,14 +481,14 @@ pub fn ksize(ptr: *mut u8) -> usize {
     }
     unsafe {
         let header = (ptr as usize - HEADER_SIZE) as *const BlockHeader;
-        (*header).size
+        (*header).size - REDZONE
     }
 }
 
 // Grow the mapped heap region by `size` bytes (must be page-aligned).
 //
-// The new memory is added to the free list and merged with the tail
-// block if adjacent.  Returns the start address of the newly mapped
+// The new memory becomes a free block, merged with the tail block if
+// that one is free.  Returns the start address of the newly mapped
 // region, or an error if the heap would exceed KERNEL_HEAP_END or
 // the frame allocator is exhausted.
 //
@@ -250,27 +497,50 @@ pub fn ksize(ptr: *mut u8) -> usize {
 // automatically via try_grow_for().
 pub fn grow_heap(size: usize) -> Result<usize, MapError> {
     assert!(
-        size % PAGE_SIZE == 0,
+        size.is_multiple_of(PAGE_SIZE),
         "grow_heap: size must be page-aligned"
     );
 
     unsafe { grow_mapped_region(size) }
 }
 
+// Switch kmalloc() between the binned search and a first-fit walk over
+// every free block.  This emulates the search cost of the old heap only:
+// the walk goes bin by bin, not in address order, so it may pick a
+// different block than the old address-ordered list would have, and
+// fragmentation is not comparable.  Only meant for benchmarking;
+// kfree() is O(1) either way.
+pub fn set_first_fit(enabled: bool) {
+    unsafe {
+        FIRST_FIT = enabled;
+    }
+}
+
+// Number of blocks currently in the bins.
+pub fn free_block_count() -> usize {
+    let mut count = 0;
+    unsafe {
+        for_each_free_block(|_| count += 1);
+    }
+    count
+}
+
 // ---------------------------------------------------------------------------
 // Internal: heap growth (single implementation)
 // ---------------------------------------------------------------------------
 
 // The single implementation backing both the public grow_heap() and the
-// internal try_grow_for().  Maps `size` bytes at HEAP_MAPPED_END,
-// creates a free block in the new region, and merges it into the list.
+// internal try_grow_for().  Maps `size` bytes at HEAP_MAPPED_END and
+// turns the old epilogue into a free block covering them.
 //
 // `size` must be page-aligned.  Caller is responsible for the assert.
 //
 // Returns the start address of the newly mapped region.
 unsafe fn grow_mapped_region(size: usize) -> Result<usize, MapError> {
     let old_end = HEAP_MAPPED_END;
-    let new_end = old_end + size;
+    // Saturating: a request near the size of the address space must
+    // fail the check below, not wrap around it
+    let new_end = old_end.saturating_add(size);
 
     if new_end > KERNEL_HEAP_END {
         println!(
@@ -280,19 +550,24 @@ unsafe fn grow_mapped_region(size: usize) -> Result<usize, MapError> {
         return Err(MapError::InvalidAddress);
     }
 
-    let flags = PageFlags::PRESENT | PageFlags::WRITABLE;
-    vmm::map_range(VirtAddr::new(old_end as u32), size, flags)?;
+    // 4 KB pages only: shrink_tail() must be able to cut anywhere
+    let flags = PageFlags::PRESENT | PageFlags::WRITABLE | PageFlags::NO_EXECUTE;
+    if let Err(e) = vmm::map_range_4k(VirtAddr::new(old_end as u32), size, flags) {
+        let _ = vmm::unmap_range(VirtAddr::new(old_end as u32), size);
+        return Err(e);
+    }
+    vmm::set_range_owner(VirtAddr::new(old_end as u32), size, FrameOwner::Heap);
     HEAP_MAPPED_END = new_end;
+    HEAP_HIGH_WATER = HEAP_HIGH_WATER.max(new_end);
 
-    // Create a free block covering the new region
-    let new_block = old_end as *mut BlockHeader;
+    // The old epilogue (allocated, prev_free already right) becomes the
+    // header of a block running up to the new epilogue.
+    let new_block = (old_end - HEADER_SIZE) as *mut BlockHeader;
     (*new_block).size = size - HEADER_SIZE;
-    (*new_block).is_free = true;
-    (*new_block).next = core::ptr::null_mut();
+    write_epilogue(new_end);
 
-    // Insert into free list - will auto-merge with the previous tail
-    // block if the old last free block ended exactly at old_end.
-    insert_free_block(new_block);
+    // Merges with the previous tail block if that one was free
+    release_block(new_block);
 
     dbg_println!(
         "Heap: grew by {} KB, mapped end now {:#x}",
@@ -307,8 +582,8 @@ unsafe fn grow_mapped_region(size: usize) -> Result<usize, MapError> {
 // Computes how much to grow (at least GROW_INCREMENT, or enough for the
 // request) and delegates to grow_mapped_region().
 unsafe fn try_grow_for(needed: usize) -> Result<(), MapError> {
-    // We need HEADER_SIZE overhead for the new free block, plus the
-    // requested amount.  Round up to whole pages.
+    // The new epilogue costs HEADER_SIZE, plus the requested amount.
+    // Round up to whole pages.
     let total_needed = needed + HEADER_SIZE;
     let grow_size = align_up(
         if total_needed > GROW_INCREMENT {
@@ -323,15 +598,139 @@ unsafe fn try_grow_for(needed: usize) -> Result<(), MapError> {
     Ok(())
 }
 
+// Unmap the end of the free tail block `tail` if it is large enough
+// (see SHRINK_THRESHOLD), leaving SHRINK_KEEP bytes of it mapped.
+unsafe fn shrink_tail(tail: *mut BlockHeader) {
+    if (*tail).size < SHRINK_THRESHOLD {
+        return;
+    }
+
+    let keep_end = tail as usize + 2 * HEADER_SIZE + SHRINK_KEEP;
+    let new_end = align_up(keep_end, PAGE_SIZE).max(KERNEL_HEAP_START + KERNEL_HEAP_INITIAL_SIZE);
+    if new_end >= HEAP_MAPPED_END {
+        return;
+    }
+    let old_end = HEAP_MAPPED_END;
+
+    // Unmap first: a large page the cut would split stays as it is
+    let pages = match vmm::unmap_range(VirtAddr::new(new_end as u32), old_end - new_end) {
+        Ok(pages) => pages,
+        Err(_) => return,
+    };
+
+    // Re-file the tail at its new size, then close the region below
+    // the unmapped pages
+    bin_remove(tail);
+    (*tail).size = new_end - HEADER_SIZE - (tail as usize + HEADER_SIZE);
+    write_epilogue(new_end);
+    write_footer(tail);
+    (*next_block(tail)).prev_free = true;
+    bin_insert(tail);
+
+    HEAP_MAPPED_END = new_end;
+    STATS.shrinks += 1;
+    STATS.pages_returned += pages;
+
+    dbg_println!(
+        "Heap: shrank by {} KB, mapped end now {:#x}",
+        (old_end - new_end) / 1024,
+        new_end
+    );
+}
+
+// Write the zero-size allocated header that closes the region ending
+// at `end`.  prev_free starts false; release_block() fixes it up.
+unsafe fn write_epilogue(end: usize) {
+    ((end - HEADER_SIZE) as *mut BlockHeader).write(BlockHeader::new(0, false));
+}
+
+// ---------------------------------------------------------------------------
+// Internal: bins
+// ---------------------------------------------------------------------------
+
+// Bin holding free blocks of `size` bytes (size >= MIN_BLOCK_SIZE).
+#[inline]
+fn bin_index(size: usize) -> usize {
+    let log2 = usize::BITS - 1 - size.leading_zeros();
+    ((log2 - MIN_BIN_SHIFT) as usize).min(BIN_COUNT - 1)
+}
+
+unsafe fn bin_insert(block: *mut BlockHeader) {
+    let bin = bin_index((*block).size);
+    (*block).prev = core::ptr::null_mut();
+    (*block).next = BINS[bin];
+    if !BINS[bin].is_null() {
+        (*BINS[bin]).prev = block;
+    }
+    BINS[bin] = block;
+    BIN_MAP |= 1 << bin;
+}
+
+unsafe fn bin_remove(block: *mut BlockHeader) {
+    let bin = bin_index((*block).size);
+    if (*block).prev.is_null() {
+        BINS[bin] = (*block).next;
+    } else {
+        (*(*block).prev).next = (*block).next;
+    }
+    if !(*block).next.is_null() {
+        (*(*block).next).prev = (*block).prev;
+    }
+    if BINS[bin].is_null() {
+        BIN_MAP &= !(1 << bin);
+    }
+}
+
+// Call `f` on every free block, bin by bin.
+unsafe fn for_each_free_block(mut f: impl FnMut(*mut BlockHeader)) {
+    for head in BINS {
+        let mut current = head;
+        while !current.is_null() {
+            f(current);
+            current = (*current).next;
+        }
+    }
+}
+
 // ---------------------------------------------------------------------------
 // Internal: allocation
 // ---------------------------------------------------------------------------
 
-// Walk the free list and return the first block with size >= `needed`.
+// Allocate a block of `needed` bytes (red zone included), growing the
+// heap unless `flags` has ATOMIC.  Null if there is no room.
+unsafe fn alloc_block(needed: usize, flags: AllocFlags) -> *mut u8 {
+    if let Some(block) = find_free_block(needed) {
+        return allocate_block(block, needed);
+    }
+
+    // No block large enough - try to grow the heap
+    if !flags.contains(AllocFlags::ATOMIC) && try_grow_for(needed).is_ok() {
+        // Retry after growing
+        if let Some(block) = find_free_block(needed) {
+            return allocate_block(block, needed);
+        }
+    }
+    core::ptr::null_mut()
+}
+
+// Find a free block with size >= `needed`.
 unsafe fn find_free_block(needed: usize) -> Option<*mut BlockHeader> {
-    let mut current = FREE_LIST;
+    if FIRST_FIT {
+        return find_first_fit(needed);
+    }
+
+    // Every block in a higher bin is larger than anything `needed`'s
+    // bin can hold, so the head of the first non-empty one fits.
+    let bin = bin_index(needed);
+    let above = BIN_MAP & !((2u32 << bin) - 1);
+    if above != 0 {
+        return Some(BINS[above.trailing_zeros() as usize]);
+    }
+
+    // Only blocks of about `needed`'s size are left: walk that bin
+    let mut current = BINS[bin];
     while !current.is_null() {
-        if (*current).is_free && (*current).size >= needed {
+        if (*current).size >= needed {
             return Some(current);
         }
         current = (*current).next;
@@ -339,28 +738,45 @@ unsafe fn find_free_block(needed: usize) -> Option<*mut BlockHeader> {
     None
 }
 
-// Mark `block` as allocated.  If the block is significantly larger
-// than `needed`, split it so the remainder stays on the free list.
+// Walk every free block and return the first with size >= `needed`.
+unsafe fn find_first_fit(needed: usize) -> Option<*mut BlockHeader> {
+    for head in BINS {
+        let mut current = head;
+        while !current.is_null() {
+            if (*current).size >= needed {
+                return Some(current);
+            }
+            current = (*current).next;
+        }
+    }
+    None
+}
+
+// Take `block` out of its bin and mark it allocated.  If it is
+// significantly larger than `needed`, split it so the remainder goes
+// back into a bin.
 //
 // Returns the usable-region pointer (header + HEADER_SIZE).
 unsafe fn allocate_block(block: *mut BlockHeader, needed: usize) -> *mut u8 {
+    bin_remove(block);
+
     // Try to split: only worth it if the remainder can hold a header
     // plus at least MIN_BLOCK_SIZE usable bytes.
     let remaining = (*block).size - needed;
     if remaining >= HEADER_SIZE + MIN_BLOCK_SIZE {
-        // Create a new free block after the allocated region
-        let new_block = (block as usize + HEADER_SIZE + needed) as *mut BlockHeader;
-        (*new_block).size = remaining - HEADER_SIZE;
-        (*new_block).is_free = true;
-        (*new_block).next = (*block).next;
-
+        // The remainder sits between us and a block that already has
+        // prev_free set, since `block` itself was free.
+        let rest = (block as usize + HEADER_SIZE + needed) as *mut BlockHeader;
+        rest.write(BlockHeader::new(remaining - HEADER_SIZE, true));
+        write_footer(rest);
+        bin_insert(rest);
         (*block).size = needed;
-        (*block).next = new_block;
+    } else {
+        (*next_block(block)).prev_free = false;
     }
 
-    // Remove from free list
-    (*block).is_free = false;
-    remove_from_free_list(block);
+    (*block).set_free(false);
+    write_redzone(block);
 
     STATS.total_allocs += 1;
     STATS.current_used_bytes += (*block).size;
@@ -369,72 +785,318 @@ unsafe fn allocate_block(block: *mut BlockHeader, needed: usize) -> *mut u8 {
     (block as usize + HEADER_SIZE) as *mut u8
 }
 
-// Remove a block from the free list.
-unsafe fn remove_from_free_list(block: *mut BlockHeader) {
-    if FREE_LIST == block {
-        FREE_LIST = (*block).next;
-        return;
-    }
-
-    let mut prev = FREE_LIST;
-    while !prev.is_null() {
-        if (*prev).next == block {
-            (*prev).next = (*block).next;
-            return;
-        }
-        prev = (*prev).next;
-    }
-}
-
 // ---------------------------------------------------------------------------
 // Internal: free + merge
 // ---------------------------------------------------------------------------
 
-// Insert a freed block back into the address-sorted free list, then
-// merge with the previous and/or next block if they are adjacent.
+// Block right after `block` in memory (the epilogue for the last one).
+#[inline]
+unsafe fn next_block(block: *mut BlockHeader) -> *mut BlockHeader {
+    (block as usize + HEADER_SIZE + (*block).size) as *mut BlockHeader
+}
+
+// Block right before `block` in memory.  Only valid if prev_free is set.
+#[inline]
+unsafe fn prev_block(block: *mut BlockHeader) -> *mut BlockHeader {
+    *(block as *const usize).sub(1) as *mut BlockHeader
+}
+
+// Store the header address in the last word of a free block.
+#[inline]
+unsafe fn write_footer(block: *mut BlockHeader) {
+    (next_block(block) as *mut usize)
+        .sub(1)
+        .write(block as usize);
+}
+
+// Fill the red zone at the end of an allocated block.
+#[inline]
+unsafe fn write_redzone(block: *mut BlockHeader) {
+    let end = next_block(block) as *mut u8;
+    core::ptr::write_bytes(end.sub(REDZONE), REDZONE_BYTE, REDZONE);
+}
+
+#[inline]
+unsafe fn redzone_intact(block: *const BlockHeader) -> bool {
+    let end = next_block(block as *mut BlockHeader) as *const u8;
+    let zone = core::slice::from_raw_parts(end.sub(REDZONE), REDZONE);
+    zone.iter().all(|&b| b == REDZONE_BYTE)
+}
+
+// Mark an allocated block free, merge it with the free blocks around
+// it and file the result in its bin.  Returns the merged block.
 //
 // Merging is the key to avoiding fragmentation: if three 64-byte
 // blocks are freed in sequence, they coalesce into a single ~192-byte
 // block rather than staying as three separate entries.
-unsafe fn insert_free_block(block: *mut BlockHeader) {
-    let addr = block as usize;
+unsafe fn release_block(block: *mut BlockHeader) -> *mut BlockHeader {
+    let mut block = block;
 
-    // Find insertion point: the block should go between `prev` and `next`
-    // where prev.addr < block.addr < next.addr.
-    let mut prev: *mut BlockHeader = core::ptr::null_mut();
-    let mut current = FREE_LIST;
+    let next = next_block(block);
+    if (*next).is_free {
+        bin_remove(next);
+        (*block).size += HEADER_SIZE + (*next).size;
+    }
 
-    while !current.is_null() && (current as usize) < addr {
-        prev = current;
-        current = (*current).next;
+    if (*block).prev_free {
+        let prev = prev_block(block);
+        bin_remove(prev);
+        (*prev).size += HEADER_SIZE + (*block).size;
+        // The absorbed header stays in memory; mark it free so that
+        // check_live() still catches a second kfree() of this block.
+        (*block).set_free(true);
+        block = prev;
     }
 
-    // Link block into the list
-    (*block).next = current;
-    if prev.is_null() {
-        FREE_LIST = block;
-    } else {
-        (*prev).next = block;
+    (*block).set_free(true);
+    write_footer(block);
+    (*next_block(block)).prev_free = true;
+    bin_insert(block);
+    block
+}
+
+// Release `block` and, if the merged block is now the last one, give
+// its pages back when it is large enough (see shrink_tail()).
+unsafe fn release_and_trim(block: *mut BlockHeader) {
+    let block = release_block(block);
+    if next_block(block) as usize + HEADER_SIZE == HEAP_MAPPED_END {
+        shrink_tail(block);
+    }
+}
+
+// Checks run before an allocated block is freed or resized.  Returns
+// false (and counts it) for a double free or a resize of a freed
+// block; panics if the header or the red zone was overwritten.
+unsafe fn check_live(header: *mut BlockHeader, op: &str) -> bool {
+    let ptr = header as usize + HEADER_SIZE;
+    debug_assert!(
+        (header as usize) >= KERNEL_HEAP_START && (header as usize) < HEAP_MAPPED_END,
+        "{}: pointer {:#x} outside heap region",
+        op,
+        ptr
+    );
+
+    if !(*header).magic_ok() {
+        panic!(
+            "{}: corrupt header at {:#x} (block {:#x})",
+            op, header as usize, ptr
+        );
+    }
+
+    if (*header).is_free {
+        STATS.double_frees += 1;
+        return false;
     }
 
-    // Merge with next block if adjacent in memory
-    //   block_end = block_addr + HEADER_SIZE + block.size
-    //   If block_end == current_addr, they're physically contiguous.
-    if !current.is_null() {
-        let block_end = addr + HEADER_SIZE + (*block).size;
-        if block_end == current as usize {
-            (*block).size += HEADER_SIZE + (*current).size;
-            (*block).next = (*current).next;
+    if !redzone_intact(header) {
+        panic!(
+            "{}: buffer overflow past {:#x} ({} bytes), red zone overwritten",
+            op,
+            ptr,
+            (*header).size - REDZONE
+        );
+    }
+    true
+}
+
+// Resize the allocated block `block` to hold `new_size` bytes in place.
+// Shrinking splits the excess off as a free block; growing absorbs the
+// free block that follows, or new pages when `block` is the last one.
+// Pages are only mapped or given back with `may_map` (see kfree()).
+unsafe fn resize_block(block: *mut BlockHeader, new_size: usize, may_map: bool) -> bool {
+    let needed = align_up(new_size, ALLOC_ALIGN) + REDZONE;
+    let old_size = (*block).size;
+
+    if needed > old_size {
+        let mut next = next_block(block);
+        let (room, last) = if (*next).is_free {
+            (old_size + HEADER_SIZE + (*next).size, next_block(next))
+        } else {
+            (old_size, next)
+        };
+        if room < needed {
+            // Only the last block (or the one before a free tail) can
+            // grow into new pages, which land right after it
+            if (*last).size != 0 || !may_map || try_grow_for(needed - room).is_err() {
+                return false;
+            }
+            next = next_block(block);
+        }
+        bin_remove(next);
+        (*block).size += HEADER_SIZE + (*next).size;
+        (*next_block(block)).prev_free = false;
+    }
+
+    // Hand back whatever is left past `needed`
+    let remaining = (*block).size - needed;
+    if remaining >= HEADER_SIZE + MIN_BLOCK_SIZE {
+        let rest = (block as usize + HEADER_SIZE + needed) as *mut BlockHeader;
+        rest.write(BlockHeader::new(remaining - HEADER_SIZE, false));
+        (*block).size = needed;
+        if may_map {
+            release_and_trim(rest);
+        } else {
+            release_block(rest);
         }
     }
 
-    // Merge with previous block if adjacent in memory
-    if !prev.is_null() {
-        let prev_end = prev as usize + HEADER_SIZE + (*prev).size;
-        if prev_end == addr {
-            (*prev).size += HEADER_SIZE + (*block).size;
-            (*prev).next = (*block).next;
+    write_redzone(block);
+    STATS.current_used_bytes = STATS.current_used_bytes - old_size + (*block).size;
+    STATS.resized_in_place += 1;
+    true
+}
+
+// ---------------------------------------------------------------------------
+// Integrity check
+// ---------------------------------------------------------------------------
+
+// One block as seen by verify().  `addr` is the header address.
+#[derive(Clone, Copy)]
+pub struct BlockInfo {
+    pub addr: usize,
+    pub size: usize,
+    pub free: bool,
+}
+
+// Totals from a successful verify().
+pub struct HeapReport {
+    pub blocks: usize,
+    pub free_blocks: usize,
+    pub used_bytes: usize,
+    pub free_bytes: usize,
+}
+
+// First block that broke an invariant, with the blocks around it.
+// `next` is None when the block's own size can't be trusted.
+pub struct HeapCorruption {
+    pub block: BlockInfo,
+    pub reason: &'static str,
+    pub prev: Option<BlockInfo>,
+    pub next: Option<BlockInfo>,
+}
+
+unsafe fn block_info(block: *const BlockHeader) -> BlockInfo {
+    BlockInfo {
+        addr: block as usize,
+        size: (*block).size,
+        free: (*block).is_free,
+    }
+}
+
+// Walk every block from KERNEL_HEAP_START to the epilogue, then every
+// bin, checking:
+//   - sizes are aligned and blocks stay inside the mapped region
+//   - header magics and red zones (hardened mode)
+//   - prev_free matches the previous block, and no two free blocks touch
+//   - free blocks carry a footer and sit in the right bin
+//   - the bins hold exactly the free blocks found by the walk
+pub fn verify() -> Result<HeapReport, HeapCorruption> {
+    unsafe {
+        let epilogue = HEAP_MAPPED_END - HEADER_SIZE;
+        let mut report = HeapReport {
+            blocks: 0,
+            free_blocks: 0,
+            used_bytes: 0,
+            free_bytes: 0,
+        };
+        let mut prev: Option<BlockInfo> = None;
+        let mut block = KERNEL_HEAP_START as *mut BlockHeader;
+
+        // End of `block`, if its size keeps it inside the heap
+        let end_of = |block: *mut BlockHeader| {
+            (block as usize + HEADER_SIZE)
+                .checked_add((*block).size)
+                .filter(|&next| next <= epilogue)
+        };
+        let corrupt = |block: *mut BlockHeader, prev, reason| {
+            let next = end_of(block).filter(|_| (*block).size.is_multiple_of(ALLOC_ALIGN));
+            Err(HeapCorruption {
+                block: block_info(block),
+                reason,
+                prev,
+                next: next.map(|next| block_info(next as *const BlockHeader)),
+            })
+        };
+
+        while (block as usize) < epilogue {
+            let info = block_info(block);
+            if !info.size.is_multiple_of(ALLOC_ALIGN) || info.size < MIN_BLOCK_SIZE {
+                return corrupt(block, prev, "bad block size");
+            }
+            if end_of(block).is_none() {
+                return corrupt(block, prev, "block runs past the mapped end");
+            }
+            if !(*block).magic_ok() {
+                return corrupt(block, prev, "bad header magic");
+            }
+            let prev_free = prev.is_some_and(|p| p.free);
+            if (*block).prev_free != prev_free {
+                return corrupt(block, prev, "prev_free disagrees with previous block");
+            }
+            if info.free {
+                if prev_free {
+                    return corrupt(block, prev, "adjacent free blocks not merged");
+                }
+                if *(next_block(block) as *const usize).sub(1) != block as usize {
+                    return corrupt(block, prev, "free block footer overwritten");
+                }
+                report.free_blocks += 1;
+                report.free_bytes += info.size;
+            } else {
+                if !redzone_intact(block) {
+                    return corrupt(block, prev, "red zone overwritten");
+                }
+                report.used_bytes += info.size;
+            }
+            report.blocks += 1;
+            prev = Some(info);
+            block = next_block(block);
+        }
+
+        let end = epilogue as *mut BlockHeader;
+        if block != end || (*end).size != 0 || (*end).is_free || !(*end).magic_ok() {
+            return corrupt(end, prev, "epilogue missing or damaged");
+        }
+        if (*end).prev_free != prev.is_some_and(|p| p.free) {
+            return corrupt(end, prev, "prev_free disagrees with previous block");
+        }
+
+        // Every binned block must be one the walk counted as free
+        let mut binned = 0;
+        for (bin, &head) in BINS.iter().enumerate() {
+            if head.is_null() == (BIN_MAP & (1 << bin) != 0) {
+                return corrupt(end, prev, "bin bitmap out of sync");
+            }
+            let mut last: Option<BlockInfo> = None;
+            let mut current = head;
+            while !current.is_null() {
+                // A wild link can't be dereferenced: blame the block holding it
+                let addr = current as usize;
+                if !(KERNEL_HEAP_START..epilogue).contains(&addr)
+                    || !addr.is_multiple_of(ALLOC_ALIGN)
+                {
+                    let holder = last.map_or(end, |l| l.addr as *mut BlockHeader);
+                    return corrupt(holder, None, "bin link points outside the heap");
+                }
+                if !(*current).is_free {
+                    return corrupt(current, last, "bin holds a block that isn't free");
+                }
+                if bin_index((*current).size) != bin {
+                    return corrupt(current, None, "free block filed in the wrong bin");
+                }
+                binned += 1;
+                if binned > report.free_blocks {
+                    return corrupt(current, last, "bin list longer than the free blocks");
+                }
+                last = Some(block_info(current));
+                current = (*current).next;
+            }
         }
+        if binned != report.free_blocks {
+            return corrupt(end, prev, "free block missing from the bins");
+        }
+
+        Ok(report)
     }
 }
 
@@ -468,6 +1130,11 @@ pub fn heap_mapped_size() -> usize {
     unsafe { HEAP_MAPPED_END - KERNEL_HEAP_START }
 }
 
+// Returns the largest number of bytes ever mapped for the heap.
+pub fn heap_high_water() -> usize {
+    unsafe { HEAP_HIGH_WATER - KERNEL_HEAP_START }
+}
+
 // Print heap allocator statistics.
 pub fn print_stats() {
     unsafe {
@@ -482,28 +1149,43 @@ pub fn print_stats() {
             max / 1024
         );
         dbg_println!(
-            "  Mapped:      {:#x}..{:#x} ({} KB)",
+            "  Mapped:      {:#x}..{:#x} ({} KB, high-water {} KB)",
             KERNEL_HEAP_START,
             HEAP_MAPPED_END,
-            mapped / 1024
+            mapped / 1024,
+            heap_high_water() / 1024
+        );
+        dbg_println!(
+            "  Shrinks:     {} ({} pages returned)",
+            STATS.shrinks,
+            STATS.pages_returned
         );
         dbg_println!("  Allocs:      {}", STATS.total_allocs);
         dbg_println!("  Frees:       {}", STATS.total_frees);
+        dbg_println!(
+            "  Reallocs:    {} in place, {} moved",
+            STATS.resized_in_place,
+            STATS.realloc_moves
+        );
         dbg_println!("  In use:      {} bytes", STATS.current_used_bytes);
+        dbg_println!(
+            "  Failures:    {} failed allocs, {} double frees",
+            STATS.failed_allocs,
+            STATS.double_frees
+        );
 
-        // Walk free list to report total free space
+        // Walk the bins to report total free space
         let mut free_bytes: usize = 0;
         let mut free_blocks: usize = 0;
-        let mut current = FREE_LIST;
-        while !current.is_null() {
-            free_bytes += (*current).size;
+        for_each_free_block(|block| {
+            free_bytes += (*block).size;
             free_blocks += 1;
-            current = (*current).next;
-        }
+        });
         dbg_println!(
-            "  Free:        {} bytes in {} block(s)",
+            "  Free:        {} bytes in {} block(s), {} bin(s) in use",
             free_bytes,
-            free_blocks
+            free_blocks,
+            BIN_MAP.count_ones()
         );
     }
 }
@@ -523,6 +1205,14 @@ pub fn test_heap() {
     test_reuse_after_free();
     test_alignment();
     test_zero_alloc();
+    test_bins();
+    test_grow_merges_tail();
+    test_shrink();
+    test_verify();
+    test_realloc();
+    test_alloc_flags();
+    test_shrink_large_growth();
+    test_double_free_after_merge();
 
     println!("\n=== Heap Allocator Self-Test PASSED ===\n");
 }
@@ -593,7 +1283,7 @@ fn test_merge_on_free() {
     assert!(!a.is_null() && !b.is_null() && !c.is_null());
 
     // Free middle first, then sides - exercises both forward and
-    // backward merge paths in insert_free_block().
+    // backward merge paths in release_block().
     kfree(b);
     kfree(a);
     kfree(c);
@@ -636,7 +1326,7 @@ fn test_reuse_after_free() {
     kfree(a);
 
     // A new allocation of the same size should reuse the same block
-    // (first-fit will find it immediately at the head of the free list).
+    // (it merged back into the free block it was split from).
     let b = kmalloc(48);
     assert!(!b.is_null());
     assert_eq!(
@@ -681,3 +1371,299 @@ fn test_zero_alloc() {
 
     println!("OK");
 }
+
+fn test_bins() {
+    print!("[Heap test 8] Size-class bins ... ");
+
+    // Small holes separated by live blocks, so they can't coalesce
+    let mut blocks = [core::ptr::null_mut(); 8];
+    for (i, block) in blocks.iter_mut().enumerate() {
+        *block = kmalloc(if i % 2 == 0 { 24 } else { 200 });
+        assert!(!block.is_null());
+    }
+    let holes_before = free_block_count();
+    kfree(blocks[2]);
+    kfree(blocks[4]);
+    assert_eq!(free_block_count(), holes_before + 2);
+
+    // A request too big for the holes must not land in one
+    let big = kmalloc(100);
+    assert!(big != blocks[2] && big != blocks[4]);
+
+    // Freeing a live block between two holes merges all three
+    kfree(blocks[3]);
+    assert_eq!(free_block_count(), holes_before + 1);
+    let merged = kmalloc(150);
+    assert_eq!(merged, blocks[2], "neighbouring holes were not merged");
+
+    kfree(merged);
+    kfree(big);
+    for i in [0, 1, 5, 6, 7] {
+        kfree(blocks[i]);
+    }
+    println!("OK");
+}
+
+fn test_grow_merges_tail() {
+    print!("[Heap test 9] Growth merges with a free tail ... ");
+
+    let blocks_before = free_block_count();
+    let end_before = heap_mapped_end();
+
+    // Larger than anything free, so the heap has to grow
+    let free_bytes = unsafe {
+        let mut total = 0;
+        for_each_free_block(|block| total += (*block).size);
+        total
+    };
+    let ptr = kmalloc(free_bytes + PAGE_SIZE);
+    assert!(!ptr.is_null(), "allocation requiring growth failed");
+    assert!(heap_mapped_end() > end_before, "heap did not grow");
+
+    // The grown block started inside the old free tail
+    assert!((ptr as usize) < end_before);
+    kfree(ptr);
+    assert_eq!(free_block_count(), blocks_before);
+    println!("OK");
+}
+
+fn test_shrink() {
+    print!("[Heap test 10] Shrink with hysteresis ... ");
+
+    let start_mapped = heap_mapped_size();
+    let start_shrinks = unsafe { STATS.shrinks };
+
+    // A burst that forces growth well past the threshold
+    let big = kmalloc(start_mapped + 2 * SHRINK_THRESHOLD);
+    assert!(!big.is_null());
+    let grown = heap_mapped_size();
+    assert!(grown > start_mapped);
+    assert!(heap_high_water() >= grown);
+
+    // Freeing it gives the pages back, down to SHRINK_KEEP of slack
+    kfree(big);
+    let shrunk = heap_mapped_size();
+    assert_eq!(unsafe { STATS.shrinks }, start_shrinks + 1);
+    assert!(shrunk < grown, "heap did not shrink");
+    assert!(shrunk >= KERNEL_HEAP_INITIAL_SIZE);
+    assert!(heap_high_water() >= grown, "high-water mark went down");
+
+    // Small churn at the top neither grows nor shrinks the heap
+    for _ in 0..16 {
+        let p = kmalloc(SHRINK_KEEP / 2);
+        assert!(!p.is_null());
+        kfree(p);
+    }
+    assert_eq!(heap_mapped_size(), shrunk, "heap thrashed at the boundary");
+    assert_eq!(unsafe { STATS.shrinks }, start_shrinks + 1);
+
+    println!("OK");
+}
+
+fn test_verify() {
+    print!("[Heap test 11] Integrity walk ... ");
+
+    let a = kmalloc(40);
+    let b = kmalloc(40);
+    let c = kmalloc(40);
+    assert!(!a.is_null() && !b.is_null() && !c.is_null());
+    let report = match verify() {
+        Ok(report) => report,
+        Err(bad) => panic!("verify failed at {:#x}: {}", bad.block.addr, bad.reason),
+    };
+    assert!(report.blocks >= 4 && report.free_blocks >= 1);
+
+    // Clear b's prev_free while a is free: the walk must blame b and
+    // name a as its neighbour
+    kfree(a);
+    unsafe {
+        let header = (b as usize - HEADER_SIZE) as *mut BlockHeader;
+        (*header).prev_free = false;
+        match verify() {
+            Err(bad) => {
+                assert_eq!(bad.block.addr, header as usize);
+                assert_eq!(bad.prev.map(|p| p.addr), Some(a as usize - HEADER_SIZE));
+                assert_eq!(bad.next.map(|n| n.addr), Some(c as usize - HEADER_SIZE));
+            }
+            Ok(_) => panic!("verify missed a stale prev_free"),
+        }
+        (*header).prev_free = true;
+    }
+
+    // One byte past ksize() lands in the red zone
+    #[cfg(feature = "heap_harden")]
+    unsafe {
+        let past = b.add(ksize(b));
+        let saved = *past;
+        *past = !REDZONE_BYTE;
+        match verify() {
+            Err(bad) => assert_eq!(bad.block.addr, b as usize - HEADER_SIZE),
+            Ok(_) => panic!("verify missed a red zone overwrite"),
+        }
+        *past = saved;
+    }
+
+    kfree(b);
+    kfree(c);
+    assert!(verify().is_ok());
+    println!("OK");
+}
+
+fn test_realloc() {
+    print!("[Heap test 12] krealloc in place and by copy ... ");
+
+    let a = kmalloc(64);
+    let b = kmalloc(64);
+    let c = kmalloc(64);
+    assert!(!a.is_null() && !b.is_null() && !c.is_null());
+    unsafe {
+        for i in 0..64 {
+            *a.add(i) = i as u8;
+        }
+    }
+
+    // Grows into the free neighbour without moving
+    kfree(b);
+    let grown = krealloc(a, 120);
+    assert_eq!(grown, a, "krealloc moved despite a free neighbour");
+    assert!(ksize(a) >= 120);
+
+    // Shrinks in place; the excess becomes a free block again
+    assert_eq!(krealloc(a, 16), a);
+    assert!(ksize(a) < 64);
+    unsafe {
+        let next = next_block((a as usize - HEADER_SIZE) as *mut BlockHeader);
+        assert!((*next).is_free && !(*next).prev_free);
+        assert_eq!(next_block(next) as usize, c as usize - HEADER_SIZE);
+        assert!((*next_block(next)).prev_free);
+    }
+
+    // Too big for the gap before c: moves and keeps the contents
+    let moved = krealloc(a, 1024);
+    assert!(!moved.is_null() && moved != a);
+    unsafe {
+        for i in 0..16 {
+            assert_eq!(*moved.add(i), i as u8, "krealloc lost data");
+        }
+    }
+
+    // C semantics for null and zero
+    let fresh = krealloc(core::ptr::null_mut(), 32);
+    assert!(!fresh.is_null());
+    assert!(krealloc(fresh, 0).is_null());
+    assert!(!kresize(moved, 0));
+
+    kfree(moved);
+    kfree(c);
+    assert!(verify().is_ok());
+    println!("OK");
+}
+
+fn test_alloc_flags() {
+    print!("[Heap test 13] kmalloc_flags ZERO and ATOMIC ... ");
+
+    let flags = AllocFlags::ZERO | AllocFlags::ATOMIC;
+    assert!(flags.contains(AllocFlags::ZERO) && flags.contains(AllocFlags::ATOMIC));
+    assert!(!flags.contains(AllocFlags::NOFAIL));
+
+    // Dirty a block, free it, and get it back zeroed
+    let dirty = kmalloc(200);
+    assert!(!dirty.is_null());
+    unsafe { core::ptr::write_bytes(dirty, 0xFF, 200) };
+    kfree(dirty);
+    let zeroed = kmalloc_flags(200, AllocFlags::ZERO);
+    assert!(!zeroed.is_null());
+    assert!((0..200).all(|i| unsafe { *zeroed.add(i) } == 0));
+    kfree(zeroed);
+
+    // ATOMIC uses free blocks but fails rather than grow
+    let small = kmalloc_flags(64, AllocFlags::ATOMIC);
+    assert!(!small.is_null());
+    kfree(small);
+    let end_before = heap_mapped_end();
+    let free_bytes = unsafe {
+        let mut total = 0;
+        for_each_free_block(|block| total += (*block).size);
+        total
+    };
+    let failed = unsafe { STATS.failed_allocs };
+    assert!(kmalloc_flags(free_bytes + PAGE_SIZE, AllocFlags::ATOMIC).is_null());
+    assert_eq!(
+        heap_mapped_end(),
+        end_before,
+        "ATOMIC allocation grew the heap"
+    );
+    assert_eq!(unsafe { STATS.failed_allocs }, failed + 1);
+
+    // With interrupts off (as in a handler) plain kmalloc is ATOMIC too
+    let irq = interrupts_save();
+    let ptr = kmalloc(free_bytes + PAGE_SIZE);
+    let end_after = heap_mapped_end();
+    interrupts_restore(irq);
+    assert!(ptr.is_null());
+    assert_eq!(
+        end_after, end_before,
+        "kmalloc grew the heap with interrupts off"
+    );
+    assert_eq!(unsafe { STATS.failed_allocs }, failed + 2);
+
+    println!("OK");
+}
+
+fn test_shrink_large_growth() {
+    print!("[Heap test 14] Shrink and regrow after a 4 MB growth ... ");
+
+    // Past a large page, so map_range() would have used one
+    const GROWTH: usize = 4 * 1024 * 1024 + 2 * SHRINK_THRESHOLD;
+    let start_mapped = heap_mapped_size();
+    let (shrinks, returned) = unsafe { (STATS.shrinks, STATS.pages_returned) };
+
+    let big = kmalloc(GROWTH);
+    assert!(!big.is_null(), "growth past 4 MB failed");
+    let grown = heap_mapped_size();
+    assert!(grown >= start_mapped + GROWTH);
+
+    // Freeing it cuts the tail wherever SHRINK_KEEP ends, which is not
+    // on a large-page boundary; every page past the cut must be gone
+    kfree(big);
+    let shrunk = heap_mapped_size();
+    assert!(shrunk < grown, "heap did not shrink");
+    assert_eq!(unsafe { STATS.shrinks }, shrinks + 1);
+    assert_eq!(
+        unsafe { STATS.pages_returned },
+        returned + (grown - shrunk) / PAGE_SIZE
+    );
+    assert!(!vmm::is_mapped(VirtAddr::new(heap_mapped_end() as u32)));
+
+    // The same growth maps the released range again
+    let again = kmalloc(GROWTH);
+    assert!(!again.is_null(), "regrowth after a shrink failed");
+    assert!(heap_mapped_size() > shrunk);
+    kfree(again);
+    assert!(verify().is_ok());
+
+    println!("OK");
+}
+
+fn test_double_free_after_merge() {
+    print!("[Heap test 15] Double free after a backward merge ... ");
+
+    // `c` keeps `b` away from the heap tail, so nothing is trimmed
+    let a = kmalloc(64);
+    let b = kmalloc(64);
+    let c = kmalloc(64);
+    assert!(!a.is_null() && !b.is_null() && !c.is_null());
+
+    kfree(a);
+    kfree(b); // merges into a's free block
+    let double_frees = unsafe { STATS.double_frees };
+    let frees = unsafe { STATS.total_frees };
+    kfree(b);
+    assert_eq!(unsafe { STATS.double_frees }, double_frees + 1);
+    assert_eq!(unsafe { STATS.total_frees }, frees);
+
+    kfree(c);
+    assert!(verify().is_ok());
+
+    println!("OK");
+}
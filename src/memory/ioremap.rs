@@ -0,0 +1,206 @@
+// memory/ioremap.rs - Mapping device memory (MMIO)
+//
+// Device registers and framebuffers live at fixed physical addresses
+// outside RAM.  ioremap() maps such a range into the vmalloc window with
+// the requested cache policy and returns an MmioRegion; the mapping is
+// fenced by guard pages and listed by `vmaps` like any vmalloc region.
+//
+// Nothing here goes through the frame allocator: the frames belong to
+// the device.  A range that overlaps usable RAM is refused, whether the
+// allocator hands those frames out or keeps them (kernel image, frame
+// bitmap and metadata), so a driver can never end up sharing its
+// registers with a heap page or remap kernel memory with another cache
+// policy.  Holes and firmware areas and addresses beyond the end of
+// RAM, up to 4 GB, are fine.
+//
+// MmioRegion accessors are volatile and bounds-checked: an offset past
+// the mapped size (or misaligned for its width) panics instead of
+// poking the neighbouring device.  Dropping the region unmaps it;
+// iounmap() only makes that explicit.
+
+use super::define::PAGE_SIZE;
+use super::pageflags::PageFlags;
+use super::physical::{self, FrameOwner};
+use super::vmm::{self, PhysAddr, VmError};
+use crate::{dbg_println, m_print, m_println};
+
+// End of the 32-bit physical address space.
+const PHYS_LIMIT: u64 = 1 << 32;
+
+// How the CPU may cache accesses to the range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CachePolicy {
+    // PCD + PWT: every access goes to the device (registers)
+    Uncached,
+    // PWT: reads may hit the cache, writes always reach the device
+    // (framebuffers)
+    WriteThrough,
+}
+
+impl CachePolicy {
+    fn flags(self) -> PageFlags {
+        match self {
+            CachePolicy::Uncached => PageFlags::CACHE_DISABLE | PageFlags::WRITE_THROUGH,
+            CachePolicy::WriteThrough => PageFlags::WRITE_THROUGH,
+        }
+    }
+}
+
+// A mapped MMIO range.
+pub struct MmioRegion {
+    // Start of the mapping (page aligned)
+    virt: u32,
+    // Offset of `phys` inside the first page
+    offset: usize,
+    phys: u32,
+    size: usize,
+}
+
+impl MmioRegion {
+    // Virtual address of physical byte `phys`.
+    pub fn base(&self) -> *mut u8 {
+        (self.virt as usize + self.offset) as *mut u8
+    }
+
+    pub fn phys(&self) -> u32 {
+        self.phys
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    #[inline]
+    fn check(&self, offset: usize, width: usize) {
+        assert!(
+            offset.is_multiple_of(width)
+                && offset
+                    .checked_add(width)
+                    .is_some_and(|end| end <= self.size),
+            "MMIO access at offset {:#x} (width {}) outside region {:#x}+{:#x}",
+            offset,
+            width,
+            self.phys,
+            self.size
+        );
+    }
+
+    pub fn read32(&self, offset: usize) -> u32 {
+        self.check(offset, 4);
+        unsafe { (self.base().add(offset) as *const u32).read_volatile() }
+    }
+
+    pub fn write32(&self, offset: usize, value: u32) {
+        self.check(offset, 4);
+        unsafe { (self.base().add(offset) as *mut u32).write_volatile(value) }
+    }
+}
+
+impl Drop for MmioRegion {
+    fn drop(&mut self) {
+        if let Err(e) = vmm::vunmap_io(self.virt) {
+            dbg_println!("iounmap: {:#x} was not mapped ({:?})", self.virt, e);
+        }
+    }
+}
+
+// Map `size` bytes of device memory at `phys` with `policy`, read/write
+// and never executable.  `phys` need not be page aligned.
+pub fn ioremap(phys: u32, size: usize, policy: CachePolicy) -> Result<MmioRegion, VmError> {
+    if size == 0 {
+        return Err(VmError::ZeroSize);
+    }
+    if phys as u64 + size as u64 > PHYS_LIMIT {
+        return Err(VmError::PhysRange);
+    }
+    let offset = phys as usize % PAGE_SIZE;
+    let first = phys as usize - offset;
+    // Counted in u64: a range that ends at exactly 4 GB overflows usize
+    let pages = (offset as u64 + size as u64).div_ceil(PAGE_SIZE as u64) as usize;
+    if physical::overlaps_usable_ram(first / PAGE_SIZE, pages) {
+        return Err(VmError::ManagedRam);
+    }
+
+    let virt = vmm::vmap_io(
+        PhysAddr::new(first as u32),
+        pages,
+        PageFlags::WRITABLE | policy.flags(),
+        "ioremap",
+    )?;
+    dbg_println!(
+        "ioremap: phys {:#x}+{:#x} at {:#x} ({:?})",
+        phys,
+        size,
+        virt as usize + offset,
+        policy
+    );
+    Ok(MmioRegion {
+        virt,
+        offset,
+        phys,
+        size,
+    })
+}
+
+// Release a mapping obtained from ioremap().
+pub fn iounmap(region: MmioRegion) {
+    drop(region);
+}
+
+// ---------------------------------------------------------------------------
+// Self-test - needs the heap (region bookkeeping)
+// ---------------------------------------------------------------------------
+
+pub fn test_ioremap() {
+    m_print!("[ioremap test] VGA text buffer, RAM and 4 GB refusal ... ");
+
+    // The VGA text buffer is device memory the boot mapping also covers:
+    // a write through one mapping shows through the other.
+    const VGA_PHYS: u32 = 0xB8000;
+    let vga = ioremap(
+        VGA_PHYS + 0x10,
+        80 * 25 * 2 - 0x10,
+        CachePolicy::WriteThrough,
+    )
+    .expect("ioremap VGA failed");
+    let boot_view = (crate::vga::VGA_BUFFER_ADDR + 0x10) as *const u32;
+    let saved = vga.read32(0);
+    vga.write32(0, 0x0F21_0F21);
+    assert_eq!(unsafe { boot_view.read_volatile() }, 0x0F21_0F21);
+    vga.write32(0, saved);
+
+    let virt = vga.base() as u32;
+    let flags = vmm::page_flags(vmm::VirtAddr::new(virt)).expect("not mapped");
+    assert!(flags.contains(PageFlags::WRITE_THROUGH) && !flags.is_cache_disabled());
+    assert!(vmm::find_region(virt).is_some_and(|r| r.io_phys == Some(VGA_PHYS)));
+    assert!(matches!(
+        vmm::vfree(virt & !0xFFF, PAGE_SIZE),
+        Err(VmError::IoRegion)
+    ));
+    iounmap(vga);
+    assert!(!vmm::is_mapped(vmm::VirtAddr::new(virt)));
+
+    // Usable RAM is never MMIO
+    let frame = vmm::alloc_frame(FrameOwner::Kernel).expect("alloc failed");
+    let phys = frame.start_address() as u32;
+    vmm::free_frame(PhysAddr::new(phys));
+    assert!(matches!(
+        ioremap(phys, PAGE_SIZE, CachePolicy::Uncached),
+        Err(VmError::ManagedRam)
+    ));
+
+    // Nor is the kernel image, although the allocator never hands it out
+    let text = super::physmap::virt_to_phys(test_ioremap as fn() as usize) as u32;
+    assert!(matches!(
+        ioremap(text, PAGE_SIZE, CachePolicy::Uncached),
+        Err(VmError::ManagedRam)
+    ));
+
+    // Nor does a range wrap past 4 GB onto low memory
+    assert!(matches!(
+        ioremap(0xFFFF_F000, 2 * PAGE_SIZE, CachePolicy::Uncached),
+        Err(VmError::PhysRange)
+    ));
+
+    m_println!("OK");
+}
@@ -0,0 +1,222 @@
+// memory/kmap.rs - Temporary kernel mappings of arbitrary frames
+//
+// Only RAM below PHYSMAP_SIZE is reachable through the physmap, but the
+// frame allocator hands out frames anywhere.  kmap() gives any frame a
+// short-lived kernel address inside a small fixed window just below the
+// foreign slots:
+//
+//   layout().kmap_base() .. + KMAP_SLOTS * PAGE_SIZE
+//
+// The page table behind the window is created once by init().  It is a
+// kernel PDE, so every address space shares it, and mapping a slot is a
+// single PTE write.  Unmapping clears the PTE and invlpg's the slot -
+// no allocation, no full TLB flush.  A slot that was never mapped, or
+// was unmapped with invlpg, has no TLB entry, so mapping needs no flush.
+//
+// Slots are claimed from a bitmap with interrupts off, so the page fault
+// handler (COW copies) can kmap while another kmap is live.
+//
+// The returned KmapGuard unmaps its slot when dropped; kunmap() only
+// makes that explicit at the call site.
+
+use super::define::{KMAP_SLOTS, PAGE_SIZE};
+use super::pageflags::PageFlags;
+use super::paging::PageEntry;
+use super::physical::PhysFrame;
+use super::vmm::{self, MapError, Tables, VirtAddr};
+use crate::utils::{interrupts_restore, interrupts_save};
+use crate::{dbg_println, m_print, m_println};
+
+// One bit per slot, set while a guard holds it
+static mut KMAP_USED: u32 = 0;
+static mut KMAP_READY: bool = false;
+
+const _: () = assert!(KMAP_SLOTS >= 1 && KMAP_SLOTS <= 32);
+
+const ALL_SLOTS: u32 = if KMAP_SLOTS == 32 {
+    u32::MAX
+} else {
+    (1 << KMAP_SLOTS) - 1
+};
+
+// Create the window's page table.  Must run after vmm::init() and before
+// any address space is created, so they all inherit the PDE.
+pub fn init() {
+    let base = VirtAddr::new(vmm::layout().kmap_base());
+    vmm::ensure_page_table(base).expect("kmap: cannot create window page table");
+    unsafe {
+        KMAP_READY = true;
+    }
+    dbg_println!("kmap: {} slots at {:#x}", KMAP_SLOTS, base.0);
+}
+
+#[inline]
+fn slot_virt(slot: usize) -> VirtAddr {
+    VirtAddr::new(vmm::layout().kmap_base() + (slot * PAGE_SIZE) as u32)
+}
+
+// A frame mapped into the kmap window.  Valid until dropped.
+pub struct KmapGuard {
+    slot: usize,
+    frame: PhysFrame,
+}
+
+impl KmapGuard {
+    pub fn virt(&self) -> VirtAddr {
+        slot_virt(self.slot)
+    }
+
+    pub fn frame(&self) -> PhysFrame {
+        self.frame
+    }
+
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.virt().0 as *mut u8
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.as_ptr(), PAGE_SIZE) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.as_ptr(), PAGE_SIZE) }
+    }
+
+    pub fn zero(&mut self) {
+        self.as_mut_slice().fill(0);
+    }
+}
+
+impl Drop for KmapGuard {
+    fn drop(&mut self) {
+        let virt = self.virt();
+        unsafe {
+            vmm::write_pte_at(
+                Tables::active(),
+                virt.pde_index(),
+                virt.pte_index(),
+                PageEntry::empty(),
+            );
+        }
+        vmm::flush_tlb_entry(virt);
+
+        let flags = interrupts_save();
+        unsafe {
+            KMAP_USED &= !(1 << self.slot);
+        }
+        interrupts_restore(flags);
+    }
+}
+
+// Map `frame` read/write (never executable) into a free slot.
+pub fn kmap(frame: PhysFrame) -> Result<KmapGuard, MapError> {
+    assert!(unsafe { KMAP_READY }, "kmap used before kmap::init()");
+
+    let flags = interrupts_save();
+    let free = unsafe { !KMAP_USED } & ALL_SLOTS;
+    if free == 0 {
+        interrupts_restore(flags);
+        return Err(MapError::NoKmapSlot);
+    }
+    let slot = free.trailing_zeros() as usize;
+    unsafe {
+        KMAP_USED |= 1 << slot;
+    }
+    interrupts_restore(flags);
+
+    let virt = slot_virt(slot);
+    unsafe {
+        vmm::write_pte_at(
+            Tables::active(),
+            virt.pde_index(),
+            virt.pte_index(),
+            PageEntry::new_wide(
+                frame.start_address() as u64,
+                PageFlags::PRESENT | PageFlags::WRITABLE | PageFlags::NO_EXECUTE,
+            ),
+        );
+    }
+    Ok(KmapGuard { slot, frame })
+}
+
+// Release a mapping obtained from kmap().
+pub fn kunmap(guard: KmapGuard) {
+    drop(guard);
+}
+
+// Fill a frame with zeroes.
+pub fn zero_frame(frame: PhysFrame) -> Result<(), MapError> {
+    kmap(frame)?.zero();
+    Ok(())
+}
+
+// Copy the contents of `src` into `dst`.
+pub fn copy_frame(dst: PhysFrame, src: PhysFrame) -> Result<(), MapError> {
+    let from = kmap(src)?;
+    let mut to = kmap(dst)?;
+    to.as_mut_slice().copy_from_slice(from.as_slice());
+    Ok(())
+}
+
+// Number of slots currently held.
+pub fn slots_in_use() -> usize {
+    unsafe { KMAP_USED.count_ones() as usize }
+}
+
+// ---------------------------------------------------------------------------
+// Self-test - run once after the heap is up
+// ---------------------------------------------------------------------------
+
+pub fn test_kmap() {
+    m_print!("[kmap test] map, alias, copy, exhaust ... ");
+
+    let a = vmm::alloc_frame(super::physical::FrameOwner::Kernel).expect("alloc a failed");
+    let b = vmm::alloc_frame(super::physical::FrameOwner::Kernel).expect("alloc b failed");
+    let in_use = slots_in_use();
+
+    // Write through one slot, read back through another
+    {
+        let mut first = kmap(a).expect("kmap a failed");
+        for (i, byte) in first.as_mut_slice().iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let alias = kmap(a).expect("kmap alias failed");
+        assert_ne!(first.virt().0, alias.virt().0);
+        assert_eq!(
+            vmm::translate(alias.virt()).map(|p| p.0),
+            Some(a.start_address() as u32)
+        );
+        assert_eq!(alias.as_slice()[0x123], 0x23);
+        kunmap(alias);
+    }
+
+    // Whole-frame helpers
+    copy_frame(b, a).expect("copy_frame failed");
+    assert_eq!(kmap(b).expect("kmap b failed").as_slice()[0xFFF], 0xFF);
+    zero_frame(b).expect("zero_frame failed");
+    assert!(kmap(b)
+        .expect("kmap b failed")
+        .as_slice()
+        .iter()
+        .all(|&x| x == 0));
+
+    // Exhaust the window, then check a slot comes back after a drop
+    let mut held = alloc::vec::Vec::new();
+    while let Ok(g) = kmap(a) {
+        held.push(g);
+    }
+    assert_eq!(held.len(), KMAP_SLOTS - in_use);
+    assert!(matches!(kmap(a), Err(MapError::NoKmapSlot)));
+    let freed = held.pop().unwrap().virt();
+    assert!(
+        vmm::translate(freed).is_none(),
+        "slot still mapped after drop"
+    );
+    assert!(kmap(a).is_ok());
+    drop(held);
+    assert_eq!(slots_in_use(), in_use);
+
+    vmm::free_frame(vmm::PhysAddr::new(a.start_address() as u32));
+    vmm::free_frame(vmm::PhysAddr::new(b.start_address() as u32));
+    m_println!("OK");
+}
@@ -1,11 +1,21 @@
 use paging::PageDirectory;
 
+pub mod addrspace;
 pub mod allocator;
 pub mod define;
+pub mod fallible;
 pub mod heap;
+pub mod ioremap;
+pub mod kmap;
 pub mod pageflags;
 pub mod paging;
 pub mod physical;
+pub mod physmap;
+pub mod sections;
+pub mod slab;
+#[cfg(feature = "alloc_track")]
+pub mod tracking;
+pub mod vma;
 pub mod vmm;
 
 extern "C" {
@@ -27,29 +37,62 @@ pub fn init() {
     unsafe {
         #[cfg(feature = "verbose")]
         println!("Cleaning identity map...");
-        clear_page1();
+        if paging::pae_enabled() {
+            // The boot 4 MB identity map is two 2 MB PDEs under PAE
+            paging().clear_entry(0);
+            paging().clear_entry(1);
+            vmm::flush_tlb_all();
+        } else {
+            clear_page1();
+        }
+    }
+
+    // W^X for the kernel image: page_table1 no longer backs the identity
+    // map, so its PTEs only describe the higher half now.
+    sections::protect_kernel();
+    // sections::test_kernel_wx();
+    // Reserve the kmap window's page table and extend the direct map
+    // before any address space copies the kernel half.
+    kmap::init();
+    if let Some(memory_map) = crate::multiboot2::meminfo::get_memory_map() {
+        physmap::init(memory_map);
+        // The frame metadata table may live above the boot mapping
+        physical::attach_frame_metadata();
     }
+    // physical::test_memory_map_clip();
+    // Slab caches live in the physmap
+    slab::init();
+
     // diagnose_page_directory();
     // Run VMM self-tests after everything is initialised
     // vmm::test_virtual_memory();
+    // addrspace::test_address_space();
+    // vma::test_demand_paging();
 
     // Map the initial kernel heap region.
     // Must come after vmm::init() and the frame allocator.
     heap::init();
     // heap::test_heap();
+    // vmm::test_vmalloc();
+    // kmap::test_kmap();
+    // ioremap::test_ioremap();
+    // slab::test_slab();
     heap::print_stats();
 
     // Run GlobalAlloc tests if the feature is enabled.
     // These exercise Box, Vec, String through the #[global_allocator].
     #[cfg(feature = "alloc_test")]
     allocator::test_global_alloc();
+    // #[cfg(feature = "alloc_track")]
+    // tracking::test_tracking();
+    // fallible::test_fallible();
 }
 
 pub fn diagnose_page_directory() {
     println!("\n=== Page Directory Diagnostic ===\n");
 
     unsafe {
-        use crate::memory::define::KERNEL_OFFSET;
+        use crate::memory::physmap::virt_to_phys;
         use crate::memory::PAGING;
 
         let cr3: u32;
@@ -57,7 +100,7 @@ pub fn diagnose_page_directory() {
         println!("CR3 (Page Directory Physical): {:#010x}", cr3);
 
         let pd_virt = &crate::memory::define::page_directory as *const _ as u32;
-        let pd_phys = pd_virt - KERNEL_OFFSET as u32;
+        let pd_phys = virt_to_phys(pd_virt as usize) as u32;
         println!("Page Directory Virtual:  {:#010x}", pd_virt);
         println!("Page Directory Physical: {:#010x}", pd_phys);
 
@@ -69,33 +112,33 @@ pub fn diagnose_page_directory() {
 
         println!("\n--- Checking Key Entries ---\n");
 
-        let pde_0 = PAGING.as_mut().unwrap().get_entry(0);
+        let pde_0 = PAGING.as_ref().unwrap().entry(0);
         println!("PDE[0] (0x00000000-0x003FFFFF):");
-        println!("  Raw value: {:#010x}", (*pde_0).value());
-        println!("  Present:   {}", (*pde_0).present());
-        if (*pde_0).present() {
-            println!("  Address:   {:#010x}", (*pde_0).address());
-            println!("  Writable:  {}", (*pde_0).writeable());
-            println!("  User:      {}", (*pde_0).user());
+        println!("  Raw value: {:#010x}", pde_0.value());
+        println!("  Present:   {}", pde_0.present());
+        if pde_0.present() {
+            println!("  Address:   {:#010x}", pde_0.address());
+            println!("  Writable:  {}", pde_0.writeable());
+            println!("  User:      {}", pde_0.user());
         }
 
-        let pde_768 = PAGING.as_mut().unwrap().get_entry(768);
+        let pde_768 = PAGING.as_ref().unwrap().entry(768);
         println!("\nPDE[768] (0xC0000000-0xC03FFFFF):");
-        println!("  Raw value: {:#010x}", (*pde_768).value());
-        println!("  Present:   {}", (*pde_768).present());
-        if (*pde_768).present() {
-            println!("  Address:   {:#010x}", (*pde_768).address());
-            println!("  Writable:  {}", (*pde_768).writeable());
-            println!("  User:      {}", (*pde_768).user());
+        println!("  Raw value: {:#010x}", pde_768.value());
+        println!("  Present:   {}", pde_768.present());
+        if pde_768.present() {
+            println!("  Address:   {:#010x}", pde_768.address());
+            println!("  Writable:  {}", pde_768.writeable());
+            println!("  User:      {}", pde_768.user());
 
             extern "C" {
                 static page_table1: [u32; 1024];
             }
             let pt1_virt = &page_table1 as *const _ as u32;
-            let pt1_phys = pt1_virt - KERNEL_OFFSET as u32;
+            let pt1_phys = virt_to_phys(pt1_virt as usize) as u32;
             println!("  page_table1 phys: {:#010x}", pt1_phys);
 
-            if (*pde_768).address() == pt1_phys {
+            if pde_768.address() == pt1_phys {
                 println!("  Points to page_table1 OK");
             } else {
                 println!("  Does NOT point to page_table1!");
@@ -107,13 +150,13 @@ pub fn diagnose_page_directory() {
         println!("\n--- All Present Entries ---\n");
         let mut count = 0;
         for i in 0..1024 {
-            let pde = PAGING.as_mut().unwrap().get_entry(i);
-            if (*pde).present() {
+            let pde = PAGING.as_ref().unwrap().entry(i);
+            if pde.present() {
                 let virt_start = i * 0x400000;
                 println!(
                     "PDE[{}] -> {:#010x} (maps {:#010x}-{:#010x})",
                     i,
-                    (*pde).address(),
+                    pde.address(),
                     virt_start,
                     virt_start + 0x3FFFFF
                 );
@@ -214,31 +257,31 @@ pub fn test_paging_infrastructure() {
     // Test 3: Read existing page directory entries
     println!("\n[Test 3] Reading current page directory");
     unsafe {
-        let pde_0 = PAGING.as_mut().unwrap().get_entry(0);
-        println!("  PDE[0] (0x00000000): {}", *pde_0);
-        if !(*pde_0).present() {
+        let pde_0 = PAGING.as_ref().unwrap().entry(0);
+        println!("  PDE[0] (0x00000000): {}", pde_0);
+        if !pde_0.present() {
             println!("    Identity mapping correctly cleared");
         } else {
             println!("    Warning: Identity mapping still present");
-            println!("      Value: {:#010x}", (*pde_0).value());
+            println!("      Value: {:#010x}", pde_0.value());
         }
 
-        let pde_768 = PAGING.as_mut().unwrap().get_entry(768);
-        println!("  PDE[768] (0xC0000000): {}", *pde_768);
+        let pde_768 = PAGING.as_ref().unwrap().entry(768);
+        println!("  PDE[768] (0xC0000000): {}", pde_768);
 
-        if (*pde_768).present() {
+        if pde_768.present() {
             println!("    PDE[768] is present for higher-half kernel");
-            println!("      Points to physical: {:#010x}", (*pde_768).address());
-            println!("      Writable: {}", (*pde_768).writeable());
+            println!("      Points to physical: {:#010x}", pde_768.address());
+            println!("      Writable: {}", pde_768.writeable());
         } else {
             println!("    CRITICAL: PDE[768] is NOT present!");
-            println!("      Value: {:#010x}", (*pde_768).value());
+            println!("      Value: {:#010x}", pde_768.value());
 
             println!("\n    Scanning for present entries:");
             for i in 0..1024 {
-                let pde = PAGING.as_mut().unwrap().get_entry(i);
-                if (*pde).present() {
-                    println!("      PDE[{}] = {:#010x}", i, (*pde).value());
+                let pde = PAGING.as_ref().unwrap().entry(i);
+                if pde.present() {
+                    println!("      PDE[{}] = {:#010x}", i, pde.value());
                 }
             }
         }
@@ -2,12 +2,16 @@
 //
 // Both PageDirectoryEntry and PageTableEntry use these flags.
 // No other file should define its own PRESENT / WRITABLE / etc. constants.
+//
+// Flags are kept as a u64 so the same type covers PAE entries, whose
+// execute-disable bit is bit 63.  Legacy 32-bit entries only ever see
+// the low 12 bits.
 
 use core::fmt;
 use core::ops::{BitAnd, BitOr, Not};
 
 #[derive(Clone, Copy, PartialEq, Eq)]
-pub struct PageFlags(u32);
+pub struct PageFlags(u64);
 
 impl PageFlags {
     pub const NONE: PageFlags = PageFlags(0);
@@ -20,26 +24,42 @@ impl PageFlags {
     pub const DIRTY: PageFlags = PageFlags(1 << 6);
     pub const HUGE_PAGE: PageFlags = PageFlags(1 << 7); // PSE in PDE, PAT in PTE
     pub const GLOBAL: PageFlags = PageFlags(1 << 8);
-
-    // Mask that covers all 12 flag bits (bits 0-11).
-    const FLAGS_MASK: u32 = 0xFFF;
-    // Mask for the 20-bit physical page frame address (bits 12-31).
-    pub const ADDR_MASK: u32 = 0xFFFFF000;
+    // Bits 9-11 are ignored by the MMU and free for the OS.
+    // Copy-on-write: page is shared read-only, copy it on the first write.
+    pub const COW: PageFlags = PageFlags(1 << 9);
+    // PAE only: execute-disable (needs EFER.NXE).  Dropped when the
+    // entry is written in a mode that cannot honour it.
+    pub const NO_EXECUTE: PageFlags = PageFlags(1 << 63);
+
+    // Permission bits protect_range() may change on a live mapping.
+    pub const PROTECTION: PageFlags = PageFlags(
+        Self::WRITABLE.0
+            | Self::USER.0
+            | Self::WRITE_THROUGH.0
+            | Self::CACHE_DISABLE.0
+            | Self::NO_EXECUTE.0,
+    );
+
+    // Mask that covers all flag bits (bits 0-11 and NX).
+    const FLAGS_MASK: u64 = 0x8000_0000_0000_0FFF;
+    // Mask for the physical page frame address (bits 12-51; legacy
+    // entries only use bits 12-31).
+    pub const ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
 
     // -- Constructors --------------------------------------------------------
 
-    // Build PageFlags from a raw u32 (e.g. read from a hardware entry).
-    // Only the low 12 bits are kept.
+    // Build PageFlags from a raw entry value (e.g. read from a hardware
+    // entry).  Only the flag bits are kept.
     #[inline]
-    pub const fn from_raw(val: u32) -> Self {
+    pub const fn from_raw(val: u64) -> Self {
         PageFlags(val & Self::FLAGS_MASK)
     }
 
     // -- Accessors -----------------------------------------------------------
 
-    // Raw u32 value (only the low 12 bits are meaningful).
+    // Raw value (only the flag bits are meaningful).
     #[inline]
-    pub const fn value(self) -> u32 {
+    pub const fn value(self) -> u64 {
         self.0
     }
 
@@ -55,6 +75,24 @@ impl PageFlags {
     pub const fn is_user(self) -> bool {
         self.0 & Self::USER.0 != 0
     }
+    #[inline]
+    pub const fn is_cow(self) -> bool {
+        self.0 & Self::COW.0 != 0
+    }
+    #[inline]
+    pub const fn is_no_execute(self) -> bool {
+        self.0 & Self::NO_EXECUTE.0 != 0
+    }
+    #[inline]
+    pub const fn is_cache_disabled(self) -> bool {
+        self.0 & Self::CACHE_DISABLE.0 != 0
+    }
+
+    // `self` with its PROTECTION bits replaced by those of `prot`.
+    #[inline]
+    pub const fn with_protection(self, prot: PageFlags) -> PageFlags {
+        PageFlags((self.0 & !Self::PROTECTION.0) | (prot.0 & Self::PROTECTION.0))
+    }
 
     // Check whether `self` contains all the bits in `other`.
     #[inline]
@@ -64,12 +102,12 @@ impl PageFlags {
 
     // -- Helpers for entry construction / decomposition ----------------------
 
-    // Combine a page-aligned physical address with flags into the raw u32
-    // that the hardware expects in a PDE or PTE.
+    // Combine a page-aligned physical address with flags into the raw
+    // value the hardware expects in a PDE or PTE.
     //
     // Panics (debug) if `phys_addr` is not 4 KB aligned.
     #[inline]
-    pub fn to_entry(self, phys_addr: u32) -> u32 {
+    pub fn to_entry(self, phys_addr: u64) -> u64 {
         debug_assert!(
             phys_addr & !Self::ADDR_MASK == 0,
             "address {:#x} is not 4 KB aligned",
@@ -80,13 +118,13 @@ impl PageFlags {
 
     // Extract the flags portion from a raw PDE / PTE value.
     #[inline]
-    pub const fn flags_of(raw: u32) -> PageFlags {
+    pub const fn flags_of(raw: u64) -> PageFlags {
         PageFlags(raw & Self::FLAGS_MASK)
     }
 
     // Extract the 4 KB-aligned physical address from a raw PDE / PTE value.
     #[inline]
-    pub const fn addr_of(raw: u32) -> u32 {
+    pub const fn addr_of(raw: u64) -> u64 {
         raw & Self::ADDR_MASK
     }
 }
@@ -130,10 +168,12 @@ impl fmt::Display for PageFlags {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "{}{}{}",
+            "{}{}{}{}{}",
             if self.is_present() { 'P' } else { '-' },
             if self.is_writable() { 'W' } else { 'R' }, // W = writable, R = read-only
             if self.is_user() { 'U' } else { 'K' },     // U = user, K = kernel
+            if self.is_cow() { "C" } else { "" },       // C = copy-on-write
+            if self.is_no_execute() { "N" } else { "" }, // N = no-execute
         )
     }
 }
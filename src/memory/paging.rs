@@ -15,32 +15,102 @@
 // accessors for the context-dependent bits.
 //
 // Similarly, the "page directory" and "page table" are both arrays of
-// 1024 PageEntry values.  The only wrapper we keep is `PageDirectory`,
+// PageEntry values.  The only wrapper we keep is `PageDirectory`,
 // which exists solely for the early boot init path (before the VMM's
 // recursive mapping is live).  After vmm::init(), all PDE/PTE access
-// goes through the recursive mapping using raw PageEntry pointers.
+// goes through the recursive mapping with read_entry() / write_entry().
+//
+// PAE mode (feature `pae`, picked at boot if CPUID has it) widens every
+// entry to 64 bits: three levels (PDPT -> 4 page directories -> page
+// tables of 512 entries) and the NX bit.  PageEntry always holds the
+// 64-bit form; read_entry() / write_entry() convert to and from
+// whatever the hardware tables use.
+//
+// Here PAE only buys NX.  Frame numbers and PhysAddr stay 32-bit,
+// PageEntry::address() drops the high bits and the frame allocator
+// clips the memory map at 4 GB, so RAM above 4 GB is never used.
 
-use super::define::{KERNEL_OFFSET, PAGE_DIRECTORY_ENTRIES};
+use super::define::PAGE_DIRECTORY_ENTRIES;
 use super::pageflags::PageFlags;
+use super::physmap::virt_to_phys;
 use core::fmt;
 use core::ptr::NonNull;
 
 // ---------------------------------------------------------------------------
-// PageEntry - a single 4-byte page directory or page table entry
+// Paging mode
+// ---------------------------------------------------------------------------
+
+// Set once by enable_pae(); legacy 2-level paging until then.
+static mut PAE_ENABLED: bool = false;
+// EFER.NXE is on: NO_EXECUTE is honoured instead of dropped.
+static mut NX_ENABLED: bool = false;
+
+#[inline]
+pub fn pae_enabled() -> bool {
+    unsafe { PAE_ENABLED }
+}
+
+#[inline]
+pub fn nx_enabled() -> bool {
+    unsafe { NX_ENABLED }
+}
+
+// Bytes per hardware entry in the current mode.
+#[inline]
+pub fn entry_size() -> usize {
+    if pae_enabled() {
+        8
+    } else {
+        4
+    }
+}
+
+// Read entry `index` of the hardware table at `table`.
+#[inline]
+pub(crate) unsafe fn read_entry(table: *const u8, index: usize) -> PageEntry {
+    if pae_enabled() {
+        PageEntry((table as *const u64).add(index).read_volatile())
+    } else {
+        PageEntry((table as *const u32).add(index).read_volatile() as u64)
+    }
+}
+
+// Write entry `index` of the hardware table at `table`.  NO_EXECUTE is
+// dropped when the CPU would treat it as a reserved bit.
+#[inline]
+pub(crate) unsafe fn write_entry(table: *mut u8, index: usize, entry: PageEntry) {
+    let mut raw = entry.0;
+    if !nx_enabled() {
+        raw &= !PageFlags::NO_EXECUTE.value();
+    }
+    if pae_enabled() {
+        (table as *mut u64).add(index).write_volatile(raw);
+    } else {
+        debug_assert!(raw >> 32 == 0, "entry {:#x} does not fit 32 bits", raw);
+        (table as *mut u32).add(index).write_volatile(raw as u32);
+    }
+}
+
+// ---------------------------------------------------------------------------
+// PageEntry - a single page directory or page table entry
 // ---------------------------------------------------------------------------
 //
-// #[repr(C)] with no alignment override: size = 4, align = 4.
-// This is critical - pointer arithmetic (e.g. ptr.add(index)) must
-// stride by 4 bytes, not 4096.
+// A plain value: it is never overlaid on a hardware table, so its size
+// does not have to match the entry width of the current mode.
 
-#[repr(C)]
 #[derive(Clone, Copy)]
-pub struct PageEntry(u32);
+pub struct PageEntry(u64);
 
 impl PageEntry {
     // Create an entry pointing to `phys_addr` with the given flags.
     // Panics (debug) if `phys_addr` is not 4 KB aligned.
     pub fn new(phys_addr: u32, flags: PageFlags) -> Self {
+        Self::new_wide(phys_addr as u64, flags)
+    }
+
+    // Same as new() for a physical address that may lie above 4 GB
+    // (PAE only).
+    pub fn new_wide(phys_addr: u64, flags: PageFlags) -> Self {
         debug_assert!(
             phys_addr & 0xFFF == 0,
             "PageEntry address {:#x} must be 4KB aligned",
@@ -61,7 +131,7 @@ impl PageEntry {
             "PageEntry address {:#x} must be 4KB aligned",
             phys_addr
         );
-        self.0 = flags.to_entry(phys_addr);
+        self.0 = flags.to_entry(phys_addr as u64);
     }
 
     // Clear this entry (mark as not present).
@@ -71,9 +141,9 @@ impl PageEntry {
 
     // -- Raw access ----------------------------------------------------------
 
-    // Raw u32 value as the hardware sees it.
+    // Raw value as a PAE entry (a legacy entry is its low 32 bits).
     #[inline]
-    pub fn value(&self) -> u32 {
+    pub fn value(&self) -> u64 {
         self.0
     }
 
@@ -83,16 +153,23 @@ impl PageEntry {
         PageFlags::flags_of(self.0)
     }
 
-    // Extract the 4 KB-aligned physical address (high 20 bits).
+    // Extract the 4 KB-aligned physical address.  The frame allocator
+    // only hands out memory below 4 GB, so this is the low 32 bits;
+    // address_wide() has the full PAE address.
     #[inline]
     pub fn address(&self) -> u32 {
+        PageFlags::addr_of(self.0) as u32
+    }
+
+    #[inline]
+    pub fn address_wide(&self) -> u64 {
         PageFlags::addr_of(self.0)
     }
 
     // Page frame number (address >> 12).
     #[inline]
     pub fn page_frame_number(&self) -> u32 {
-        self.0 >> 12
+        (PageFlags::addr_of(self.0) >> 12) as u32
     }
 
     // -- Common flag queries (identical for PDE and PTE) ---------------------
@@ -125,6 +202,10 @@ impl PageEntry {
     pub fn global(&self) -> bool {
         self.flags().contains(PageFlags::GLOBAL)
     }
+    #[inline]
+    pub fn no_execute(&self) -> bool {
+        self.flags().is_no_execute()
+    }
 
     // -- Context-dependent bits (dual names) ---------------------------------
     //
@@ -141,7 +222,7 @@ impl PageEntry {
         self.flags().contains(PageFlags::DIRTY)
     }
 
-    // Bit 7 as a PDE field (4 MB page size / PSE).
+    // Bit 7 as a PDE field (4 MB page size / PSE; 2 MB under PAE).
     #[inline]
     pub fn page_size_4mb(&self) -> bool {
         self.flags().contains(PageFlags::HUGE_PAGE)
@@ -162,7 +243,7 @@ impl fmt::Display for PageEntry {
         write!(
             f,
             "phys {:#010x} {} accessed={} dirty={}",
-            self.address(),
+            self.address_wide(),
             self.flags(),
             self.accessed(),
             self.dirty(),
@@ -172,7 +253,7 @@ impl fmt::Display for PageEntry {
 
 impl fmt::Debug for PageEntry {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "PageEntry({:#010x})", self.0)
+        write!(f, "PageEntry({:#018x})", self.0)
     }
 }
 
@@ -183,30 +264,26 @@ impl fmt::Debug for PageEntry {
 // This exists for the early init path: memory::init() creates it to
 // point at the assembly-defined `page_directory` symbol, then uses it
 // to install the recursive mapping at PDE[1023].  After vmm::init(),
-// all access goes through the recursive mapping and this wrapper is
-// no longer needed for runtime operations.
+// it is the master copy of the kernel half (see vmm::map_page).
+//
+// Under PAE it wraps the four boot page directories instead, which sit
+// back to back and are indexed 0..2048 as one array - the same numbering
+// vmm uses (PDE index = address >> 21).
 //
 // We don't have a separate `PageTable` wrapper because:
 //   1. The VMM accesses page tables through the recursive mapping.
 //   2. A page table is structurally identical to a page directory:
-//      1024 × PageEntry, 4 KB aligned.  If you ever need one, just
-//      use a `[PageEntry; 1024]` or access it through a raw pointer.
+//      one 4 KB page of entries.  If you ever need one, access it
+//      through read_entry() / write_entry().
 
 pub struct PageDirectory {
-    entries: NonNull<[PageEntry; PAGE_DIRECTORY_ENTRIES]>,
+    entries: NonNull<u8>,
 }
 
 impl PageDirectory {
     // Wrap the assembly-defined `page_directory` symbol.
     pub fn new() -> Self {
-        unsafe {
-            PageDirectory {
-                entries: NonNull::new_unchecked(
-                    (&super::define::page_directory as *const [u32; 1024])
-                        as *mut [PageEntry; 1024],
-                ),
-            }
-        }
+        Self::default()
     }
 
     // Const version for static initialisation.
@@ -214,23 +291,36 @@ impl PageDirectory {
         unsafe {
             PageDirectory {
                 entries: NonNull::new_unchecked(
-                    (&super::define::page_directory as *const [u32; 1024])
-                        as *mut [PageEntry; 1024],
+                    (&super::define::page_directory as *const [u32; 1024]) as *mut u8,
                 ),
             }
         }
     }
 
+    // Number of PDEs reachable through this wrapper.
+    #[inline]
+    fn len(&self) -> usize {
+        if pae_enabled() {
+            PAE_DIRS * PAE_ENTRIES
+        } else {
+            PAGE_DIRECTORY_ENTRIES
+        }
+    }
+
     // Set PDE[index] to point to `phys_addr` with `flags`.
     pub fn set_entry(&mut self, index: usize, phys_addr: u32, flags: PageFlags) {
-        assert!(index < PAGE_DIRECTORY_ENTRIES, "PDE index out of bounds");
+        assert!(index < self.len(), "PDE index out of bounds");
         assert!(
             phys_addr & 0xFFF == 0,
             "Page table address must be 4KB aligned"
         );
 
         unsafe {
-            (*self.get_entry(index)).set(phys_addr, flags);
+            write_entry(
+                self.entries.as_ptr(),
+                index,
+                PageEntry::new(phys_addr, flags),
+            );
         }
 
         #[cfg(feature = "verbose")]
@@ -242,27 +332,137 @@ impl PageDirectory {
 
     // Clear PDE[index] (mark as not present).
     pub fn clear_entry(&mut self, index: usize) {
-        assert!(index < PAGE_DIRECTORY_ENTRIES, "PDE index out of bounds");
+        assert!(index < self.len(), "PDE index out of bounds");
 
         unsafe {
-            (*self.get_entry(index)).clear();
+            write_entry(self.entries.as_ptr(), index, PageEntry::empty());
         }
 
         #[cfg(feature = "verbose")]
         println!("PDE[{}] cleared", index);
     }
 
-    // Get a raw pointer to PDE[index]
-    pub fn get_entry(&mut self, index: usize) -> *mut PageEntry {
-        unsafe {
-            let base = self.entries.as_ptr() as *mut PageEntry;
-            base.add(index)
-        }
+    // Read PDE[index]
+    pub fn entry(&self, index: usize) -> PageEntry {
+        assert!(index < self.len(), "PDE index out of bounds");
+        unsafe { read_entry(self.entries.as_ptr(), index) }
     }
 
-    // Physical address of this page directory
+    // Physical address of this page directory - the value for CR3, so
+    // the PDPT under PAE.
     // Assumes the directory lives in the higher half
     pub fn physical_address(&self) -> u32 {
-        (self.entries.as_ptr() as u32).wrapping_sub(KERNEL_OFFSET as u32)
+        #[cfg(feature = "pae")]
+        if pae_enabled() {
+            return unsafe { kernel_phys(&PAE_BOOT.pdpt as *const _ as *const u8) as u32 };
+        }
+        virt_to_phys(self.entries.as_ptr() as usize) as u32
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Switching to PAE
+// ---------------------------------------------------------------------------
+
+// Page directories per PAE address space, and entries per PAE table.
+pub const PAE_DIRS: usize = 4;
+pub const PAE_ENTRIES: usize = 512;
+
+// The PAE counterpart of the assembly `page_directory` / `page_table1`
+// pair, in .bss so it is covered by the boot 4 MB mapping.
+#[cfg(feature = "pae")]
+#[repr(C, align(4096))]
+struct PaeBootTables {
+    // The four page directories, back to back (2048 PDEs)
+    dirs: [[u64; PAE_ENTRIES]; PAE_DIRS],
+    // The boot 4 MB mapping re-expressed as two 2 MB tables
+    tables: [[u64; PAE_ENTRIES]; 2],
+    // Page-directory-pointer table: CR3 points here (32-byte aligned)
+    pdpt: [u64; PAE_DIRS],
+}
+
+#[cfg(feature = "pae")]
+static mut PAE_BOOT: PaeBootTables = PaeBootTables {
+    dirs: [[0; PAE_ENTRIES]; PAE_DIRS],
+    tables: [[0; PAE_ENTRIES]; 2],
+    pdpt: [0; PAE_DIRS],
+};
+
+#[cfg(feature = "pae")]
+extern "C" {
+    static page_table1: [u32; 1024];
+    static _kernel_end: u8;
+    // bootstrap.asm: load CR3 with `pdpt_phys` and turn on CR4.PAE.
+    // Runs through the identity map, so only before clear_page1().
+    fn enable_pae_paging(pdpt_phys: u32);
+}
+
+#[cfg(feature = "pae")]
+#[inline]
+fn kernel_phys(virt: *const u8) -> u64 {
+    virt_to_phys(virt as usize) as u64
+}
+
+// Rebuild the boot mappings as PAE tables and switch the CPU over.
+// Returns the wrapper for the new boot directories.
+//
+// Called from vmm::init() while only the boot 4 MB is mapped (identity
+// and higher half, both through page_table1).  With `nx`, EFER.NXE is
+// turned on and the boot frames past the kernel image become
+// non-executable.
+#[cfg(feature = "pae")]
+pub(crate) unsafe fn enable_pae(nx: bool) -> PageDirectory {
+    const KERNEL_PDE: usize = super::define::KERNEL_OFFSET >> 22;
+    const EFER: u32 = 0xC000_0080;
+    const EFER_NXE: u64 = 1 << 11;
+
+    let legacy = &super::define::page_directory;
+    for (i, &pde) in legacy.iter().enumerate() {
+        assert!(
+            pde & 1 == 0 || i == 0 || i == KERNEL_PDE,
+            "PAE: unexpected boot PDE[{}] = {:#x}",
+            i,
+            pde
+        );
+    }
+
+    let boot = &mut PAE_BOOT;
+    let image_end = kernel_phys(&_kernel_end);
+    for (i, &pte) in page_table1.iter().enumerate() {
+        let mut entry = pte as u64;
+        if nx && entry & 1 != 0 && PageFlags::addr_of(entry) >= image_end {
+            entry |= PageFlags::NO_EXECUTE.value();
+        }
+        boot.tables[i / PAE_ENTRIES][i % PAE_ENTRIES] = entry;
+    }
+
+    // Identity (PDE 0-1) and higher half (PDE 1536-1537 = dirs[3][0..2])
+    // share the two converted tables, like PDE[0] / PDE[768] do.
+    let table_flags = PageFlags::PRESENT | PageFlags::WRITABLE;
+    for half in 0..2 {
+        let table = kernel_phys(boot.tables[half].as_ptr() as *const u8);
+        let entry = PageEntry::new_wide(table, table_flags).0;
+        boot.dirs[0][half] = entry;
+        boot.dirs[PAE_DIRS - 1][half] = entry;
+    }
+
+    // The last four PDEs are the recursive slots (one per directory).
+    // PDPT entries only take P and the cache bits - RW/US are reserved.
+    for i in 0..PAE_DIRS {
+        let dir = kernel_phys(boot.dirs[i].as_ptr() as *const u8);
+        boot.dirs[PAE_DIRS - 1][PAE_ENTRIES - PAE_DIRS + i] =
+            PageEntry::new_wide(dir, table_flags).0;
+        boot.pdpt[i] = PageEntry::new_wide(dir, PageFlags::PRESENT).0;
+    }
+
+    if nx {
+        crate::utils::wrmsr(EFER, crate::utils::rdmsr(EFER) | EFER_NXE);
+    }
+    enable_pae_paging(kernel_phys(boot.pdpt.as_ptr() as *const u8) as u32);
+    PAE_ENABLED = true;
+    NX_ENABLED = nx;
+
+    PageDirectory {
+        entries: NonNull::new_unchecked(boot.dirs.as_mut_ptr() as *mut u8),
     }
 }
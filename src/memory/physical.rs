@@ -1,6 +1,12 @@
+use crate::m_print;
 use crate::m_println;
 use crate::{dbg_println, multiboot2::meminfo::MemoryInfoEntry};
 // use core::intrinsics::saturating_sub;
+use super::define::{BOOT_MAPPED_LIMIT, PHYSMAP_SIZE, ZONE_DMA_END, ZONE_NORMAL_END};
+#[cfg(feature = "frame_poison")]
+use super::physmap::is_direct_mapped;
+use super::physmap::{phys_to_virt, virt_to_phys};
+use core::ops::Range;
 use core::ptr::NonNull;
 pub const PAGE_SIZE: usize = 4096;
 
@@ -25,15 +31,165 @@ impl PhysFrame {
     }
 }
 
+// Physical memory zone a frame belongs to.  See define.rs for the limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    // Below 16 MB, reachable by ISA DMA
+    Dma = 0,
+    // Inside the kernel's direct-mapped window
+    Normal = 1,
+    // Everything else - needs an explicit mapping
+    High = 2,
+}
+
+pub const ZONE_COUNT: usize = 3;
+
+impl Zone {
+    pub const ALL: [Zone; ZONE_COUNT] = [Zone::Dma, Zone::Normal, Zone::High];
+
+    // Zone that owns frame number `frame`.
+    pub fn of_frame(frame: usize) -> Zone {
+        let addr = frame * PAGE_SIZE;
+        if addr < ZONE_DMA_END {
+            Zone::Dma
+        } else if addr < ZONE_NORMAL_END {
+            Zone::Normal
+        } else {
+            Zone::High
+        }
+    }
+
+    // Frame number range [start, end) covered by this zone, clamped to
+    // `total_frames`.  Empty if the zone lies above the end of RAM.
+    pub fn frame_range(self, total_frames: usize) -> core::ops::Range<usize> {
+        let (start, end) = match self {
+            Zone::Dma => (0, ZONE_DMA_END / PAGE_SIZE),
+            Zone::Normal => (ZONE_DMA_END / PAGE_SIZE, ZONE_NORMAL_END / PAGE_SIZE),
+            Zone::High => (ZONE_NORMAL_END / PAGE_SIZE, usize::MAX),
+        };
+        let end = core::cmp::min(end, total_frames);
+        core::cmp::min(start, end)..end
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Zone::Dma => "DMA",
+            Zone::Normal => "Normal",
+            Zone::High => "High",
+        }
+    }
+}
+
+// Who a frame was handed out to.  Purely informational for now, but it
+// lets diagnostics and future COW / page-cache code tell frames apart.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameOwner {
+    Free = 0,
+    // Generic kernel allocation (allocate_frame with no better tag)
+    Kernel,
+    Heap,
+    // Slab pages backing a KmemCache
+    Slab,
+    // Slab pages holding objects only, their header kept off the slab
+    SlabObjects,
+    PageTable,
+    User,
+    Dma,
+    // Kernel image, allocator metadata, firmware holes - never released
+    Reserved,
+}
+
+impl FrameOwner {
+    pub fn name(self) -> &'static str {
+        match self {
+            FrameOwner::Free => "free",
+            FrameOwner::Kernel => "kernel",
+            FrameOwner::Heap => "heap",
+            FrameOwner::Slab => "slab",
+            FrameOwner::SlabObjects => "slab objects",
+            FrameOwner::PageTable => "page table",
+            FrameOwner::User => "user",
+            FrameOwner::Dma => "dma",
+            FrameOwner::Reserved => "reserved",
+        }
+    }
+}
+
+// Per-frame metadata, one entry per physical frame (Linux's `struct page`).
+//
+// A frame with refcount 0 that is marked used in the bitmap is a
+// reserved frame: it was never handed out by the allocator and
+// deallocate_frame() refuses to release it.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Page {
+    pub refcount: u16,
+    pub owner: FrameOwner,
+    pub flags: u8,
+}
+
+impl Page {
+    // Frame must never be released, whatever its refcount says
+    pub const PINNED: u8 = 1 << 0;
+    // Frame is free and was filled with FRAME_POISON (frame_poison feature)
+    pub const POISONED: u8 = 1 << 1;
+
+    const fn empty() -> Self {
+        Page {
+            refcount: 0,
+            owner: FrameOwner::Free,
+            flags: 0,
+        }
+    }
+
+    const fn reserved() -> Self {
+        Page {
+            refcount: 0,
+            owner: FrameOwner::Reserved,
+            flags: Page::PINNED,
+        }
+    }
+}
+
+// Multiboot memory map type for usable RAM
+const MEMORY_AVAILABLE: u32 = 1;
+
+// Frame numbers and PhysAddr are 32-bit: RAM at or above 4 GB is left
+// out until they are widened.
+const PHYS_ADDR_LIMIT: u64 = 1 << 32;
+
+// Metadata changes the allocator can hold before the Page table is
+// reachable (see FrameAllocator::attach_metadata()).  Only the early
+// page tables are allocated that soon.
+const EARLY_PAGES: usize = 64;
+
+// Pattern written over freed frames when the `frame_poison` feature is
+// enabled.  0x6B is the byte Linux uses for freed slab objects, so it is
+// easy to recognise in a hexdump.
+#[cfg(feature = "frame_poison")]
+const FRAME_POISON: u32 = 0x6B6B_6B6B;
+
 pub struct FrameAllocator {
     // Bitmap for frame allocation status. Each bit represents one frame
     bitmap: NonNull<[u8]>,
+    // Metadata table, indexed by frame number.  Only reachable once
+    // `pages_ready`; until then `early` holds the entries that differ
+    // from what the bitmap implies (see page()).
+    pages: NonNull<[Page]>,
+    pages_ready: bool,
+    early: [(usize, Page); EARLY_PAGES],
+    early_len: usize,
     // Total number of frames being managed
     total_frames: usize,
     // Next frame to check when allocating (simple optimization)
     next_free_frame: usize,
     // Statistics
     used_frames: usize,
+    // Usable RAM frames per zone, as reported by the memory map
+    zone_present: [usize; ZONE_COUNT],
+    // Currently free frames per zone
+    zone_free: [usize; ZONE_COUNT],
 }
 
 #[derive(Debug)]
@@ -41,6 +197,32 @@ pub enum AllocationError {
     NoFramesAvailable,
     InvalidFrame,
     FrameInUse,
+    // count was zero or align was not a power of two
+    InvalidRequest,
+    // Frame was never handed out by the allocator (kernel image, holes...)
+    FrameReserved,
+    // Refcount would overflow
+    TooManyReferences,
+}
+
+// Largest block order reported in the fragmentation histogram.
+// Order 10 = 1024 frames = 4 MB, the size of a PSE large page.
+pub const MAX_ORDER: usize = 10;
+
+// Snapshot of how the free frames are laid out in the bitmap.
+//
+// `free_blocks[k]` counts the naturally aligned free blocks of 2^k frames
+// you would get by splitting every free run the way a buddy allocator
+// would (largest aligned block first).  A healthy allocator has most of
+// its free memory in the high orders.
+#[derive(Debug, Clone, Copy)]
+pub struct FragmentationStats {
+    // Number of maximal runs of consecutive free frames
+    pub free_runs: usize,
+    // Length in frames of the longest free run
+    pub largest_run: usize,
+    // Buddy-style histogram, indexed by order
+    pub free_blocks: [usize; MAX_ORDER + 1],
 }
 
 impl FrameAllocator {
@@ -54,21 +236,11 @@ impl FrameAllocator {
             dbg_println!(
                 "Frame allocator bitmap: phys {:#x}, virt {:#x}",
                 bitmap_addr,
-                bitmap_addr + super::define::KERNEL_OFFSET
+                phys_to_virt(bitmap_addr)
             );
         }
-        let mut highest_addr = 0;
-
-        // Find highest address to determine number of frames needed
-        for entry in memory_map {
-            let end_addr = (entry.base_addr + entry.length) as usize;
-            if end_addr > highest_addr {
-                highest_addr = end_addr;
-            }
-        }
-
-        let total_frames = (highest_addr + PAGE_SIZE - 1) / PAGE_SIZE; // Round up
-        let bitmap_size = (total_frames + 7) / 8; // Round up to nearest byte
+        let total_frames = usable_frame_limit(memory_map);
+        let bitmap_size = total_frames.div_ceil(8);
 
         dbg_println!(
             "Total frames to manage: {} ({} MB)",
@@ -77,46 +249,96 @@ impl FrameAllocator {
         );
         dbg_println!("Bitmap size: {} bytes", bitmap_size);
 
+        // The Page table goes in the first usable run past the bitmap
+        // that is big enough and inside the physmap.  Below the boot
+        // mapping it is usable right away, otherwise from
+        // attach_metadata() on.
+        let pages_size = total_frames * core::mem::size_of::<Page>();
+        let pages_addr = find_free_run(memory_map, bitmap_addr + bitmap_size, pages_size)
+            .expect("No usable RAM run large enough for the frame metadata");
+        let pages_ready = pages_addr + pages_size <= BOOT_MAPPED_LIMIT;
+        dbg_println!(
+            "Frame metadata: {} bytes at phys {:#x}{}",
+            pages_size,
+            pages_addr,
+            if pages_ready {
+                ""
+            } else {
+                " (mapped with the physmap)"
+            }
+        );
+
         // Create bitmap accessor.
         let bitmap = unsafe {
-            let virt_addr = bitmap_addr + super::define::KERNEL_OFFSET;
-            let ptr = virt_addr as *mut u8;
+            let ptr = phys_to_virt(bitmap_addr) as *mut u8;
             // Initialize bitmap to all 1s (all frames marked as used initially)
             for i in 0..bitmap_size {
                 ptr.add(i).write(0xFF);
             }
-            NonNull::new_unchecked(core::slice::from_raw_parts_mut(ptr, bitmap_size))
+            NonNull::new_unchecked(core::ptr::slice_from_raw_parts_mut(ptr, bitmap_size))
+        };
+
+        let pages = unsafe {
+            let ptr = phys_to_virt(pages_addr) as *mut Page;
+            if pages_ready {
+                for i in 0..total_frames {
+                    ptr.add(i).write(Page::empty());
+                }
+            }
+            NonNull::new_unchecked(core::ptr::slice_from_raw_parts_mut(ptr, total_frames))
         };
 
         let mut allocator = FrameAllocator {
             bitmap,
+            pages,
+            pages_ready,
+            early: [(0, Page::empty()); EARLY_PAGES],
+            early_len: 0,
             total_frames,
             next_free_frame: 0,
             used_frames: total_frames, // Start with all frames marked as used
+            zone_present: [0; ZONE_COUNT],
+            zone_free: [0; ZONE_COUNT],
         };
 
         // Mark available regions as free
         for entry in memory_map {
-            if entry.typee == 1 {
-                // Available memory
-                let start_frame = PhysFrame::containing_address(entry.base_addr as usize);
-                let end_frame =
-                    PhysFrame::containing_address((entry.base_addr + entry.length) as usize);
+            if let Some(frames) = usable_frames(entry) {
                 dbg_println!(
                     "Marking frames {}-{} as free (region {:#x}-{:#x})",
-                    start_frame.number,
-                    end_frame.number,
+                    frames.start,
+                    frames.end,
                     entry.base_addr,
                     entry.base_addr + entry.length
                 );
-                for frame in start_frame.number..end_frame.number {
+                for frame in frames {
                     allocator.mark_frame_free(frame);
                 }
+            } else if entry.typee == MEMORY_AVAILABLE {
+                dbg_println!(
+                    "Ignoring RAM above 4 GB (region {:#x}-{:#x})",
+                    entry.base_addr,
+                    entry.base_addr + entry.length
+                );
             }
         }
 
+        // Everything free at this point is usable RAM
+        allocator.zone_present = allocator.zone_free;
+
         allocator.protect_kernel_region();
         allocator.protect_bitmap_region(bitmap_addr, bitmap_size);
+        allocator.protect_bitmap_region(pages_addr, pages_size);
+
+        // Whatever is still used now was never allocated: tag it
+        // reserved (page() infers the same until the table is attached)
+        if allocator.pages_ready {
+            for frame in 0..total_frames {
+                if allocator.is_frame_used(frame) {
+                    allocator.set_page(frame, Page::reserved());
+                }
+            }
+        }
         dbg_println!(
             "Frame allocator initialized: {} free frames available",
             allocator.total_frames - allocator.used_frames
@@ -131,10 +353,8 @@ impl FrameAllocator {
         }
 
         unsafe {
-            let kernel_start_phys =
-                (&_kernel_start as *const u8 as usize).saturating_sub(super::define::KERNEL_OFFSET);
-            let kernel_end_phys =
-                (&_kernel_end as *const u8 as usize).saturating_sub(super::define::KERNEL_OFFSET);
+            let kernel_start_phys = virt_to_phys(&_kernel_start as *const u8 as usize);
+            let kernel_end_phys = virt_to_phys(&_kernel_end as *const u8 as usize);
 
             let start_frame = PhysFrame::containing_address(kernel_start_phys);
             let end_frame = PhysFrame::containing_address(kernel_end_phys);
@@ -155,7 +375,7 @@ impl FrameAllocator {
         }
     }
 
-    // Protect bitmap memory region from allocation
+    // Protect allocator metadata (bitmap or page table) from allocation
     fn protect_bitmap_region(&mut self, bitmap_addr: usize, bitmap_size: usize) {
         let start_frame = PhysFrame::containing_address(bitmap_addr);
         let end_frame = PhysFrame::containing_address(bitmap_addr + bitmap_size - 1);
@@ -181,7 +401,7 @@ impl FrameAllocator {
             let frame = (self.next_free_frame + offset) % self.total_frames;
 
             if !self.is_frame_used(frame) {
-                self.mark_frame_used(frame);
+                self.claim_frame(frame, FrameOwner::Kernel);
                 self.next_free_frame = (frame + 1) % self.total_frames;
 
                 dbg_println!(
@@ -197,6 +417,122 @@ impl FrameAllocator {
         Err(AllocationError::NoFramesAvailable)
     }
 
+    // Allocate `count` physically contiguous frames whose first frame
+    // number is a multiple of `align` (in frames, power of two).
+    //
+    // e.g. allocate_frames(1024, 1024) returns a 4 MB-aligned 4 MB block
+    // suitable for a PSE page.  The whole run is marked used in the
+    // bitmap so it is accounted for exactly like single frames.
+    pub fn allocate_frames(
+        &mut self,
+        count: usize,
+        align: usize,
+    ) -> Result<PhysFrame, AllocationError> {
+        if count == 0 || !align.is_power_of_two() {
+            return Err(AllocationError::InvalidRequest);
+        }
+        if count == 1 && align == 1 {
+            return self.allocate_frame();
+        }
+
+        self.allocate_run(0..self.total_frames, count, align, FrameOwner::Kernel)
+    }
+
+    // Allocate a single frame from `zone`.
+    //
+    // e.g. allocate_frame_in(Zone::Dma) for an ISA DMA bounce buffer.
+    pub fn allocate_frame_in(&mut self, zone: Zone) -> Result<PhysFrame, AllocationError> {
+        self.allocate_frames_in(zone, 1, 1)
+    }
+
+    // Zone-restricted version of allocate_frames().  The whole run lies
+    // inside `zone`.
+    pub fn allocate_frames_in(
+        &mut self,
+        zone: Zone,
+        count: usize,
+        align: usize,
+    ) -> Result<PhysFrame, AllocationError> {
+        if count == 0 || !align.is_power_of_two() {
+            return Err(AllocationError::InvalidRequest);
+        }
+        if self.zone_free[zone as usize] < count {
+            return Err(AllocationError::NoFramesAvailable);
+        }
+
+        let owner = if zone == Zone::Dma {
+            FrameOwner::Dma
+        } else {
+            FrameOwner::Kernel
+        };
+        self.allocate_run(zone.frame_range(self.total_frames), count, align, owner)
+    }
+
+    // Find and claim `count` free frames inside `range`, starting on an
+    // `align` boundary.
+    fn allocate_run(
+        &mut self,
+        range: core::ops::Range<usize>,
+        count: usize,
+        align: usize,
+        owner: FrameOwner,
+    ) -> Result<PhysFrame, AllocationError> {
+        let mut start = (range.start + align - 1) & !(align - 1);
+        while start + count <= range.end {
+            match self.first_used_in(start, count) {
+                None => {
+                    for frame in start..start + count {
+                        self.claim_frame(frame, owner);
+                    }
+                    dbg_println!(
+                        "Allocated {} contiguous frames {}-{} at phys addr {:#x}",
+                        count,
+                        start,
+                        start + count - 1,
+                        PhysFrame { number: start }.start_address()
+                    );
+                    return Ok(PhysFrame { number: start });
+                }
+                // Skip past the used frame to the next aligned candidate
+                Some(used) => start = (used + 1 + align - 1) & !(align - 1),
+            }
+        }
+
+        Err(AllocationError::NoFramesAvailable)
+    }
+
+    // Release a run previously returned by allocate_frames().
+    pub fn deallocate_frames(
+        &mut self,
+        start: PhysFrame,
+        count: usize,
+    ) -> Result<(), AllocationError> {
+        if count == 0 {
+            return Err(AllocationError::InvalidRequest);
+        }
+        if start.number + count > self.total_frames {
+            return Err(AllocationError::InvalidFrame);
+        }
+
+        // Check the whole run before touching any of it, so an error
+        // leaves every frame as it was
+        for frame in start.number..start.number + count {
+            if !self.is_frame_used(frame) {
+                return Err(AllocationError::InvalidFrame);
+            }
+            let page = self.page(frame);
+            if page.refcount == 0 || page.flags & Page::PINNED != 0 {
+                return Err(AllocationError::FrameReserved);
+            }
+        }
+
+        for frame in start.number..start.number + count {
+            self.deallocate_frame(PhysFrame { number: frame })?;
+        }
+
+        Ok(())
+    }
+
     // Allocate a specific physical frame (useful for DMA or memory-mapped I/O)
     pub fn allocate_specific_frame(&mut self, frame: PhysFrame) -> Result<(), AllocationError> {
         if frame.number >= self.total_frames {
@@ -207,7 +543,7 @@ impl FrameAllocator {
             return Err(AllocationError::FrameInUse);
         }
 
-        self.mark_frame_used(frame.number);
+        self.claim_frame(frame.number, FrameOwner::Kernel);
 
         dbg_println!(
             "Allocated specific frame {} at phys addr {:#x}",
@@ -217,8 +553,35 @@ impl FrameAllocator {
 
         Ok(())
     }
-    // Deallocate a physical frame
+    // Drop one reference to a physical frame.  The frame only goes back
+    // to the bitmap once its refcount reaches zero.
     pub fn deallocate_frame(&mut self, frame: PhysFrame) -> Result<(), AllocationError> {
+        self.put_frame(frame).map(|_| ())
+    }
+
+    // Take an extra reference to an allocated frame (shared mappings,
+    // copy-on-write).  Each get_frame() must be balanced by a put_frame()
+    // or deallocate_frame().
+    pub fn get_frame(&mut self, frame: PhysFrame) -> Result<u16, AllocationError> {
+        if frame.number >= self.total_frames || !self.is_frame_used(frame.number) {
+            return Err(AllocationError::InvalidFrame);
+        }
+
+        let mut page = self.page(frame.number);
+        if page.refcount == 0 {
+            return Err(AllocationError::FrameReserved);
+        }
+        page.refcount = page
+            .refcount
+            .checked_add(1)
+            .ok_or(AllocationError::TooManyReferences)?;
+        self.set_page(frame.number, page);
+        Ok(page.refcount)
+    }
+
+    // Drop one reference.  Returns the remaining refcount; 0 means the
+    // frame was released back to the allocator.
+    pub fn put_frame(&mut self, frame: PhysFrame) -> Result<u16, AllocationError> {
         if frame.number >= self.total_frames {
             return Err(AllocationError::InvalidFrame);
         }
@@ -228,19 +591,140 @@ impl FrameAllocator {
                 "Warning: double-free of already-free frame {}",
                 frame.number
             );
+            return Ok(0);
         }
 
+        let mut page = self.page(frame.number);
+        if page.refcount == 0 || page.flags & Page::PINNED != 0 {
+            dbg_println!(
+                "Warning: refusing to free reserved frame {} ({})",
+                frame.number,
+                page.owner.name()
+            );
+            return Err(AllocationError::FrameReserved);
+        }
+
+        page.refcount -= 1;
+        if page.refcount > 0 {
+            self.set_page(frame.number, page);
+            return Ok(page.refcount);
+        }
+
+        self.set_page(frame.number, Page::empty());
         self.mark_frame_free(frame.number);
 
+        #[cfg(feature = "frame_poison")]
+        self.poison_frame(frame.number);
+
         // Update next_free_frame hint so subsequent allocations find
         // this slot sooner.
         if frame.number < self.next_free_frame {
             self.next_free_frame = frame.number;
         }
 
+        Ok(0)
+    }
+
+    // Metadata of an allocated or reserved frame.
+    pub fn frame_info(&self, frame: PhysFrame) -> Option<Page> {
+        if frame.number >= self.total_frames {
+            return None;
+        }
+        Some(self.page(frame.number))
+    }
+
+    // Re-tag an allocated frame, e.g. FrameOwner::Heap once the heap has
+    // mapped it.
+    pub fn set_owner(
+        &mut self,
+        frame: PhysFrame,
+        owner: FrameOwner,
+    ) -> Result<(), AllocationError> {
+        if frame.number >= self.total_frames || !self.is_frame_used(frame.number) {
+            return Err(AllocationError::InvalidFrame);
+        }
+        let mut page = self.page(frame.number);
+        if page.refcount == 0 {
+            return Err(AllocationError::FrameReserved);
+        }
+        page.owner = owner;
+        self.set_page(frame.number, page);
         Ok(())
     }
 
+    // Metadata of `frame`.  Before the table is attached, a frame with
+    // no early entry is either free or still reserved from boot.
+    fn page(&self, frame: usize) -> Page {
+        if self.pages_ready {
+            return unsafe { self.pages.as_ref()[frame] };
+        }
+        match self.early[..self.early_len].iter().find(|e| e.0 == frame) {
+            Some(&(_, page)) => page,
+            None if self.is_frame_used(frame) => Page::reserved(),
+            None => Page::empty(),
+        }
+    }
+
+    fn set_page(&mut self, frame: usize, page: Page) {
+        if self.pages_ready {
+            unsafe { self.pages.as_mut()[frame] = page };
+            return;
+        }
+        let slot = self.early[..self.early_len]
+            .iter()
+            .position(|e| e.0 == frame);
+        match slot {
+            // Back to what the bitmap implies: forget the entry
+            Some(i) if page == Page::empty() => {
+                self.early_len -= 1;
+                self.early[i] = self.early[self.early_len];
+            }
+            Some(i) => self.early[i].1 = page,
+            None if page == Page::empty() => {}
+            None => {
+                assert!(
+                    self.early_len < EARLY_PAGES,
+                    "More than {} frames allocated before the frame metadata was mapped",
+                    EARLY_PAGES
+                );
+                self.early[self.early_len] = (frame, page);
+                self.early_len += 1;
+            }
+        }
+    }
+
+    // Start using the Page table once the physmap covers it, filling it
+    // in from the bitmap and the early entries.  Called right after
+    // physmap::init(); a no-op if the table sat in the boot mapping.
+    pub fn attach_metadata(&mut self) {
+        if self.pages_ready {
+            return;
+        }
+        let table = self.pages.as_ptr() as *mut Page;
+        for frame in 0..self.total_frames {
+            unsafe { table.add(frame).write(self.page(frame)) };
+        }
+        self.pages_ready = true;
+        self.early_len = 0;
+        dbg_println!("Frame metadata table attached");
+    }
+
+    // Mark a free frame used and give it its first reference.
+    fn claim_frame(&mut self, frame: usize, owner: FrameOwner) {
+        #[cfg(feature = "frame_poison")]
+        self.check_poison(frame);
+
+        self.mark_frame_used(frame);
+        self.set_page(
+            frame,
+            Page {
+                refcount: 1,
+                owner,
+                flags: 0,
+            },
+        );
+    }
+
     // Check if a frame is marked as used
     fn is_frame_used(&self, frame: usize) -> bool {
         let byte_index = frame / 8;
@@ -251,6 +735,84 @@ impl FrameAllocator {
         }
     }
 
+    // Kernel virtual address of `frame`, if it is reachable without
+    // building a mapping.
+    #[cfg(feature = "frame_poison")]
+    fn frame_virt(frame: usize) -> Option<*mut u32> {
+        let phys = PhysFrame { number: frame };
+        if !is_direct_mapped(phys.start_address(), PAGE_SIZE) {
+            return None;
+        }
+        Some(phys_to_virt(phys.start_address()) as *mut u32)
+    }
+
+    // Fill a just-released frame with FRAME_POISON.  Frames we cannot
+    // reach are left alone and will not be checked on reuse.
+    #[cfg(feature = "frame_poison")]
+    fn poison_frame(&mut self, frame: usize) {
+        if let Some(ptr) = Self::frame_virt(frame) {
+            for i in 0..PAGE_SIZE / 4 {
+                unsafe { ptr.add(i).write_volatile(FRAME_POISON) };
+            }
+            let mut page = self.page(frame);
+            page.flags |= Page::POISONED;
+            self.set_page(frame, page);
+        }
+    }
+
+    // Verify a poisoned frame is still intact before handing it out.
+    // Any write since it was freed means somebody kept using it:
+    // that is a use-after-free and we stop the machine right there.
+    #[cfg(feature = "frame_poison")]
+    fn check_poison(&mut self, frame: usize) {
+        if self.page(frame).flags & Page::POISONED == 0 {
+            return;
+        }
+        let ptr = match Self::frame_virt(frame) {
+            Some(ptr) => ptr,
+            None => return,
+        };
+        for i in 0..PAGE_SIZE / 4 {
+            let word = unsafe { ptr.add(i).read_volatile() };
+            if word != FRAME_POISON {
+                let diff = word ^ FRAME_POISON;
+                let offset = i * 4 + diff.trailing_zeros() as usize / 8;
+                panic!(
+                    "use-after-free: frame {} (phys {:#x}) modified while free, \
+                     first corrupted offset {:#x} (found {:#010x})",
+                    frame,
+                    PhysFrame { number: frame }.start_address(),
+                    offset,
+                    word
+                );
+            }
+        }
+        let mut page = self.page(frame);
+        page.flags &= !Page::POISONED;
+        self.set_page(frame, page);
+    }
+
+    // Return the first used frame in [start, start + count), if any.
+    // Whole 0x00 bytes are skipped eight frames at a time.
+    fn first_used_in(&self, start: usize, count: usize) -> Option<usize> {
+        let end = start + count;
+        let mut frame = start;
+        while frame < end {
+            if frame.is_multiple_of(8) && frame + 8 <= end {
+                let byte = unsafe { self.bitmap.as_ref()[frame / 8] };
+                if byte == 0 {
+                    frame += 8;
+                    continue;
+                }
+            }
+            if self.is_frame_used(frame) {
+                return Some(frame);
+            }
+            frame += 1;
+        }
+        None
+    }
+
     // Mark a frame as used in the bitmap
     fn mark_frame_used(&mut self, frame: usize) {
         if !self.is_frame_used(frame) {
@@ -261,6 +823,7 @@ impl FrameAllocator {
                 bitmap[byte_index] |= 1 << bit_index;
             }
             self.used_frames += 1;
+            self.zone_free[Zone::of_frame(frame) as usize] -= 1;
         }
     }
 
@@ -276,6 +839,7 @@ impl FrameAllocator {
             bitmap[byte_index] &= !(1 << bit_index);
         }
         self.used_frames = self.used_frames.saturating_sub(1);
+        self.zone_free[Zone::of_frame(frame) as usize] += 1;
     }
 
     // Get the total number of frames
@@ -293,6 +857,35 @@ impl FrameAllocator {
         self.total_frames - self.used_frames
     }
 
+    // Usable RAM frames in `zone`
+    pub fn zone_present_frames(&self, zone: Zone) -> usize {
+        self.zone_present[zone as usize]
+    }
+
+    // Free frames in `zone`
+    pub fn zone_free_frames(&self, zone: Zone) -> usize {
+        self.zone_free[zone as usize]
+    }
+
+    // Print one line of usage per zone
+    pub fn print_zone_stats(&self) {
+        m_println!("  Zones:");
+        for zone in Zone::ALL {
+            let range = zone.frame_range(self.total_frames);
+            let present = self.zone_present_frames(zone);
+            let free = self.zone_free_frames(zone);
+            m_println!(
+                "    {:<6} {:#010x}-{:#010x}  {} / {} frames used ({} KB free)",
+                zone.name(),
+                range.start * PAGE_SIZE,
+                range.end * PAGE_SIZE,
+                present.saturating_sub(free),
+                present,
+                free * PAGE_SIZE / 1024
+            );
+        }
+    }
+
     // Get memory statistics in bytes
     pub fn memory_stats(&self) -> (usize, usize, usize) {
         let total_bytes = self.total_frames * PAGE_SIZE;
@@ -301,6 +894,47 @@ impl FrameAllocator {
         (total_bytes, used_bytes, free_bytes)
     }
 
+    // Walk the bitmap and describe how fragmented the free space is.
+    pub fn fragmentation(&self) -> FragmentationStats {
+        let mut stats = FragmentationStats {
+            free_runs: 0,
+            largest_run: 0,
+            free_blocks: [0; MAX_ORDER + 1],
+        };
+
+        let mut frame = 0;
+        while frame < self.total_frames {
+            if self.is_frame_used(frame) {
+                frame += 1;
+                continue;
+            }
+
+            let run_start = frame;
+            while frame < self.total_frames && !self.is_frame_used(frame) {
+                frame += 1;
+            }
+            let run_len = frame - run_start;
+
+            stats.free_runs += 1;
+            if run_len > stats.largest_run {
+                stats.largest_run = run_len;
+            }
+
+            // Split the run into naturally aligned power-of-two blocks
+            let mut block = run_start;
+            while block < frame {
+                let mut order = MAX_ORDER;
+                while block & ((1 << order) - 1) != 0 || block + (1 << order) > frame {
+                    order -= 1;
+                }
+                stats.free_blocks[order] += 1;
+                block += 1 << order;
+            }
+        }
+
+        stats
+    }
+
     // Print memory statistics
     pub fn print_stats(&self) {
         let (total, used, free) = self.memory_stats();
@@ -320,6 +954,25 @@ impl FrameAllocator {
             free / (1024 * 1024),
             self.free_frames()
         );
+        self.print_zone_stats();
+
+        let frag = self.fragmentation();
+        let free = self.free_frames();
+        // Share of free memory that is NOT in the largest run
+        let frag_pct = (frag.largest_run * 100)
+            .checked_div(free)
+            .map_or(0, |largest_pct| 100 - largest_pct);
+        m_println!(
+            "  Largest free run: {} frames in {} run(s), fragmentation {}%",
+            frag.largest_run,
+            frag.free_runs,
+            frag_pct
+        );
+        m_print!("  Free blocks by order:");
+        for (order, count) in frag.free_blocks.iter().enumerate() {
+            m_print!(" {}:{}", order, count);
+        }
+        m_println!();
     }
 }
 
@@ -331,8 +984,7 @@ pub fn init_frame_allocator(memory_map: &[MemoryInfoEntry]) {
     }
 
     let bitmap_addr = unsafe {
-        let kernel_end_phys =
-            (&_kernel_end as *const u8 as usize).saturating_sub(super::define::KERNEL_OFFSET);
+        let kernel_end_phys = virt_to_phys(&_kernel_end as *const u8 as usize);
         // Align to next page boundary
         (kernel_end_phys + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
     };
@@ -346,3 +998,98 @@ pub fn init_frame_allocator(memory_map: &[MemoryInfoEntry]) {
         }
     }
 }
+
+// Frames of a usable memory-map entry, clipped to PHYS_ADDR_LIMIT.
+// None if the entry is not usable RAM or lies wholly above the limit.
+fn usable_frames(entry: &MemoryInfoEntry) -> Option<Range<usize>> {
+    if entry.typee != MEMORY_AVAILABLE {
+        return None;
+    }
+    let end = entry.base_addr.saturating_add(entry.length);
+    let start = entry.base_addr.min(PHYS_ADDR_LIMIT) / PAGE_SIZE as u64;
+    let end = end.min(PHYS_ADDR_LIMIT) / PAGE_SIZE as u64;
+    (start < end).then_some(start as usize..end as usize)
+}
+
+// Does any frame of [first, first + count) lie in usable RAM, as the
+// boot memory map describes it?  This includes the frames the allocator
+// keeps for itself: kernel image, bitmap and frame metadata.
+pub fn overlaps_usable_ram(first: usize, count: usize) -> bool {
+    let Some(memory_map) = crate::multiboot2::meminfo::get_memory_map() else {
+        return false;
+    };
+    memory_map
+        .iter()
+        .filter_map(usable_frames)
+        .any(|frames| frames.start < first + count && first < frames.end)
+}
+
+// One past the highest usable frame.  Everything is sized from the end
+// of usable RAM: reserved ranges above it (firmware, MMIO) never hold
+// frames we hand out.
+fn usable_frame_limit(memory_map: &[MemoryInfoEntry]) -> usize {
+    memory_map
+        .iter()
+        .filter_map(usable_frames)
+        .map(|frames| frames.end)
+        .max()
+        .unwrap_or(0)
+}
+
+// Start of the first page-aligned run of `size` bytes of usable RAM at
+// or above `floor`, inside the physmap.
+fn find_free_run(memory_map: &[MemoryInfoEntry], floor: usize, size: usize) -> Option<usize> {
+    let align = |addr: u64| (addr + PAGE_SIZE as u64 - 1) & !(PAGE_SIZE as u64 - 1);
+    memory_map
+        .iter()
+        .filter(|entry| entry.typee == MEMORY_AVAILABLE)
+        .find_map(|entry| {
+            let start = align(entry.base_addr.max(floor as u64));
+            let end = entry
+                .base_addr
+                .saturating_add(entry.length)
+                .min(PHYSMAP_SIZE as u64);
+            (start + size as u64 <= end).then_some(start as usize)
+        })
+}
+
+// See FrameAllocator::attach_metadata().
+pub fn attach_frame_metadata() {
+    unsafe {
+        if let Some(allocator) = FRAME_ALLOCATOR.as_mut() {
+            allocator.attach_metadata();
+        }
+    }
+}
+
+// Runs on a synthetic memory map, so it can be called at any point.
+pub fn test_memory_map_clip() {
+    m_print!("[frame test] memory map entries above 4 GB ... ");
+    const MB: u64 = 1 << 20;
+    let map = [
+        MemoryInfoEntry::new(0, 0x9F000, MEMORY_AVAILABLE),
+        MemoryInfoEntry::new(MB, 127 * MB, MEMORY_AVAILABLE),
+        // Straddles 4 GB: only the part below is kept
+        MemoryInfoEntry::new(PHYS_ADDR_LIMIT - 2 * MB, 4 * MB, MEMORY_AVAILABLE),
+        // Wholly above 4 GB: must not wrap onto low frames
+        MemoryInfoEntry::new(PHYS_ADDR_LIMIT, 1024 * MB, MEMORY_AVAILABLE),
+        MemoryInfoEntry::new(PHYS_ADDR_LIMIT + 4096 * MB, 2 * MB, 2),
+    ];
+
+    assert_eq!(usable_frames(&map[0]), Some(0..0x9F));
+    assert_eq!(usable_frames(&map[1]), Some(0x100..0x8000));
+    assert_eq!(usable_frames(&map[2]), Some(0xFFE00..0x100000));
+    assert_eq!(usable_frames(&map[3]), None);
+    assert_eq!(usable_frames(&map[4]), None);
+    assert_eq!(usable_frame_limit(&map), 0x100000);
+    assert_eq!(usable_frame_limit(&[map[0], map[3]]), 0x9F);
+
+    // The run search stays inside the physmap and below 4 GB
+    assert_eq!(find_free_run(&map[3..], 0, PAGE_SIZE), None);
+    assert_eq!(
+        find_free_run(&map, 2 * MB as usize, PAGE_SIZE),
+        Some(2 * MB as usize)
+    );
+
+    m_println!("OK");
+}
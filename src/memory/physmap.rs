@@ -0,0 +1,108 @@
+// memory/physmap.rs - Linear map of physical memory in the kernel half
+//
+// bootstrap.asm maps the first 4 MB of RAM at KERNEL_OFFSET.  init()
+// extends that linear map over every usable RAM range multiboot
+// reports, up to PHYSMAP_SIZE (where the heap window starts):
+//
+//   virt = phys + KERNEL_OFFSET          for phys < PHYSMAP_SIZE
+//
+// The extension uses large pages wherever alignment allows, is never
+// executable and is never unmapped.  Holes in the memory map (MMIO,
+// ACPI tables, ...) are left out.  RAM above PHYSMAP_SIZE is only
+// reachable through kmap.
+//
+// phys_to_virt() / virt_to_phys() are the only place that does the
+// KERNEL_OFFSET arithmetic.  Before init() only the boot 4 MB is backed,
+// which is all the early code (frame allocator, GDT) touches.
+
+use super::define::{BOOT_MAPPED_LIMIT, KERNEL_OFFSET, PAGE_SIZE, PHYSMAP_SIZE};
+use super::pageflags::PageFlags;
+use super::vmm::{self, PhysAddr, VirtAddr};
+use crate::dbg_println;
+use crate::multiboot2::meminfo::MemoryInfoEntry;
+
+// Multiboot memory map type for usable RAM
+const MEMORY_AVAILABLE: u32 = 1;
+
+// End of the highest RAM range mapped so far
+static mut PHYSMAP_END: usize = BOOT_MAPPED_LIMIT;
+
+// Kernel virtual address of physical address `phys`.
+#[inline]
+pub fn phys_to_virt(phys: usize) -> usize {
+    assert!(
+        phys < PHYSMAP_SIZE,
+        "phys_to_virt: {:#x} is outside the physmap",
+        phys
+    );
+    phys + KERNEL_OFFSET
+}
+
+// Physical address behind a physmap (or kernel image) address.
+#[inline]
+pub fn virt_to_phys(virt: usize) -> usize {
+    assert!(
+        contains(virt),
+        "virt_to_phys: {:#x} is outside the physmap",
+        virt
+    );
+    virt - KERNEL_OFFSET
+}
+
+// Does kernel address `virt` fall inside the physmap window?
+#[inline]
+pub fn contains(virt: usize) -> bool {
+    (KERNEL_OFFSET..KERNEL_OFFSET + PHYSMAP_SIZE).contains(&virt)
+}
+
+// Is the RAM range [phys, phys + len) reachable through the physmap
+// right now?  Only meaningful for RAM: holes below the end stay unmapped.
+#[inline]
+pub fn is_direct_mapped(phys: usize, len: usize) -> bool {
+    phys.checked_add(len)
+        .is_some_and(|end| end <= unsafe { PHYSMAP_END })
+}
+
+// End of the physmap: physical addresses below it are direct mapped.
+pub fn end() -> usize {
+    unsafe { PHYSMAP_END }
+}
+
+// Map every usable RAM range between the boot mapping and PHYSMAP_SIZE.
+// Must run after vmm::init() and the frame allocator (page tables for
+// unaligned edges come from it).
+pub fn init(memory_map: &[MemoryInfoEntry]) {
+    let flags = PageFlags::PRESENT | PageFlags::WRITABLE | PageFlags::NO_EXECUTE;
+    let mut mapped = 0;
+
+    for entry in memory_map.iter().filter(|e| e.typee == MEMORY_AVAILABLE) {
+        let limit = PHYSMAP_SIZE as u64;
+        let start = entry.base_addr.max(BOOT_MAPPED_LIMIT as u64);
+        let end = (entry.base_addr + entry.length).min(limit);
+        // Only whole pages: round the start up and the end down
+        let start = ((start + PAGE_SIZE as u64 - 1) & !(PAGE_SIZE as u64 - 1)) as usize;
+        let end = (end & !(PAGE_SIZE as u64 - 1)) as usize;
+        if start >= end {
+            continue;
+        }
+
+        vmm::map_range_to(
+            VirtAddr::new(phys_to_virt(start) as u32),
+            PhysAddr::new(start as u32),
+            end - start,
+            flags,
+        )
+        .expect("physmap: cannot map RAM");
+        mapped += end - start;
+        unsafe {
+            PHYSMAP_END = PHYSMAP_END.max(end);
+        }
+    }
+
+    dbg_println!(
+        "physmap: {} KB mapped, phys 0..{:#x} at {:#x}",
+        mapped / 1024,
+        end(),
+        KERNEL_OFFSET
+    );
+}
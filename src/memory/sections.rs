@@ -0,0 +1,141 @@
+// memory/sections.rs - Kernel image sections and W^X
+//
+// linker.ld gives .text, .rodata and .data/.bss their own 4 KB-aligned
+// ranges and exports their bounds.  bootstrap.asm maps the whole first
+// 4 MB read/write, so protect_kernel() tightens the image once paging
+// is set up:
+//
+//   .text          read-only, executable
+//   .rodata        read-only, no-execute
+//   .data + .bss   read/write, no-execute (includes the boot stack)
+//
+// CR0.WP (set by vmm::init) makes the read-only bits bind ring 0 too,
+// so a stray kernel write to code or constants page-faults, and the
+// handler names the section through section_of().  No-execute only
+// takes effect under PAE with NX; legacy paging has no such bit.
+
+use super::define::PAGE_SIZE;
+use super::pageflags::PageFlags;
+use super::paging;
+use super::vmm::{self, VirtAddr};
+use crate::{dbg_println, m_print, m_println};
+
+extern "C" {
+    static _text_start: u8;
+    static _text_end: u8;
+    static _rodata_start: u8;
+    static _rodata_end: u8;
+    static _data_start: u8;
+    static _data_end: u8;
+}
+
+#[derive(Clone, Copy)]
+pub struct Section {
+    pub name: &'static str,
+    pub start: usize,
+    pub end: usize,
+    // Protection applied by protect_kernel()
+    pub flags: PageFlags,
+}
+
+impl Section {
+    pub fn contains(&self, addr: usize) -> bool {
+        (self.start..self.end).contains(&addr)
+    }
+
+    pub fn size(&self) -> usize {
+        self.end - self.start
+    }
+}
+
+// The kernel image sections, in address order.
+pub fn sections() -> [Section; 3] {
+    unsafe {
+        [
+            Section {
+                name: ".text",
+                start: &_text_start as *const u8 as usize,
+                end: &_text_end as *const u8 as usize,
+                flags: PageFlags::NONE,
+            },
+            Section {
+                name: ".rodata",
+                start: &_rodata_start as *const u8 as usize,
+                end: &_rodata_end as *const u8 as usize,
+                flags: PageFlags::NO_EXECUTE,
+            },
+            Section {
+                name: ".data/.bss",
+                start: &_data_start as *const u8 as usize,
+                end: &_data_end as *const u8 as usize,
+                flags: PageFlags::WRITABLE | PageFlags::NO_EXECUTE,
+            },
+        ]
+    }
+}
+
+// The kernel section containing `addr`, if any.
+pub fn section_of(addr: usize) -> Option<Section> {
+    sections().into_iter().find(|s| s.contains(addr))
+}
+
+// Apply W^X to the kernel image.  Runs once from memory::init(), after
+// the identity map is gone (in legacy mode it shares page_table1).
+pub fn protect_kernel() {
+    for section in sections() {
+        debug_assert!(section.start % PAGE_SIZE == 0 && section.end % PAGE_SIZE == 0);
+        if section.size() == 0 {
+            continue;
+        }
+        vmm::protect_range(
+            VirtAddr::new(section.start as u32),
+            section.size(),
+            section.flags,
+        )
+        .expect("W^X: kernel image not mapped");
+        dbg_println!(
+            "W^X: {} {:#010x}..{:#010x} -> {}",
+            section.name,
+            section.start,
+            section.end,
+            section.flags | PageFlags::PRESENT
+        );
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Self-test - run after protect_kernel()
+// ---------------------------------------------------------------------------
+
+pub fn test_kernel_wx() {
+    m_print!("[W^X test] kernel section protection ... ");
+
+    let [text, rodata, data] = sections();
+    assert!(text.end <= rodata.start && rodata.end <= data.start);
+
+    // This function itself lives in .text
+    assert_eq!(
+        section_of(test_kernel_wx as fn() as usize).map(|s| s.name),
+        Some(".text")
+    );
+
+    for section in [text, rodata, data] {
+        let mut addr = section.start;
+        while addr < section.end {
+            let flags = vmm::page_flags(VirtAddr::new(addr as u32)).expect("image page unmapped");
+            assert_eq!(
+                flags.is_writable(),
+                section.flags.is_writable(),
+                "{} page {:#x} has the wrong write bit",
+                section.name,
+                addr
+            );
+            if paging::nx_enabled() {
+                assert_eq!(flags.is_no_execute(), section.flags.is_no_execute());
+            }
+            addr += PAGE_SIZE;
+        }
+    }
+
+    m_println!("OK");
+}
@@ -0,0 +1,735 @@
+// memory/slab.rs - Slab allocator for fixed-size kernel objects
+//
+// A KmemCache hands out objects of a single size.  Its memory comes in
+// whole pages ("slabs") from the frame allocator, reached through the
+// physmap, so slabs never touch the kernel heap:
+//
+//   ┌────────────┬─────┬──────────┬──────────┬─────┬──────────┐
+//   │ SlabHeader │ pad │ object 0 │ object 1 │ ... │ object n │
+//   └────────────┴─────┴──────────┴──────────┴─────┴──────────┘
+//   ◄─ page aligned      ◄─ aligned to the cache's alignment
+//
+// Free objects of a slab are chained through their first word, so
+// alloc and free are O(1) pushes and pops.  The slab owning an object
+// is found by rounding the object's address down to the page.
+//
+// Caches of large objects (OFF_SLAB_MIN and up) would lose a whole
+// object to the header, so theirs comes from the slab-header cache
+// instead and the page holds objects only.  Such pages are tagged
+// FrameOwner::SlabObjects, and their header is found by walking the
+// slabs of the off-slab caches.
+//
+// Each cache keeps two doubly linked slab lists:
+//   partial - slabs with at least one free object (alloc uses the head)
+//   full    - slabs with no free object
+// A slab that becomes empty goes back to the frame allocator unless it
+// is the cache's last one, so a cache cycling a single object doesn't
+// bounce a frame in and out of the frame allocator.
+//
+// Caches are meant to live in statics: every slab records its cache's
+// address, and so does the slabinfo registry, so a cache must not move
+// once it has allocated.  KmemCache::new() is const for that reason.
+//
+// The general caches kmalloc-8 .. kmalloc-1024 serve small allocations
+// for the global allocator (see allocator.rs).
+
+use super::define::PAGE_SIZE;
+use super::physical::{FrameOwner, PhysFrame, Zone, FRAME_ALLOCATOR};
+use super::physmap;
+use super::vmm::{self, PhysAddr};
+use crate::utils::{interrupts_restore, interrupts_save, interrupts_were_enabled};
+use crate::{dbg_println, m_print, m_println};
+
+// ---------------------------------------------------------------------------
+// Constants
+// ---------------------------------------------------------------------------
+
+// Written into every live slab header; kmem_free() checks it before
+// trusting the header.
+const SLAB_MAGIC: u32 = 0x51AB_CAC3;
+
+// Maximum number of caches listed by slabinfo.
+const MAX_CACHES: usize = 32;
+
+// Largest object served by the general kmalloc-N caches.
+pub const KMALLOC_MAX_SIZE: usize = 1024;
+
+// Smallest general cache (kmalloc-8).
+const KMALLOC_MIN_SHIFT: u32 = 3;
+
+const KMALLOC_CLASSES: usize = 8;
+
+// Objects this large get an off-slab header (see the top of the file).
+const OFF_SLAB_MIN: usize = PAGE_SIZE / 8;
+
+// ---------------------------------------------------------------------------
+// Slab layout
+// ---------------------------------------------------------------------------
+
+// Header at the start of every slab page, or off the slab for caches
+// of large objects.
+//
+// SAFETY: written into raw frame memory via pointer casts, hence
+// #[repr(C)].
+#[repr(C)]
+struct SlabHeader {
+    magic: u32,
+    // Objects handed out from this slab
+    inuse: usize,
+    cache: *mut KmemCache,
+    // Page holding the objects (the header's own page if on-slab)
+    page: usize,
+    prev: *mut SlabHeader,
+    next: *mut SlabHeader,
+    // First free object (null when the slab is full)
+    free: *mut FreeObject,
+}
+
+// A free object: only its first word is used.
+#[repr(C)]
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+const HEADER_SIZE: usize = core::mem::size_of::<SlabHeader>();
+
+// Objects must at least hold the free-list link.
+const MIN_OBJECT_SIZE: usize = core::mem::size_of::<FreeObject>();
+const MIN_ALIGN: usize = core::mem::align_of::<FreeObject>();
+
+#[inline]
+const fn align_up(val: usize, align: usize) -> usize {
+    (val + align - 1) & !(align - 1)
+}
+
+// Live slab header of the page holding `addr`, if any.
+unsafe fn slab_of(addr: usize) -> Option<*mut SlabHeader> {
+    let page = addr & !(PAGE_SIZE - 1);
+    if !has_off_slab_header(page) {
+        let slab = page as *mut SlabHeader;
+        return ((*slab).magic == SLAB_MAGIC).then_some(slab);
+    }
+    (*core::ptr::addr_of!(CACHES))
+        .iter()
+        .filter(|c| !c.is_null() && (***c).off_slab)
+        .find_map(|&cache| (*cache).find_slab(page))
+}
+
+// Does the slab page at `page` keep its header elsewhere?
+fn has_off_slab_header(page: usize) -> bool {
+    if !physmap::contains(page) {
+        return false;
+    }
+    let phys = physmap::virt_to_phys(page);
+    unsafe {
+        FRAME_ALLOCATOR.as_ref().is_some_and(|a| {
+            a.frame_info(PhysFrame::containing_address(phys))
+                .is_some_and(|p| p.owner == FrameOwner::SlabObjects)
+        })
+    }
+}
+
+unsafe fn list_push(head: &mut *mut SlabHeader, slab: *mut SlabHeader) {
+    (*slab).prev = core::ptr::null_mut();
+    (*slab).next = *head;
+    if !(*head).is_null() {
+        (**head).prev = slab;
+    }
+    *head = slab;
+}
+
+unsafe fn list_remove(head: &mut *mut SlabHeader, slab: *mut SlabHeader) {
+    if (*slab).prev.is_null() {
+        *head = (*slab).next;
+    } else {
+        (*(*slab).prev).next = (*slab).next;
+    }
+    if !(*slab).next.is_null() {
+        (*(*slab).next).prev = (*slab).prev;
+    }
+    (*slab).prev = core::ptr::null_mut();
+    (*slab).next = core::ptr::null_mut();
+}
+
+// One frame the physmap can reach.  Normal first so DMA frames stay
+// available for devices.
+fn alloc_slab_frame(owner: FrameOwner) -> Option<PhysFrame> {
+    unsafe {
+        let allocator = FRAME_ALLOCATOR.as_mut()?;
+        let frame = allocator
+            .allocate_frame_in(Zone::Normal)
+            .or_else(|_| allocator.allocate_frame_in(Zone::Dma))
+            .ok()?;
+        let _ = allocator.set_owner(frame, owner);
+        if !physmap::is_direct_mapped(frame.start_address(), PAGE_SIZE) {
+            // RAM the physmap skipped (e.g. before physmap::init())
+            let _ = allocator.deallocate_frame(frame);
+            return None;
+        }
+        Some(frame)
+    }
+}
+
+// ---------------------------------------------------------------------------
+// KmemCache
+// ---------------------------------------------------------------------------
+
+pub struct KmemCache {
+    name: &'static str,
+    // Object size as requested (at least MIN_OBJECT_SIZE)
+    object_size: usize,
+    // Distance between two objects: object_size rounded up to the alignment
+    stride: usize,
+    // Offset of object 0 from the start of the slab page
+    offset: usize,
+    // Header kept in the slab-header cache rather than on the page
+    off_slab: bool,
+    per_slab: usize,
+    partial: *mut SlabHeader,
+    full: *mut SlabHeader,
+    slabs: usize,
+    active: usize,
+    registered: bool,
+}
+
+// Snapshot of a cache's counters, as shown by slabinfo.
+pub struct SlabStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub active: usize,
+    pub total: usize,
+    pub slabs: usize,
+    pub per_slab: usize,
+}
+
+impl SlabStats {
+    // Percentage of slab memory holding live objects.
+    pub fn utilisation(&self) -> usize {
+        if self.slabs == 0 {
+            return 0;
+        }
+        self.active * self.object_size * 100 / (self.slabs * PAGE_SIZE)
+    }
+}
+
+impl KmemCache {
+    // A cache of `size`-byte objects aligned to `align` (power of two).
+    // At least one object must fit in a page after the slab header.
+    pub const fn new(name: &'static str, size: usize, align: usize) -> Self {
+        assert!(
+            align.is_power_of_two(),
+            "KmemCache: alignment must be a power of two"
+        );
+        let align = if align < MIN_ALIGN { MIN_ALIGN } else { align };
+        let size = if size < MIN_OBJECT_SIZE {
+            MIN_OBJECT_SIZE
+        } else {
+            size
+        };
+        let stride = align_up(size, align);
+        let off_slab = size >= OFF_SLAB_MIN;
+        let offset = if off_slab {
+            0
+        } else {
+            align_up(HEADER_SIZE, align)
+        };
+        assert!(
+            offset + stride <= PAGE_SIZE,
+            "KmemCache: object does not fit in a one-page slab"
+        );
+
+        KmemCache {
+            name,
+            object_size: size,
+            stride,
+            offset,
+            off_slab,
+            per_slab: (PAGE_SIZE - offset) / stride,
+            partial: core::ptr::null_mut(),
+            full: core::ptr::null_mut(),
+            slabs: 0,
+            active: 0,
+            registered: false,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn object_size(&self) -> usize {
+        self.object_size
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        SlabStats {
+            name: self.name,
+            object_size: self.object_size,
+            active: self.active,
+            total: self.slabs * self.per_slab,
+            slabs: self.slabs,
+            per_slab: self.per_slab,
+        }
+    }
+
+    // Allocate one object.  Returns null if no frame is left for a new
+    // slab.  The object's contents are undefined.
+    pub fn alloc(&mut self) -> *mut u8 {
+        let flags = interrupts_save();
+        let obj = unsafe { self.alloc_locked() };
+        interrupts_restore(flags);
+        obj
+    }
+
+    // Return an object obtained from this cache's alloc().  Null is a
+    // no-op; foreign pointers and double frees are reported and ignored.
+    pub fn free(&mut self, ptr: *mut u8) {
+        if ptr.is_null() {
+            return;
+        }
+        let flags = interrupts_save();
+        let result = unsafe {
+            match slab_of(ptr as usize) {
+                Some(slab) if core::ptr::eq((*slab).cache, self) => {
+                    self.free_locked(slab, ptr as usize)
+                }
+                _ => Err("does not belong to this cache"),
+            }
+        };
+        if result.is_err() {
+            unsafe { BAD_FREES += 1 };
+        }
+        interrupts_restore(flags);
+        if let Err(reason) = result {
+            report_bad_free(flags, ptr as usize, self.name, reason);
+        }
+    }
+
+    // Release every slab and drop the cache from slabinfo.  All objects
+    // must have been freed.
+    pub fn destroy(&mut self) {
+        assert!(
+            self.active == 0,
+            "kmem_cache_destroy: {} still has {} object(s)",
+            self.name,
+            self.active
+        );
+        let flags = interrupts_save();
+        unsafe {
+            while !self.partial.is_null() {
+                let slab = self.partial;
+                list_remove(&mut self.partial, slab);
+                self.release(slab);
+            }
+            unregister(self);
+        }
+        interrupts_restore(flags);
+    }
+
+    unsafe fn alloc_locked(&mut self) -> *mut u8 {
+        if self.partial.is_null() && !self.grow() {
+            return core::ptr::null_mut();
+        }
+
+        let slab = self.partial;
+        let obj = (*slab).free;
+        (*slab).free = (*obj).next;
+        (*slab).inuse += 1;
+        if (*slab).free.is_null() {
+            list_remove(&mut self.partial, slab);
+            list_push(&mut self.full, slab);
+        }
+        self.active += 1;
+        obj as *mut u8
+    }
+
+    // Put `addr` back on its slab's free list, or say why it can't be.
+    unsafe fn free_locked(
+        &mut self,
+        slab: *mut SlabHeader,
+        addr: usize,
+    ) -> Result<(), &'static str> {
+        let offset = addr - (*slab).page;
+        if offset < self.offset
+            || !(offset - self.offset).is_multiple_of(self.stride)
+            || (offset - self.offset) / self.stride >= self.per_slab
+        {
+            return Err("is not the start of an object");
+        }
+
+        // A slab holds at most a page worth of objects, so this walk
+        // is cheap enough to keep on every free.
+        let mut cursor = (*slab).free;
+        while !cursor.is_null() {
+            if cursor as usize == addr {
+                return Err("double free");
+            }
+            cursor = (*cursor).next;
+        }
+
+        let was_full = (*slab).free.is_null();
+        let obj = addr as *mut FreeObject;
+        (*obj).next = (*slab).free;
+        (*slab).free = obj;
+        (*slab).inuse -= 1;
+        self.active -= 1;
+
+        if was_full {
+            list_remove(&mut self.full, slab);
+            list_push(&mut self.partial, slab);
+        }
+        if (*slab).inuse == 0 && self.slabs > 1 {
+            list_remove(&mut self.partial, slab);
+            self.release(slab);
+        }
+        Ok(())
+    }
+
+    // Add one slab to the partial list.
+    unsafe fn grow(&mut self) -> bool {
+        let owner = if self.off_slab {
+            FrameOwner::SlabObjects
+        } else {
+            FrameOwner::Slab
+        };
+        let Some(frame) = alloc_slab_frame(owner) else {
+            return false;
+        };
+        let page = physmap::phys_to_virt(frame.start_address());
+
+        // slab_of() finds off-slab headers through the registry
+        if self.off_slab && !self.registered {
+            register(self);
+        }
+        let slab = if !self.off_slab {
+            page as *mut SlabHeader
+        } else if self.registered {
+            (*core::ptr::addr_of_mut!(SLAB_HEADERS)).alloc_locked() as *mut SlabHeader
+        } else {
+            core::ptr::null_mut()
+        };
+        if slab.is_null() {
+            vmm::free_frame(PhysAddr::new(frame.start_address() as u32));
+            return false;
+        }
+
+        // Thread the free list in address order
+        let base = page + self.offset;
+        let mut free: *mut FreeObject = core::ptr::null_mut();
+        for i in (0..self.per_slab).rev() {
+            let obj = (base + i * self.stride) as *mut FreeObject;
+            (*obj).next = free;
+            free = obj;
+        }
+
+        slab.write(SlabHeader {
+            magic: SLAB_MAGIC,
+            inuse: 0,
+            cache: self as *mut KmemCache,
+            page,
+            prev: core::ptr::null_mut(),
+            next: core::ptr::null_mut(),
+            free,
+        });
+        list_push(&mut self.partial, slab);
+        self.slabs += 1;
+
+        if !self.registered {
+            register(self);
+        }
+        true
+    }
+
+    // Give an unlinked, empty slab back to the frame allocator.
+    unsafe fn release(&mut self, slab: *mut SlabHeader) {
+        (*slab).magic = 0;
+        self.slabs -= 1;
+        vmm::free_frame(PhysAddr::new(physmap::virt_to_phys((*slab).page) as u32));
+        if self.off_slab {
+            (*core::ptr::addr_of_mut!(SLAB_HEADERS)).free(slab as *mut u8);
+        }
+    }
+
+    // Off-slab header of this cache's slab at `page`, if it has one.
+    unsafe fn find_slab(&self, page: usize) -> Option<*mut SlabHeader> {
+        for mut slab in [self.partial, self.full] {
+            while !slab.is_null() {
+                if (*slab).page == page {
+                    return Some(slab);
+                }
+                slab = (*slab).next;
+            }
+        }
+        None
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Cache registry (slabinfo)
+// ---------------------------------------------------------------------------
+
+static mut CACHES: [*mut KmemCache; MAX_CACHES] = [core::ptr::null_mut(); MAX_CACHES];
+
+unsafe fn register(cache: &mut KmemCache) {
+    let slot = (*core::ptr::addr_of!(CACHES))
+        .iter()
+        .position(|c| c.is_null());
+    match slot {
+        Some(slot) => {
+            CACHES[slot] = cache as *mut KmemCache;
+            cache.registered = true;
+        }
+        None => dbg_println!("slab: registry full, {} not listed", cache.name),
+    }
+}
+
+unsafe fn unregister(cache: &mut KmemCache) {
+    let me = cache as *mut KmemCache;
+    for slot in (*core::ptr::addr_of_mut!(CACHES)).iter_mut() {
+        if *slot == me {
+            *slot = core::ptr::null_mut();
+        }
+    }
+    cache.registered = false;
+}
+
+// Call `f` with the counters of every registered cache.
+pub fn for_each_cache(mut f: impl FnMut(&SlabStats)) {
+    unsafe {
+        for &cache in (*core::ptr::addr_of!(CACHES)).iter() {
+            if !cache.is_null() {
+                f(&(*cache).stats());
+            }
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// General-purpose caches
+// ---------------------------------------------------------------------------
+
+// Each class is aligned to its own size, so kmalloc-N satisfies any
+// alignment up to N.  With power-of-two sizes that costs no slots:
+// only the header does, and kmalloc-512/1024 keep theirs off the slab.
+static mut KMALLOC_CACHES: [KmemCache; KMALLOC_CLASSES] = [
+    KmemCache::new("kmalloc-8", 8, 8),
+    KmemCache::new("kmalloc-16", 16, 16),
+    KmemCache::new("kmalloc-32", 32, 32),
+    KmemCache::new("kmalloc-64", 64, 64),
+    KmemCache::new("kmalloc-128", 128, 128),
+    KmemCache::new("kmalloc-256", 256, 256),
+    KmemCache::new("kmalloc-512", 512, 512),
+    KmemCache::new("kmalloc-1024", 1024, 1024),
+];
+
+const _: () = assert!(KMALLOC_MAX_SIZE == 1 << (KMALLOC_MIN_SHIFT as usize + KMALLOC_CLASSES - 1));
+
+// Off-slab headers.  Small enough to keep its own header on the slab.
+static mut SLAB_HEADERS: KmemCache = KmemCache::new(
+    "slab-header",
+    HEADER_SIZE,
+    core::mem::align_of::<SlabHeader>(),
+);
+
+const _: () = assert!(HEADER_SIZE < OFF_SLAB_MIN);
+
+static mut SLAB_READY: bool = false;
+
+// Register the general caches.  Slabs live in the physmap, so this must
+// run after physmap::init(); until then kmem_alloc() returns null and
+// small allocations stay on the heap.
+pub fn init() {
+    unsafe {
+        for cache in (*core::ptr::addr_of_mut!(KMALLOC_CACHES)).iter_mut() {
+            register(cache);
+        }
+        SLAB_READY = true;
+    }
+    dbg_println!(
+        "slab: {} general caches, {}..{} bytes",
+        KMALLOC_CLASSES,
+        1 << KMALLOC_MIN_SHIFT,
+        KMALLOC_MAX_SIZE
+    );
+}
+
+// General cache serving `size` bytes at `align`, if any.
+fn kmalloc_cache(size: usize, align: usize) -> Option<&'static mut KmemCache> {
+    let need = size.max(align).max(1 << KMALLOC_MIN_SHIFT);
+    if need > KMALLOC_MAX_SIZE {
+        return None;
+    }
+    let class = (need.next_power_of_two().trailing_zeros() - KMALLOC_MIN_SHIFT) as usize;
+    unsafe { Some(&mut (*core::ptr::addr_of_mut!(KMALLOC_CACHES))[class]) }
+}
+
+// Allocate `size` bytes aligned to `align` from the smallest general
+// cache that fits.  Null if the request is too large for the caches,
+// before init(), or when no frame is left.
+pub fn kmem_alloc(size: usize, align: usize) -> *mut u8 {
+    if !unsafe { SLAB_READY } || size == 0 {
+        return core::ptr::null_mut();
+    }
+    match kmalloc_cache(size, align) {
+        Some(cache) => cache.alloc(),
+        None => core::ptr::null_mut(),
+    }
+}
+
+// Free an object from any cache, found through its slab header.  Like
+// KmemCache::free(), a pointer that is not a slab object is reported
+// and ignored.
+pub fn kmem_free(ptr: *mut u8) {
+    if ptr.is_null() {
+        return;
+    }
+    unsafe {
+        match slab_of(ptr as usize) {
+            Some(slab) => (*(*slab).cache).free(ptr),
+            None => {
+                let flags = interrupts_save();
+                BAD_FREES += 1;
+                interrupts_restore(flags);
+                report_bad_free(flags, ptr as usize, "kmem_free", "is not a slab object");
+            }
+        }
+    }
+}
+
+// Frees refused so far: foreign pointers, misaligned objects and double
+// frees.  slabinfo shows it.
+static mut BAD_FREES: usize = 0;
+
+pub fn bad_frees() -> usize {
+    unsafe { BAD_FREES }
+}
+
+// Print a refused free, but only when the caller had interrupts on: a
+// free with interrupts off may have interrupted a console writer, and
+// println! would then spin on the lock it holds.  BAD_FREES still
+// counts it.
+fn report_bad_free(flags: u32, addr: usize, what: &str, reason: &str) {
+    if interrupts_were_enabled(flags) {
+        println!("slab: refused free of {:#x} ({}): {}", addr, what, reason);
+    }
+}
+
+// Could `ptr` have come from a slab?  Slabs are the only allocations in
+// the physmap, while the heap has its own window, so the address alone
+// tells them apart (see KERNEL_HEAP_START in define.rs).
+pub fn is_slab_object(ptr: *mut u8) -> bool {
+    physmap::contains(ptr as usize) && unsafe { SLAB_READY }
+}
+
+// Usable size of a slab object (the cache's object size).
+pub fn kmem_size(ptr: *mut u8) -> usize {
+    unsafe { slab_of(ptr as usize).map_or(0, |slab| (*(*slab).cache).object_size) }
+}
+
+// ---------------------------------------------------------------------------
+// Self-test
+// ---------------------------------------------------------------------------
+
+static mut TEST_CACHE: KmemCache = KmemCache::new("slab-test", 40, 8);
+
+fn free_frame_count() -> usize {
+    unsafe { FRAME_ALLOCATOR.as_ref().map_or(0, |a| a.free_frames()) }
+}
+
+pub fn test_slab() {
+    m_print!("[slab test] cache grow, reuse, release, kmalloc classes ... ");
+
+    let frames_before = free_frame_count();
+    let cache = unsafe { &mut *core::ptr::addr_of_mut!(TEST_CACHE) };
+    let per_slab = cache.stats().per_slab;
+    assert!(per_slab > 1);
+
+    // Fill one slab and spill into a second
+    let mut objs = alloc::vec::Vec::new();
+    for i in 0..=per_slab {
+        let obj = cache.alloc();
+        assert!(!obj.is_null(), "slab alloc failed");
+        assert!((obj as usize).is_multiple_of(8));
+        assert!(!objs.contains(&obj), "object handed out twice");
+        unsafe { core::ptr::write_bytes(obj, i as u8, 40) };
+        objs.push(obj);
+    }
+    let stats = cache.stats();
+    assert_eq!(stats.slabs, 2);
+    assert_eq!(stats.active, per_slab + 1);
+    assert_eq!(free_frame_count(), frames_before - 2);
+    assert!(is_registered(cache.name()));
+
+    // Objects don't overlap
+    for (i, &obj) in objs.iter().enumerate() {
+        assert_eq!(unsafe { *obj.add(39) }, i as u8, "object corrupted");
+    }
+
+    // A freed object is reused first
+    let recycled = objs[3];
+    cache.free(recycled);
+    assert_eq!(cache.alloc(), recycled);
+
+    // Emptying a slab releases it, but the last one is kept
+    for &obj in objs.iter() {
+        cache.free(obj);
+    }
+    assert_eq!(cache.stats().active, 0);
+    assert_eq!(cache.stats().slabs, 1);
+    assert_eq!(free_frame_count(), frames_before - 1);
+
+    // Bad frees are counted and ignored
+    let obj = cache.alloc();
+    let bad = bad_frees();
+    cache.free(unsafe { obj.add(8) });
+    assert_eq!(bad_frees(), bad + 1);
+    assert_eq!(cache.stats().active, 1);
+    cache.free(obj);
+    cache.free(obj);
+    assert_eq!(bad_frees(), bad + 2);
+    assert_eq!(cache.stats().active, 0);
+    cache.destroy();
+    assert_eq!(cache.stats().slabs, 0);
+    assert_eq!(free_frame_count(), frames_before);
+    assert!(!is_registered(cache.name()));
+
+    // General caches: size and alignment pick the class
+    let small = kmem_alloc(24, 8);
+    assert!(is_slab_object(small));
+    assert_eq!(kmem_size(small), 32);
+    let aligned = kmem_alloc(8, 256);
+    assert!((aligned as usize).is_multiple_of(256));
+    assert_eq!(kmem_size(aligned), 256);
+    assert!(kmem_alloc(KMALLOC_MAX_SIZE + 1, 8).is_null());
+    kmem_free(small);
+    kmem_free(aligned);
+
+    // Large classes keep their header off the slab: no slot is lost
+    let large = unsafe { &(*core::ptr::addr_of!(KMALLOC_CACHES))[KMALLOC_CLASSES - 1] };
+    assert_eq!(large.stats().per_slab, PAGE_SIZE / KMALLOC_MAX_SIZE);
+    let mut big = alloc::vec::Vec::new();
+    for _ in 0..=PAGE_SIZE / KMALLOC_MAX_SIZE {
+        let obj = kmem_alloc(KMALLOC_MAX_SIZE, KMALLOC_MAX_SIZE);
+        assert!(!obj.is_null() && (obj as usize).is_multiple_of(KMALLOC_MAX_SIZE));
+        assert_eq!(kmem_size(obj), KMALLOC_MAX_SIZE);
+        unsafe { core::ptr::write_bytes(obj, 0xA5, KMALLOC_MAX_SIZE) };
+        big.push(obj);
+    }
+    assert!(has_off_slab_header(big[0] as usize & !(PAGE_SIZE - 1)));
+    for &obj in big.iter() {
+        kmem_free(obj);
+    }
+
+    // Small Rust allocations go through the caches too
+    let boxed = alloc::boxed::Box::new(0x1234_5678u32);
+    assert!(is_slab_object(&*boxed as *const u32 as *mut u8));
+    drop(boxed);
+
+    m_println!("OK");
+}
+
+fn is_registered(name: &str) -> bool {
+    let mut found = false;
+    for_each_cache(|stats| found |= stats.name == name);
+    found
+}
@@ -0,0 +1,298 @@
+// memory/tracking.rs - Live allocation tracking for leak hunting
+//
+// Built with the `alloc_track` feature.  KernelAllocator reports every
+// allocation and free here, and a fixed table keeps one record per live
+// allocation: pointer, size, the return addresses of the frames that
+// asked for it, the timer tick, and a sequence number.
+//
+// The table lives in .bss, never on the heap, so recording can't recurse
+// into the allocator.  It is an open-addressed hash on the pointer with
+// linear probing; removal shifts the following entries back instead of
+// leaving tombstones.  When it is 3/4 full further allocations are
+// counted in `dropped` and not tracked (their frees are then ignored).
+//
+// Call sites are read from the EBP chain, which needs frame pointers
+// (the Makefile turns them on when alloc_track is enabled).  The walk
+// stops at the first frame outside the boot kernel stack.
+//
+// mark() remembers the current sequence number; allocations made after
+// it that are still live are what a command leaked:
+//   heapleaks mark ; snake ; heapleaks diff
+
+use crate::timer;
+use crate::utils::{interrupts_restore, interrupts_save};
+use crate::{m_print, m_println};
+
+// Return addresses kept per allocation, innermost first.
+pub const CALLER_DEPTH: usize = 4;
+
+// Frames between the EBP we start from and the allocator's caller:
+// record_alloc() itself and KernelAllocator::alloc() (or realloc()).
+const SKIP_FRAMES: usize = 2;
+
+const TABLE_BITS: u32 = 11;
+const TABLE_SIZE: usize = 1 << TABLE_BITS;
+const TABLE_MASK: usize = TABLE_SIZE - 1;
+const MAX_LIVE: usize = TABLE_SIZE * 3 / 4;
+
+// One live allocation.  ptr == 0 marks an empty slot.
+#[derive(Clone, Copy)]
+pub struct Allocation {
+    pub ptr: usize,
+    pub size: usize,
+    pub callers: [usize; CALLER_DEPTH],
+    pub tick: u32,
+    pub seq: u32,
+}
+
+const EMPTY: Allocation = Allocation {
+    ptr: 0,
+    size: 0,
+    callers: [0; CALLER_DEPTH],
+    tick: 0,
+    seq: 0,
+};
+
+static mut TABLE: [Allocation; TABLE_SIZE] = [EMPTY; TABLE_SIZE];
+static mut LIVE: usize = 0;
+static mut DROPPED: usize = 0;
+static mut NEXT_SEQ: u32 = 0;
+static mut MARK: Option<u32> = None;
+
+extern "C" {
+    static stack_bottom: u8;
+    static stack_top: u8;
+}
+
+// Home slot of `ptr` (Fibonacci hashing; the low 3 bits are always 0).
+#[inline]
+fn home(ptr: usize) -> usize {
+    ((ptr as u32 >> 3).wrapping_mul(0x9E37_79B9) >> (u32::BITS - TABLE_BITS)) as usize
+}
+
+// Return addresses of the frames above the allocator.
+#[inline(always)]
+fn call_chain() -> [usize; CALLER_DEPTH] {
+    let mut chain = [0; CALLER_DEPTH];
+    let mut ebp: usize;
+    unsafe {
+        core::arch::asm!("mov {:e}, ebp", out(reg) ebp, options(nomem, nostack));
+    }
+    let low = core::ptr::addr_of!(stack_bottom) as usize;
+    let high = core::ptr::addr_of!(stack_top) as usize;
+
+    let mut depth = 0;
+    while depth < SKIP_FRAMES + CALLER_DEPTH {
+        // [ebp] = caller's ebp, [ebp + 4] = return address
+        if ebp < low || ebp + 8 > high || !ebp.is_multiple_of(4) {
+            break;
+        }
+        let (next, ret) = unsafe { (*(ebp as *const usize), *((ebp + 4) as *const usize)) };
+        if depth >= SKIP_FRAMES {
+            chain[depth - SKIP_FRAMES] = ret;
+        }
+        if next <= ebp {
+            break;
+        }
+        ebp = next;
+        depth += 1;
+    }
+    chain
+}
+
+// Called by KernelAllocator::alloc() for every non-null allocation.
+#[inline(never)]
+pub fn record_alloc(ptr: *mut u8, size: usize) {
+    let callers = call_chain();
+    let tick = timer::get_ticks();
+
+    let flags = interrupts_save();
+    unsafe {
+        let seq = NEXT_SEQ;
+        NEXT_SEQ = NEXT_SEQ.wrapping_add(1);
+        if LIVE >= MAX_LIVE {
+            DROPPED += 1;
+        } else {
+            insert(Allocation {
+                ptr: ptr as usize,
+                size,
+                callers,
+                tick,
+                seq,
+            });
+            LIVE += 1;
+        }
+    }
+    interrupts_restore(flags);
+}
+
+// Called by KernelAllocator::dealloc().  Pointers that were never
+// tracked (allocated while the table was full) are ignored.
+pub fn record_free(ptr: *mut u8) {
+    let flags = interrupts_save();
+    unsafe {
+        if let Some(slot) = find(ptr as usize) {
+            remove_slot(slot);
+            LIVE -= 1;
+        }
+    }
+    interrupts_restore(flags);
+}
+
+// Called by KernelAllocator::realloc() when `old` now lives at `new`
+// with `size` bytes.  The record keeps its call sites, tick and
+// sequence number: a buffer that grew is still the same allocation.
+pub fn record_realloc(old: *mut u8, new: *mut u8, size: usize) {
+    let flags = interrupts_save();
+    unsafe {
+        if let Some(slot) = find(old as usize) {
+            let mut alloc = TABLE[slot];
+            alloc.ptr = new as usize;
+            alloc.size = size;
+            if old == new {
+                TABLE[slot] = alloc;
+            } else {
+                remove_slot(slot);
+                insert(alloc);
+            }
+        }
+    }
+    interrupts_restore(flags);
+}
+
+// Slot holding `ptr`, if it is tracked.
+unsafe fn find(ptr: usize) -> Option<usize> {
+    let mut slot = home(ptr);
+    while TABLE[slot].ptr != 0 {
+        if TABLE[slot].ptr == ptr {
+            return Some(slot);
+        }
+        slot = (slot + 1) & TABLE_MASK;
+    }
+    None
+}
+
+// Put `alloc` in the first empty slot from its home.  The caller has
+// checked there is room.
+unsafe fn insert(alloc: Allocation) {
+    let mut slot = home(alloc.ptr);
+    while TABLE[slot].ptr != 0 {
+        slot = (slot + 1) & TABLE_MASK;
+    }
+    TABLE[slot] = alloc;
+}
+
+// Empty `hole` and pull back the entries after it that could no longer
+// be reached from their home slot.
+unsafe fn remove_slot(mut hole: usize) {
+    let mut next = hole;
+    loop {
+        next = (next + 1) & TABLE_MASK;
+        if TABLE[next].ptr == 0 {
+            break;
+        }
+        // An entry may move into the hole only if its home slot is not
+        // cyclically inside (hole, next]
+        let h = home(TABLE[next].ptr);
+        let reachable = if hole <= next {
+            hole < h && h <= next
+        } else {
+            hole < h || h <= next
+        };
+        if !reachable {
+            TABLE[hole] = TABLE[next];
+            hole = next;
+        }
+    }
+    TABLE[hole] = EMPTY;
+}
+
+// Start a new leak window; returns its sequence number.
+pub fn mark() -> u32 {
+    unsafe {
+        MARK = Some(NEXT_SEQ);
+        NEXT_SEQ
+    }
+}
+
+// Sequence number of the last mark(), if any.
+pub fn last_mark() -> Option<u32> {
+    unsafe { MARK }
+}
+
+// (live allocations tracked, allocations not tracked because the
+// table was full)
+pub fn stats() -> (usize, usize) {
+    unsafe { (LIVE, DROPPED) }
+}
+
+// Call `f` for every tracked allocation, or only for those made since
+// the mark numbered `since`.  Interrupts are off meanwhile and `f` must
+// not allocate: that would change the table under the walk.
+pub fn for_each_live(since: Option<u32>, mut f: impl FnMut(&Allocation)) {
+    let flags = interrupts_save();
+    unsafe {
+        for alloc in (*core::ptr::addr_of!(TABLE)).iter() {
+            let in_window = since.is_none_or(|mark| alloc.seq.wrapping_sub(mark) < u32::MAX / 2);
+            if alloc.ptr != 0 && in_window {
+                f(alloc);
+            }
+        }
+    }
+    interrupts_restore(flags);
+}
+
+// ---------------------------------------------------------------------------
+// Self-test
+// ---------------------------------------------------------------------------
+
+fn live_since(since: u32) -> (usize, usize) {
+    let (mut count, mut bytes) = (0, 0);
+    for_each_live(Some(since), |a| {
+        count += 1;
+        bytes += a.size;
+    });
+    (count, bytes)
+}
+
+pub fn test_tracking() {
+    m_print!("[tracking test] record, mark, diff, realloc, table removal ... ");
+
+    let since = mark();
+    assert_eq!(last_mark(), Some(since));
+    assert_eq!(live_since(since), (0, 0));
+
+    let boxed = alloc::boxed::Box::new([0u8; 48]);
+    let mut v: alloc::vec::Vec<u32> = alloc::vec::Vec::with_capacity(100);
+    v.push(1);
+    let (count, bytes) = live_since(since);
+    assert_eq!((count, bytes), (2, 48 + 400));
+
+    let mut found = false;
+    for_each_live(Some(since), |a| {
+        if a.ptr == &*boxed as *const [u8; 48] as usize {
+            found = a.size == 48 && a.callers[0] != 0;
+        }
+    });
+    assert!(found, "Box allocation not tracked with a caller");
+
+    // Enough churn to collide in the table, freed in a different order
+    let mut many: alloc::vec::Vec<alloc::boxed::Box<u64>> = alloc::vec::Vec::with_capacity(64);
+    for i in 0..64 {
+        many.push(alloc::boxed::Box::new(i));
+    }
+    assert_eq!(live_since(since).0, 2 + 1 + 64);
+    many.retain(|b| **b % 2 == 0);
+    assert_eq!(live_since(since).0, 2 + 1 + 32);
+    drop(many);
+    assert_eq!(live_since(since), (2, 48 + 400));
+
+    // Growing the Vec reallocates: same record, new size
+    v.reserve_exact(299);
+    assert_eq!(live_since(since), (2, 48 + 1200));
+
+    drop(boxed);
+    drop(v);
+    assert_eq!(live_since(since), (0, 0));
+    m_println!("OK");
+}
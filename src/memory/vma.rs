@@ -0,0 +1,311 @@
+// memory/vma.rs - Virtual memory areas and demand paging
+//
+// A VMA declares that a virtual range is lazily backed: nothing is
+// mapped up front, and the first access to each page raises a
+// not-present page fault.  The page fault handler asks handle_fault()
+// to resolve it; we allocate a frame, fill it and map it, then the
+// faulting instruction is simply retried.
+//
+// Backing kinds:
+//   - Anonymous:  the page is zero-filled
+//   - Fill(f):    the page is zeroed, then `f` writes its contents
+//
+// The registry is a fixed-size static table rather than a heap
+// structure: it is consulted from the page fault handler, which must
+// never allocate, and it must be usable before the heap exists.
+
+use super::define::PAGE_SIZE;
+use super::pageflags::PageFlags;
+use super::vmm::{self, VirtAddr};
+use crate::{dbg_println, m_print, m_println};
+
+// Maximum number of simultaneously registered areas.
+const MAX_VMAS: usize = 32;
+
+// Fill callback: `data` is the freshly mapped, zeroed page at `page`.
+pub type FillFn = fn(page: VirtAddr, data: &mut [u8]);
+
+#[derive(Clone, Copy)]
+pub enum VmaBacking {
+    // Zero-filled on first touch
+    Anonymous,
+    // Zeroed, then filled by the callback
+    Fill(FillFn),
+}
+
+#[derive(Clone, Copy)]
+pub struct Vma {
+    pub start: u32,
+    // Exclusive end, page aligned
+    pub end: u32,
+    // PTE flags used when a page is faulted in
+    pub flags: PageFlags,
+    pub backing: VmaBacking,
+    pub name: &'static str,
+}
+
+impl Vma {
+    #[inline]
+    pub fn contains(&self, addr: u32) -> bool {
+        addr >= self.start && addr < self.end
+    }
+}
+
+#[derive(Debug)]
+pub enum VmaError {
+    // Start or size not page aligned, or size zero
+    Unaligned,
+    // Range overlaps an existing VMA
+    Overlap,
+    // MAX_VMAS areas already registered
+    TableFull,
+    // Range reaches the recursive / foreign mapping slots
+    InvalidAddress,
+    // A page of the range is already mapped
+    AlreadyMapped,
+}
+
+static mut VMAS: [Option<Vma>; MAX_VMAS] = [None; MAX_VMAS];
+
+// Declare [start, start + size) as lazily backed by `backing`.
+//
+// Nothing is mapped yet, and nothing may be: unregister() unmaps the
+// whole range, so a range holding mappings is refused.  Pages in it
+// must only ever be mapped by the fault handler.
+pub fn register(
+    start: VirtAddr,
+    size: usize,
+    flags: PageFlags,
+    backing: VmaBacking,
+    name: &'static str,
+) -> Result<(), VmaError> {
+    if size == 0 || !start.is_page_aligned() || !size.is_multiple_of(PAGE_SIZE) {
+        return Err(VmaError::Unaligned);
+    }
+    let end = start.0 as usize + size;
+    if end > vmm::layout().foreign_base() as usize {
+        return Err(VmaError::InvalidAddress);
+    }
+    let end = end as u32;
+    if vmm::any_mapped(start, size) {
+        return Err(VmaError::AlreadyMapped);
+    }
+
+    unsafe {
+        for vma in VMAS.iter().flatten() {
+            if start.0 < vma.end && vma.start < end {
+                return Err(VmaError::Overlap);
+            }
+        }
+
+        let slot = VMAS
+            .iter_mut()
+            .find(|v| v.is_none())
+            .ok_or(VmaError::TableFull)?;
+        *slot = Some(Vma {
+            start: start.0,
+            end,
+            flags: flags | PageFlags::PRESENT,
+            backing,
+            name,
+        });
+    }
+
+    dbg_println!(
+        "VMA: registered '{}' {:#x}..{:#x} ({} KB)",
+        name,
+        start.0,
+        end,
+        size / 1024
+    );
+    Ok(())
+}
+
+// Remove the VMA starting at `start` and unmap (and free) every page
+// that was faulted in.  Returns the removed area.
+pub fn unregister(start: VirtAddr) -> Option<Vma> {
+    let vma = unsafe {
+        let slot = VMAS
+            .iter_mut()
+            .find(|v| matches!(v, Some(vma) if vma.start == start.0))?;
+        slot.take()?
+    };
+
+    // Faults fill a VMA one 4 KB page at a time, so there is no large
+    // page to split here
+    let freed =
+        vmm::unmap_range(VirtAddr::new(vma.start), (vma.end - vma.start) as usize).unwrap_or(0);
+    dbg_println!(
+        "VMA: unregistered '{}' ({} page(s) released)",
+        vma.name,
+        freed
+    );
+    Some(vma)
+}
+
+// Find the VMA containing `addr`, if any.
+pub fn find(addr: u32) -> Option<Vma> {
+    unsafe { VMAS.iter().flatten().find(|v| v.contains(addr)).copied() }
+}
+
+// Try to resolve a page fault at `addr`.
+//
+// Returns true if the page was mapped and the faulting instruction can
+// be retried.  Returns false for addresses outside every VMA, for
+// protection violations (page present) and for accesses the VMA does
+// not permit (write to read-only, user access to kernel area).
+pub fn handle_fault(addr: u32, error_code: u32) -> bool {
+    let vma = match find(addr) {
+        Some(vma) => vma,
+        None => return false,
+    };
+
+    // Bit 0: page was present - this is a protection violation
+    if error_code & (1 << 0) != 0 {
+        return false;
+    }
+    // Bit 1: write access
+    if error_code & (1 << 1) != 0 && !vma.flags.is_writable() {
+        return false;
+    }
+    // Bit 2: user mode access
+    if error_code & (1 << 2) != 0 && !vma.flags.is_user() {
+        return false;
+    }
+
+    let page = VirtAddr::new(addr & !(PAGE_SIZE as u32 - 1));
+
+    // Map writable first so we can fill the page, then drop to the
+    // VMA's real flags if those are read-only.
+    let phys = match vmm::map_alloc(page, vma.flags | PageFlags::WRITABLE) {
+        Ok(phys) => phys,
+        Err(_) => return false,
+    };
+
+    unsafe {
+        let data = core::slice::from_raw_parts_mut(page.0 as *mut u8, PAGE_SIZE);
+        data.fill(0);
+        if let VmaBacking::Fill(fill) = vma.backing {
+            fill(page, data);
+        }
+    }
+
+    // Drop the write bit in place: no page table to free and allocate
+    // again inside the fault handler
+    if !vma.flags.is_writable() && vmm::protect_range(page, PAGE_SIZE, vma.flags).is_err() {
+        let _ = vmm::unmap_page(page);
+        vmm::free_frame(phys);
+        return false;
+    }
+
+    true
+}
+
+// Print every registered VMA.
+pub fn print_vmas() {
+    m_println!("Virtual memory areas:");
+    unsafe {
+        for vma in VMAS.iter().flatten() {
+            let kind = match vma.backing {
+                VmaBacking::Anonymous => "anon",
+                VmaBacking::Fill(_) => "fill",
+            };
+            m_println!(
+                "  {:#010x}..{:#010x} {} {} {}",
+                vma.start,
+                vma.end,
+                vma.flags,
+                kind,
+                vma.name
+            );
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Self-test
+// ---------------------------------------------------------------------------
+
+fn test_fill_pattern(page: VirtAddr, data: &mut [u8]) {
+    let tag = (page.0 >> 12) as u8;
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte = tag.wrapping_add(i as u8);
+    }
+}
+
+pub fn test_demand_paging() {
+    m_print!("[VMA test] anonymous + fill demand paging ... ");
+
+    // Borrow a free stretch of the vmalloc window: 4 pages each, with
+    // a gap between them
+    let base = vmm::find_free_range(12).expect("no free virtual range");
+    let anon = VirtAddr::new(base);
+    let filled = VirtAddr::new(base + 8 * PAGE_SIZE as u32);
+    let size = 4 * PAGE_SIZE;
+
+    // A range that already holds a mapping is refused
+    let phys = vmm::map_alloc(filled, PageFlags::WRITABLE).expect("map_alloc failed");
+    assert!(matches!(
+        register(
+            anon,
+            12 * PAGE_SIZE,
+            PageFlags::NONE,
+            VmaBacking::Anonymous,
+            "mapped"
+        ),
+        Err(VmaError::AlreadyMapped)
+    ));
+    let _ = vmm::unmap_page(filled);
+    vmm::free_frame(phys);
+
+    register(
+        anon,
+        size,
+        PageFlags::WRITABLE,
+        VmaBacking::Anonymous,
+        "test-anon",
+    )
+    .expect("register anon failed");
+    register(
+        filled,
+        size,
+        PageFlags::NONE,
+        VmaBacking::Fill(test_fill_pattern),
+        "test-fill",
+    )
+    .expect("register fill failed");
+    assert!(matches!(
+        register(
+            anon,
+            PAGE_SIZE,
+            PageFlags::NONE,
+            VmaBacking::Anonymous,
+            "dup"
+        ),
+        Err(VmaError::Overlap)
+    ));
+
+    assert!(!vmm::is_mapped(anon), "VMA should not be mapped up front");
+
+    unsafe {
+        // Read fault on an anonymous page: zero-filled
+        let p = (anon.0 + PAGE_SIZE as u32 + 8) as *mut u32;
+        assert_eq!(p.read_volatile(), 0);
+        p.write_volatile(0xFEED_BEEF);
+        assert_eq!(p.read_volatile(), 0xFEED_BEEF);
+
+        // Read fault on a fill page: callback content, mapped read-only
+        let q = (filled.0 + 2 * PAGE_SIZE as u32) as *const u8;
+        let tag = ((filled.0 + 2 * PAGE_SIZE as u32) >> 12) as u8;
+        assert_eq!(q.read_volatile(), tag);
+        assert_eq!(q.add(5).read_volatile(), tag.wrapping_add(5));
+    }
+    assert!(vmm::is_mapped(VirtAddr::new(anon.0 + PAGE_SIZE as u32)));
+    assert!(!vmm::is_mapped(anon), "untouched pages stay unmapped");
+
+    assert!(unregister(anon).is_some());
+    assert!(unregister(filled).is_some());
+    assert!(!vmm::is_mapped(VirtAddr::new(anon.0 + PAGE_SIZE as u32)));
+
+    m_println!("OK");
+}
@@ -25,13 +25,17 @@
 // All PDE/PTE access goes through the unified PageEntry type and
 // PageFlags - no raw bitmask constants in this file.
 
-use super::define::{KERNEL_OFFSET, PAGE_SIZE};
+use super::addrspace::AddressSpace;
+use super::define::{KERNEL_OFFSET, KMAP_SLOTS, PAGE_SIZE, VMALLOC_END, VMALLOC_START};
+use super::kmap::kmap;
 use super::pageflags::PageFlags;
-use super::paging::PageEntry;
-use super::physical::{PhysFrame, FRAME_ALLOCATOR};
+use super::paging::{self, PageEntry, PAE_DIRS, PAE_ENTRIES};
+use super::physical::{FrameOwner, PhysFrame, FRAME_ALLOCATOR};
 use crate::dbg_println;
 use crate::m_print;
 use crate::m_println;
+use alloc::vec::Vec;
+
 // ---------------------------------------------------------------------------
 // Address wrapper types
 // ---------------------------------------------------------------------------
@@ -48,14 +52,16 @@ impl VirtAddr {
         VirtAddr(addr)
     }
     // Bits [31:22] - selects one of 1024 page directory entries
+    // (PAE: bits [31:21], one of 2048 across the four directories)
     #[inline]
     pub fn pde_index(&self) -> usize {
-        (self.0 >> 22) as usize
+        (self.0 >> layout().dir_shift) as usize
     }
     // Bits [21:12] - selects one of 1024 page table entries
+    // (PAE: bits [20:12], one of 512)
     #[inline]
     pub fn pte_index(&self) -> usize {
-        ((self.0 >> 12) & 0x3FF) as usize
+        (self.0 >> 12) as usize & (layout().table_entries - 1)
     }
     // Bits [11:0] - byte offset within the 4 KB page
     #[inline]
@@ -66,6 +72,10 @@ impl VirtAddr {
     pub fn is_page_aligned(&self) -> bool {
         self.0 & 0xFFF == 0
     }
+    #[inline]
+    pub fn is_large_page_aligned(&self) -> bool {
+        self.0 as usize & (layout().large_page_size() - 1) == 0
+    }
     // Is this address in the kernel half (>= 0xC0000000)?
     #[inline]
     pub fn is_kernel(&self) -> bool {
@@ -82,22 +92,168 @@ impl PhysAddr {
     pub fn is_page_aligned(&self) -> bool {
         self.0 & 0xFFF == 0
     }
+    #[inline]
+    pub fn is_large_page_aligned(&self) -> bool {
+        self.0 as usize & (layout().large_page_size() - 1) == 0
+    }
 }
 
 // ---------------------------------------------------------------------------
-// Recursive mapping constants
+// Recursive mapping layout
 // ---------------------------------------------------------------------------
-
+//
+// Legacy paging: 1024 PDEs of 4 MB, page tables of 1024 × 4-byte entries.
 // We sacrifice the last 4 MB of virtual space (0xFFC00000-0xFFFFFFFF)
 // for the recursive mapping.  This region is kernel-only.
-const RECURSIVE_INDEX: usize = 1023;
+//
+// PAE: the four page directories are treated as one array of 2048 PDEs
+// of 2 MB (index = address >> 21), page tables of 512 × 8-byte entries.
+// The last four PDEs point to the four directories, which puts page
+// table N at 0xFF800000 + N * PAGE_SIZE and all 2048 PDEs back to back
+// at 0xFFFFC000 - the same arithmetic as the legacy layout, so every
+// walker below works for both.
+//
+// Below the recursive slots sits the "foreign" window (one PDE, or four
+// under PAE): pointing it at another address space's directories
+// exposes that space's page tables at foreign_base() + N * PAGE_SIZE,
+// exactly like the recursive slots do for the active one (the other
+// space's own recursive slots point to itself, so its PDEs show up at
+// the same offset).  The window is private to each address space and
+// empty at rest.
+#[derive(Clone, Copy)]
+pub(crate) struct Layout {
+    // log2 of the span of one PDE (22 or 21)
+    pub(crate) dir_shift: u32,
+    // Entries per page table
+    pub(crate) table_entries: usize,
+    // First foreign slot; everything from here up is reserved
+    pub(crate) foreign_index: usize,
+    // First recursive slot
+    pub(crate) recursive_index: usize,
+    // Kernel PDEs shared by every address space: kernel_pde_start..foreign_index
+    pub(crate) kernel_pde_start: usize,
+}
+
+const LEGACY_LAYOUT: Layout = Layout {
+    dir_shift: 22,
+    table_entries: 1024,
+    foreign_index: 1022,
+    recursive_index: 1023,
+    kernel_pde_start: KERNEL_OFFSET >> 22,
+};
+
+const PAE_LAYOUT: Layout = Layout {
+    dir_shift: 21,
+    table_entries: PAE_ENTRIES,
+    foreign_index: 2040,
+    recursive_index: 2044,
+    kernel_pde_start: KERNEL_OFFSET >> 21,
+};
+
+impl Layout {
+    // Base virtual address where page table N is mapped:
+    //   page_table_virt(N) = tables_base() + N * PAGE_SIZE
+    #[inline]
+    fn tables_base(self) -> u32 {
+        (self.recursive_index as u32) << self.dir_shift
+    }
+
+    #[inline]
+    pub(crate) fn foreign_base(self) -> u32 {
+        (self.foreign_index as u32) << self.dir_shift
+    }
+
+    // Virtual address of the PDEs themselves
+    // (= page_table_virt(recursive_index))
+    #[inline]
+    fn dir_virt(self) -> u32 {
+        self.tables_base() + (self.recursive_index as u32) * PAGE_SIZE as u32
+    }
+
+    // Start of the kmap window (see kmap.rs): KMAP_SLOTS pages just
+    // below the foreign window.
+    #[inline]
+    pub(crate) fn kmap_base(self) -> u32 {
+        self.foreign_base() - (KMAP_SLOTS * PAGE_SIZE) as u32
+    }
+
+    // Size mapped by one PS=1 PDE: 4 MB, or 2 MB under PAE.
+    #[inline]
+    pub(crate) fn large_page_size(self) -> usize {
+        1 << self.dir_shift
+    }
+
+    #[inline]
+    pub(crate) fn large_page_frames(self) -> usize {
+        self.large_page_size() / PAGE_SIZE
+    }
+
+    // Number of recursive (and foreign) slots: one per directory.
+    #[inline]
+    pub(crate) fn slots(self) -> usize {
+        if self.table_entries == PAE_ENTRIES {
+            PAE_DIRS
+        } else {
+            1
+        }
+    }
+}
+
+// Layout of the paging mode in use.
+#[inline]
+pub(crate) fn layout() -> Layout {
+    if paging::pae_enabled() {
+        PAE_LAYOUT
+    } else {
+        LEGACY_LAYOUT
+    }
+}
+
+// Set by init() once CR4.PSE is on (or PAE, where PS=1 always works);
+// map_range() only builds large pages when it is.
+static mut PSE_ENABLED: bool = false;
+
+// Can the VMM use large pages?
+#[inline]
+pub fn pse_enabled() -> bool {
+    unsafe { PSE_ENABLED }
+}
+
+// Which recursive window a page-table walk goes through.
+#[derive(Clone, Copy)]
+pub(crate) struct Tables {
+    base: u32,
+    // True for the tables behind CR3: only those need invlpg
+    active: bool,
+}
+
+impl Tables {
+    #[inline]
+    pub(crate) fn active() -> Tables {
+        Tables {
+            base: layout().tables_base(),
+            active: true,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn foreign() -> Tables {
+        Tables {
+            base: layout().foreign_base(),
+            active: false,
+        }
+    }
 
-// Base virtual address where page table N is mapped:
-//   page_table_virt(N) = PAGE_TABLES_VBASE + N * PAGE_SIZE
-const PAGE_TABLES_VBASE: u32 = 0xFFC00000;
+    #[inline]
+    fn pd_virt(self) -> u32 {
+        self.base + (layout().recursive_index as u32) * PAGE_SIZE as u32
+    }
 
-// Virtual address of the page directory itself (= page_table_virt(1023))
-const PAGE_DIR_VIRT: u32 = PAGE_TABLES_VBASE + (RECURSIVE_INDEX as u32) * PAGE_SIZE as u32;
+    #[inline]
+    fn pt_virt(self, pde_index: usize) -> u32 {
+        self.base + pde_index as u32 * PAGE_SIZE as u32
+    }
+}
 
 // ---------------------------------------------------------------------------
 // Error types
@@ -108,12 +264,28 @@ pub enum MapError {
     FrameAllocationFailed,
     AlreadyMapped,
     InvalidAddress,
+    // kmap: every slot of the window is in use
+    NoKmapSlot,
+    // The range is covered by a large page, which cannot be split
+    LargePage,
 }
 
 #[derive(Debug)]
+pub enum ProtectError {
+    // A page in the range is not mapped
+    NotMapped,
+    // The range covers only part of a large page
+    LargePage,
+    // The range reaches into the reserved top of memory or wraps
+    InvalidAddress,
+}
+
+#[derive(Debug, PartialEq, Eq)]
 pub enum UnmapError {
     NotMapped,
     PageTableNotPresent,
+    // The address is covered by a large page, which cannot be split
+    LargePage,
 }
 
 // Errors returned by vmalloc / vfree.
@@ -121,13 +293,48 @@ pub enum UnmapError {
 pub enum VmError {
     // size argument was zero.
     ZeroSize,
-    // Address or range overlaps the recursive-mapping region (0xFFC00000+).
-    // That region is the live page directory - it cannot be remapped.
+    // Address or range overlaps the reserved top of memory (0xFF7F0000+):
+    // kmap window, foreign window and the live page directory.
     RecursiveRegion,
     // No free physical frames available.
     OutOfMemory,
     // One or more pages in the requested range are already mapped.
     AlreadyMapped,
+    // vfree: no vmalloc region starts at this address.
+    NotAllocated,
+    // vfree: range does not match the recorded region exactly.
+    PartialRange,
+    // vmalloc: no gap in the vmalloc window is large enough.
+    NoVirtualSpace,
+    // vfree: the region maps device memory - use iounmap.
+    IoRegion,
+    // ioremap: the range contains usable RAM.
+    ManagedRam,
+    // ioremap: the range runs past the 4 GB physical address space.
+    PhysRange,
+    // The range covers only part of a large page.
+    LargePage,
+}
+
+impl From<MapError> for VmError {
+    fn from(e: MapError) -> Self {
+        match e {
+            MapError::FrameAllocationFailed => VmError::OutOfMemory,
+            MapError::AlreadyMapped => VmError::AlreadyMapped,
+            MapError::InvalidAddress => VmError::RecursiveRegion,
+            MapError::NoKmapSlot => VmError::OutOfMemory,
+            MapError::LargePage => VmError::LargePage,
+        }
+    }
+}
+
+impl From<UnmapError> for VmError {
+    fn from(e: UnmapError) -> Self {
+        match e {
+            UnmapError::LargePage => VmError::LargePage,
+            UnmapError::NotMapped | UnmapError::PageTableNotPresent => VmError::NotAllocated,
+        }
+    }
 }
 
 // ---------------------------------------------------------------------------
@@ -143,83 +350,301 @@ fn page_align_up(addr: u32) -> u32 {
 // vmalloc / vfree / vsize
 //
 // Design:
-//   - Any virtual address is accepted; addr is rounded UP to the nearest
-//     page boundary before use.
-//   - The only hard block is the recursive-mapping region (0xFFC00000+)
-//     which is a hardware constraint - PDE[1023] is the page directory
-//     itself and cannot be remapped.
-//   - No bookkeeping table.  The caller owns (addr, size), mirroring
-//     POSIX mmap/munmap.  vsize walks the live page tables directly.
+//   - vmalloc(size) picks the range itself: first fit inside the
+//     VMALLOC_START..VMALLOC_END window, leaving at least one unmapped
+//     guard page between any two regions and at both window edges, so a
+//     linear overrun or underrun faults on the very next page.
+//   - vmalloc_at(addr, size) maps at a caller-chosen address instead;
+//     addr is rounded UP to the nearest page boundary.  The only hard
+//     block is the reserved top of memory (kmap window, foreign and
+//     recursive slots, 0xFF7F0000+).
+//   - Every successful allocation is recorded in VM_REGIONS, an
+//     address-sorted list on the kernel heap.  vfree only accepts a range
+//     that matches a recorded region exactly, so a wrong size can no
+//     longer unmap somebody else's pages.
 // ---------------------------------------------------------------------------
 
-// Map `size` bytes starting at `addr` (rounded up to page boundary).
+// Unmapped pages left on each side of a vmalloc region.
+const GUARD_SIZE: u32 = PAGE_SIZE as u32;
+
+// One live vmalloc allocation.
+#[derive(Debug, Clone, Copy)]
+pub struct VmRegion {
+    pub start: u32,
+    pub pages: usize,
+    pub flags: PageFlags,
+    // Who asked for it - free-form tag shown by `vmaps`
+    pub owner: &'static str,
+    // ioremap: the device memory behind the region.  None for vmalloc
+    // memory, whose frames come and go with the region.
+    pub io_phys: Option<u32>,
+}
+
+impl VmRegion {
+    #[inline]
+    pub fn end(&self) -> u32 {
+        self.start + (self.pages * PAGE_SIZE) as u32
+    }
+
+    #[inline]
+    pub fn contains(&self, addr: u32) -> bool {
+        addr >= self.start && addr < self.end()
+    }
+}
+
+// Which side of a region a guard page sits on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuardSide {
+    // The page just below the region: an underrun.
+    Below,
+    // The page just past the end of the region: an overrun.
+    Above,
+}
+
+// Live regions, sorted by start address.
+static mut VM_REGIONS: Vec<VmRegion> = Vec::new();
+
+// Map `size` bytes at a free spot in the vmalloc window and record the
+// region under `owner`.  The pages either side stay unmapped.
+//
+// Returns the base address of the new region.
+pub fn vmalloc(size: usize, flags: PageFlags, owner: &'static str) -> Result<u32, VmError> {
+    if size == 0 {
+        return Err(VmError::ZeroSize);
+    }
+
+    let pages = size.div_ceil(PAGE_SIZE);
+    let start = find_free_range(pages).ok_or(VmError::NoVirtualSpace)?;
+    map_region(start, pages, flags, owner)?;
+    Ok(start)
+}
+
+// Map `size` bytes starting at `addr` (rounded up to page boundary) and
+// record the region under `owner`.  No guard pages are reserved.
 //
 // Returns `(aligned_addr, pages_mapped)` so the caller always knows
 // the actual base that was mapped even if rounding occurred.
-pub fn vmalloc(addr: u32, size: usize) -> Result<(u32, usize), VmError> {
+pub fn vmalloc_at(
+    addr: u32,
+    size: usize,
+    flags: PageFlags,
+    owner: &'static str,
+) -> Result<(u32, usize), VmError> {
     if size == 0 {
         return Err(VmError::ZeroSize);
     }
 
     let aligned_addr = page_align_up(addr);
-    let pages = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+    let pages = size.div_ceil(PAGE_SIZE);
     let byte_size = pages * PAGE_SIZE;
 
-    // Only hard constraint: must not touch the recursive-mapping region.
-    if (aligned_addr as usize).saturating_add(byte_size) > 0xFFC0_0000 {
+    // Only hard constraint: must not touch the reserved top of memory.
+    if (aligned_addr as usize).saturating_add(byte_size) > layout().kmap_base() as usize {
         return Err(VmError::RecursiveRegion);
     }
 
-    map_range(
-        VirtAddr::new(aligned_addr),
-        byte_size,
-        PageFlags::PRESENT | PageFlags::WRITABLE,
-    )
-    .map_err(|e| match e {
-        MapError::FrameAllocationFailed => VmError::OutOfMemory,
-        MapError::AlreadyMapped => VmError::AlreadyMapped,
-        MapError::InvalidAddress => VmError::RecursiveRegion,
-    })?;
-
+    map_region(aligned_addr, pages, flags, owner)?;
     Ok((aligned_addr, pages))
 }
 
-// Unmap `size` bytes starting at `addr` (rounded up to match vmalloc).
+// First-fit search of the vmalloc window for `pages` pages with a guard
+// page on either side.  Neighbouring regions may share one guard page.
+// Nothing is reserved: the range is only free until the next vmalloc.
+pub(crate) fn find_free_range(pages: usize) -> Option<u32> {
+    let byte_size = pages.checked_mul(PAGE_SIZE)?;
+    // Lowest start that still leaves `end` (exclusive, guard included)
+    // at or below `limit`.
+    let fits = |start: u32, limit: u32| {
+        (start as usize)
+            .checked_add(byte_size + GUARD_SIZE as usize)
+            .is_some_and(|end| end <= limit as usize)
+    };
+
+    let mut cursor = VMALLOC_START as u32 + GUARD_SIZE;
+    unsafe {
+        for region in VM_REGIONS.iter() {
+            if region.end() <= VMALLOC_START as u32 || region.start >= VMALLOC_END as u32 {
+                continue;
+            }
+            if fits(cursor, region.start) {
+                return Some(cursor);
+            }
+            cursor = cursor.max(region.end() + GUARD_SIZE);
+        }
+    }
+    fits(cursor, VMALLOC_END as u32).then_some(cursor)
+}
+
+// Map and record [start, start + pages) - shared tail of vmalloc and
+// vmalloc_at.  `start` is page-aligned and the range already validated.
+fn map_region(
+    start: u32,
+    pages: usize,
+    flags: PageFlags,
+    owner: &'static str,
+) -> Result<(), VmError> {
+    let byte_size = pages * PAGE_SIZE;
+
+    // Refuse up front if anything in the range is mapped, so a failure
+    // half-way through can be rolled back without touching other
+    // people's pages.
+    for i in 0..pages {
+        if is_mapped(VirtAddr::new(start + (i * PAGE_SIZE) as u32)) {
+            return Err(VmError::AlreadyMapped);
+        }
+    }
+
+    // vmalloc memory is data: never executable (where NX exists)
+    let flags = flags | PageFlags::PRESENT | PageFlags::NO_EXECUTE;
+    if let Err(e) = map_range(VirtAddr::new(start), byte_size, flags) {
+        // Whatever map_range() managed lies wholly inside the range
+        let _ = unmap_range(VirtAddr::new(start), byte_size);
+        return Err(e.into());
+    }
+
+    insert_region(VmRegion {
+        start,
+        pages,
+        flags,
+        owner,
+        io_phys: None,
+    });
+    Ok(())
+}
+
+fn insert_region(region: VmRegion) {
+    unsafe {
+        let index = VM_REGIONS.partition_point(|r| r.start < region.start);
+        VM_REGIONS.insert(index, region);
+    }
+}
+
+// Map the device range [phys, phys + pages) at a free spot in the
+// vmalloc window, guard pages included, and record it under `owner`.
+// No frame is allocated or freed for it: see ioremap.rs.
+pub fn vmap_io(
+    phys: PhysAddr,
+    pages: usize,
+    flags: PageFlags,
+    owner: &'static str,
+) -> Result<u32, VmError> {
+    if pages == 0 {
+        return Err(VmError::ZeroSize);
+    }
+    let start = find_free_range(pages).ok_or(VmError::NoVirtualSpace)?;
+    let byte_size = pages * PAGE_SIZE;
+
+    let flags = flags | PageFlags::PRESENT | PageFlags::NO_EXECUTE;
+    if let Err(e) = map_range_to(VirtAddr::new(start), phys, byte_size, flags) {
+        let _ = unmap_range_at(Tables::active(), VirtAddr::new(start), byte_size, false);
+        return Err(e.into());
+    }
+
+    insert_region(VmRegion {
+        start,
+        pages,
+        flags,
+        owner,
+        io_phys: Some(phys.0),
+    });
+    Ok(start)
+}
+
+// Undo vmap_io() for the region starting at `addr`.  The device memory
+// is left alone.  Returns the number of pages unmapped.
+pub fn vunmap_io(addr: u32) -> Result<usize, VmError> {
+    unsafe {
+        let index = VM_REGIONS
+            .iter()
+            .position(|r| r.start == addr && r.io_phys.is_some())
+            .ok_or(VmError::NotAllocated)?;
+        let region = VM_REGIONS[index];
+        let size = region.pages * PAGE_SIZE;
+        let (pages, _) =
+            unmap_range_at(Tables::active(), VirtAddr::new(region.start), size, false)?;
+        VM_REGIONS.remove(index);
+        Ok(pages)
+    }
+}
+
+// Unmap the region recorded at `addr` (rounded up to match vmalloc).
+// `size` must be the size it was allocated with.
 //
-// Returns the number of pages actually freed.
-pub fn vfree(addr: u32, size: usize) -> Result<usize, VmError> {
+// Returns the number of pages actually freed and of page tables
+// released because the region left them empty.
+pub fn vfree(addr: u32, size: usize) -> Result<(usize, usize), VmError> {
     if size == 0 {
         return Err(VmError::ZeroSize);
     }
 
     let aligned_addr = page_align_up(addr);
-    let pages = (size + PAGE_SIZE - 1) / PAGE_SIZE;
-    let byte_size = pages * PAGE_SIZE;
+    let pages = size.div_ceil(PAGE_SIZE);
+
+    unsafe {
+        let index = match VM_REGIONS.iter().position(|r| r.start == aligned_addr) {
+            Some(index) => index,
+            None if find_region(aligned_addr).is_some() => return Err(VmError::PartialRange),
+            None => return Err(VmError::NotAllocated),
+        };
+        let region = VM_REGIONS[index];
+        if region.io_phys.is_some() {
+            return Err(VmError::IoRegion);
+        }
+        if region.pages != pages {
+            return Err(VmError::PartialRange);
+        }
 
-    let freed = unmap_range(VirtAddr::new(aligned_addr), byte_size);
-    Ok(freed)
+        let freed = unmap_range_at(
+            Tables::active(),
+            VirtAddr::new(region.start),
+            region.pages * PAGE_SIZE,
+            true,
+        )?;
+        VM_REGIONS.remove(index);
+        Ok(freed)
+    }
 }
 
-// Count consecutive mapped pages from `addr` (rounded up to page boundary).
-//
-// Walks the live page tables - the page tables are the ground truth.
-// Returns the number of mapped bytes, or 0 if the address is not mapped.
+// Size in bytes of the vmalloc region containing `addr` (rounded up to
+// a page boundary), or 0 if no region covers it.
 pub fn vsize(addr: u32) -> usize {
-    let aligned_addr = page_align_up(addr);
-    let mut count: usize = 0;
+    find_region(page_align_up(addr)).map_or(0, |r| r.pages * PAGE_SIZE)
+}
 
-    loop {
-        let cur = (aligned_addr as usize).saturating_add(count * PAGE_SIZE);
-        if cur >= 0xFFC0_0000 {
-            break;
-        }
-        if !is_mapped(VirtAddr::new(cur as u32)) {
-            break;
+// The recorded region containing `addr`, if any.
+pub fn find_region(addr: u32) -> Option<VmRegion> {
+    unsafe {
+        let index = VM_REGIONS.partition_point(|r| r.end() <= addr);
+        VM_REGIONS.get(index).filter(|r| r.contains(addr)).copied()
+    }
+}
+
+// The region whose guard page contains `addr`, if any.  A page between
+// two regions is reported as the overrun of the lower one.
+pub fn guard_hit(addr: u32) -> Option<(VmRegion, GuardSide)> {
+    let page = addr & !(PAGE_SIZE as u32 - 1);
+    if find_region(page).is_some() {
+        return None;
+    }
+    unsafe {
+        let index = VM_REGIONS.partition_point(|r| r.end() <= page);
+        if index > 0 && VM_REGIONS[index - 1].end() == page {
+            return Some((VM_REGIONS[index - 1], GuardSide::Above));
         }
-        count += 1;
+        VM_REGIONS
+            .get(index)
+            .filter(|r| r.start == page.wrapping_add(GUARD_SIZE))
+            .map(|r| (*r, GuardSide::Below))
     }
+}
 
-    count * PAGE_SIZE
+// Call `f` for every live region, lowest address first.
+pub fn for_each_region(mut f: impl FnMut(&VmRegion)) {
+    unsafe {
+        for region in VM_REGIONS.iter() {
+            f(region);
+        }
+    }
 }
 
 // ---------------------------------------------------------------------------
@@ -247,37 +672,119 @@ pub fn flush_tlb_all() {
 // ---------------------------------------------------------------------------
 // Typed PDE / PTE access via recursive mapping
 //
-// PageEntry is #[repr(C)] wrapping a single u32 (size = 4, align = 4),
-// so ptr.add(index) correctly strides by 4 bytes - matching the
-// hardware layout of 1024 × 4-byte entries per page table.
+// The recursive window shows the hardware tables as they are: 4-byte
+// entries in legacy mode, 8-byte entries under PAE.  paging::read_entry
+// and write_entry stride and convert accordingly.
 // ---------------------------------------------------------------------------
 
 // Read PDE[index] through the recursive mapping.
 #[inline]
 unsafe fn read_pde(index: usize) -> PageEntry {
-    let ptr = PAGE_DIR_VIRT as *const PageEntry;
-    ptr.add(index).read_volatile()
+    paging::read_entry(layout().dir_virt() as *const u8, index)
 }
 
-// Write PDE[index] through the recursive mapping.
+// Read PDE[index] of the directory behind `t`.
 #[inline]
-unsafe fn write_pde(index: usize, entry: PageEntry) {
-    let ptr = PAGE_DIR_VIRT as *mut PageEntry;
-    ptr.add(index).write_volatile(entry);
+pub(crate) unsafe fn read_pde_at(t: Tables, index: usize) -> PageEntry {
+    paging::read_entry(t.pd_virt() as *const u8, index)
+}
+
+// Write PDE[index] of the directory behind `t`.
+#[inline]
+pub(crate) unsafe fn write_pde_at(t: Tables, index: usize, entry: PageEntry) {
+    paging::write_entry(t.pd_virt() as *mut u8, index, entry);
+}
+
+// Change the flags of a present PDE.  Kernel-half entries are mirrored
+// into the boot directory, which AddressSpace::activate() copies the
+// kernel half from; otherwise the next sync would revert the change.
+unsafe fn update_pde_at(t: Tables, index: usize, entry: PageEntry) {
+    write_pde_at(t, index, entry);
+    if index >= layout().kernel_pde_start {
+        super::paging().set_entry(index, entry.address(), entry.flags());
+    }
 }
 
 // Read PTE[pte_index] inside page table `pde_index`.
 #[inline]
-unsafe fn read_pte(pde_index: usize, pte_index: usize) -> PageEntry {
-    let pt_base = (PAGE_TABLES_VBASE + pde_index as u32 * PAGE_SIZE as u32) as *const PageEntry;
-    pt_base.add(pte_index).read_volatile()
+pub(crate) unsafe fn read_pte_at(t: Tables, pde_index: usize, pte_index: usize) -> PageEntry {
+    paging::read_entry(t.pt_virt(pde_index) as *const u8, pte_index)
 }
 
 // Write PTE[pte_index] inside page table `pde_index`.
 #[inline]
-unsafe fn write_pte(pde_index: usize, pte_index: usize, entry: PageEntry) {
-    let pt_base = (PAGE_TABLES_VBASE + pde_index as u32 * PAGE_SIZE as u32) as *mut PageEntry;
-    pt_base.add(pte_index).write_volatile(entry);
+pub(crate) unsafe fn write_pte_at(t: Tables, pde_index: usize, pte_index: usize, entry: PageEntry) {
+    paging::write_entry(t.pt_virt(pde_index) as *mut u8, pte_index, entry);
+}
+
+// Make `pd_phys` reachable through the foreign window, run `f`, then
+// close the window again.  If `pd_phys` is the active directory we just
+// use the normal recursive mapping.
+//
+// `pd_phys` is the CR3 value of the address space: its page directory,
+// or under PAE the frame holding its PDPT.
+pub(crate) fn with_tables<R>(pd_phys: u32, f: impl FnOnce(Tables) -> R) -> R {
+    if pd_phys == current_pd_phys() {
+        return f(Tables::active());
+    }
+
+    let l = layout();
+    let mut dirs = [0u32; PAE_DIRS];
+    if paging::pae_enabled() {
+        dirs = pdpt_dirs(pd_phys);
+    } else {
+        dirs[0] = pd_phys;
+    }
+
+    unsafe {
+        assert!(
+            !read_pde(l.foreign_index).present(),
+            "VMM: foreign window already in use"
+        );
+        for (i, &dir) in dirs.iter().take(l.slots()).enumerate() {
+            write_pde_at(
+                Tables::active(),
+                l.foreign_index + i,
+                PageEntry::new(dir, PageFlags::PRESENT | PageFlags::WRITABLE),
+            );
+        }
+        flush_tlb_all();
+
+        let result = f(Tables::foreign());
+
+        for i in 0..l.slots() {
+            write_pde_at(Tables::active(), l.foreign_index + i, PageEntry::empty());
+        }
+        flush_tlb_all();
+        result
+    }
+}
+
+// The four page directories listed in the PDPT at `pdpt_phys` (PAE).
+pub(crate) fn pdpt_dirs(pdpt_phys: u32) -> [u32; PAE_DIRS] {
+    let mut dirs = [0u32; PAE_DIRS];
+    let pdpt =
+        kmap(PhysFrame::containing_address(pdpt_phys as usize)).expect("VMM: cannot map PDPT");
+    for (i, dir) in dirs.iter_mut().enumerate() {
+        *dir = unsafe { paging::read_entry(pdpt.as_ptr(), i) }.address();
+    }
+    dirs
+}
+
+// Physical address of the page directory currently loaded in CR3 (the
+// PDPT under PAE).
+#[inline]
+pub fn current_pd_phys() -> u32 {
+    let cr3: u32;
+    unsafe {
+        core::arch::asm!("mov {}, cr3", out(reg) cr3, options(nostack, nomem));
+    }
+    // PAE: the PDPT only has to be 32-byte aligned
+    if paging::pae_enabled() {
+        cr3 & !0x1F
+    } else {
+        cr3 & !0xFFF
+    }
 }
 
 // ---------------------------------------------------------------------------
@@ -286,29 +793,85 @@ unsafe fn write_pte(pde_index: usize, pte_index: usize, entry: PageEntry) {
 
 // Set up recursive page directory mapping at PDE[1023].
 //
-// Must be called once, early in boot, after `PAGING` is initialised.
-// After this, all map/unmap/translate operations use the recursive
-// mapping and do not need the PageDirectory wrapper.
+// With the `pae` feature, a CPU that reports PAE is first switched to
+// PAE paging (plus NX when available), whose boot tables come with
+// their recursive slots already in place.
+//
+// Must be called once, early in boot, after `PAGING` is initialised and
+// before clear_page1().  After this, all map/unmap/translate operations
+// use the recursive mapping and do not need the PageDirectory wrapper.
 pub fn init() {
     unsafe {
-        let pd = super::PAGING.as_mut().expect("PAGING not initialised");
-        let pd_phys = pd.physical_address();
-
-        pd.set_entry(
-            RECURSIVE_INDEX,
-            pd_phys,
-            PageFlags::PRESENT | PageFlags::WRITABLE,
+        // CR0.WP: make read-only PTEs binding for ring 0 too.  Without it
+        // kernel writes silently go through COW and read-only mappings.
+        core::arch::asm!(
+            "mov {tmp}, cr0",
+            "or {tmp}, 0x10000",
+            "mov cr0, {tmp}",
+            tmp = out(reg) _,
+            options(nostack, nomem),
         );
 
-        flush_tlb_all();
+        // CR4.PSE: let PDEs with PS=1 map 4 MB pages directly
+        // (CPUID.01h:EDX bit 3).
+        if crate::utils::cpuid(1)[3] & (1 << 3) != 0 {
+            core::arch::asm!(
+                "mov {tmp}, cr4",
+                "or {tmp}, 0x10",
+                "mov cr4, {tmp}",
+                tmp = out(reg) _,
+                options(nostack, nomem),
+            );
+            PSE_ENABLED = true;
+        }
+
+        #[cfg(feature = "pae")]
+        if cpu_has_pae() {
+            let pd = paging::enable_pae(cpu_has_nx());
+            super::PAGING = Some(pd);
+            PSE_ENABLED = true;
+        }
+
+        if !paging::pae_enabled() {
+            let pd = super::PAGING.as_mut().expect("PAGING not initialised");
+            let pd_phys = pd.physical_address();
+
+            pd.set_entry(
+                LEGACY_LAYOUT.recursive_index,
+                pd_phys,
+                PageFlags::PRESENT | PageFlags::WRITABLE,
+            );
+
+            flush_tlb_all();
+        }
     }
 
     dbg_println!(
-        "VMM: recursive mapping installed at PDE[{}]",
-        RECURSIVE_INDEX
+        "VMM: {} paging, recursive mapping at PDE[{}], large pages {}, NX {}",
+        if paging::pae_enabled() {
+            "PAE"
+        } else {
+            "legacy"
+        },
+        layout().recursive_index,
+        if pse_enabled() { "on" } else { "off" },
+        if paging::nx_enabled() { "on" } else { "off" }
     );
 }
 
+// CPUID.01h:EDX bit 6
+#[cfg(feature = "pae")]
+fn cpu_has_pae() -> bool {
+    crate::utils::cpuid(1)[3] & (1 << 6) != 0
+}
+
+// CPUID.80000001h:EDX bit 20, if the extended leaf exists
+#[cfg(feature = "pae")]
+fn cpu_has_nx() -> bool {
+    crate::utils::cpuid(0x8000_0000)[0] >= 0x8000_0001
+        && crate::utils::cpuid(0x8000_0001)[3] & (1 << 20) != 0
+}
+
 // ---------------------------------------------------------------------------
 // Core API
 // ---------------------------------------------------------------------------
@@ -322,6 +885,15 @@ pub fn init() {
 // PRESENT | WRITABLE (and USER if `flags` includes USER), which is the
 // standard "permissive PDE, restrictive PTE" policy.
 pub fn map_page(virt: VirtAddr, phys: PhysAddr, flags: PageFlags) -> Result<(), MapError> {
+    map_page_at(Tables::active(), virt, phys, flags)
+}
+
+fn map_page_at(
+    t: Tables,
+    virt: VirtAddr,
+    phys: PhysAddr,
+    flags: PageFlags,
+) -> Result<(), MapError> {
     assert!(
         virt.is_page_aligned(),
         "virt addr {:#x} not page-aligned",
@@ -336,55 +908,38 @@ pub fn map_page(virt: VirtAddr, phys: PhysAddr, flags: PageFlags) -> Result<(),
     let pde_idx = virt.pde_index();
     let pte_idx = virt.pte_index();
 
-    // The recursive slot is reserved - never map into it
-    if pde_idx == RECURSIVE_INDEX {
+    // The recursive and foreign slots are reserved - never map into them
+    if pde_idx >= layout().foreign_index {
         return Err(MapError::InvalidAddress);
     }
 
     unsafe {
         // Step 1: ensure a page table exists for this PDE
-        let pde = read_pde(pde_idx);
+        let pde = read_pde_at(t, pde_idx);
 
         if !pde.present() {
             // No page table yet - allocate a physical frame for one
-            let frame = alloc_frame()?;
-            let pt_phys = frame.start_address() as u32;
-
-            // PDE flags: permissive - the PTE is the real gatekeeper.
-            let mut pde_flags = PageFlags::PRESENT | PageFlags::WRITABLE;
-            if flags.is_user() {
-                pde_flags = pde_flags | PageFlags::USER;
-            }
-
-            write_pde(pde_idx, PageEntry::new(pt_phys, pde_flags));
-
-            // Full TLB flush so the recursive mapping exposes the new PT
-            flush_tlb_all();
-
-            // Zero the fresh page table - all 1024 PTEs become non-present
-            let pt_virt = (PAGE_TABLES_VBASE + pde_idx as u32 * PAGE_SIZE as u32) as *mut u8;
-            core::ptr::write_bytes(pt_virt, 0, PAGE_SIZE);
-
-            dbg_println!(
-                "VMM: allocated PT frame {:#x} for PDE[{}]",
-                pt_phys,
-                pde_idx
-            );
+            alloc_table_at(t, pde_idx, flags.is_user())?;
+        } else if pde.page_size_4mb() {
+            // Already covered by a large page
+            return Err(MapError::AlreadyMapped);
         } else if flags.is_user() && !pde.user() {
             // Page table exists but PDE lacks USER - promote it
             let promoted = PageEntry::new(pde.address(), pde.flags() | PageFlags::USER);
-            write_pde(pde_idx, promoted);
+            update_pde_at(t, pde_idx, promoted);
             flush_tlb_all();
         }
 
         // Step 2: set the PTE
-        let pte = read_pte(pde_idx, pte_idx);
+        let pte = read_pte_at(t, pde_idx, pte_idx);
         if pte.present() {
             return Err(MapError::AlreadyMapped);
         }
 
-        write_pte(pde_idx, pte_idx, PageEntry::new(phys.0, flags));
-        flush_tlb_entry(virt);
+        write_pte_at(t, pde_idx, pte_idx, PageEntry::new(phys.0, flags));
+        if t.active {
+            flush_tlb_entry(virt);
+        }
     }
 
     dbg_println!(
@@ -397,57 +952,245 @@ pub fn map_page(virt: VirtAddr, phys: PhysAddr, flags: PageFlags) -> Result<(),
     Ok(())
 }
 
-// Convenience: allocate a physical frame *and* map it at `virt`.
-pub fn map_alloc(virt: VirtAddr, flags: PageFlags) -> Result<PhysAddr, MapError> {
-    let frame = alloc_frame()?;
-    let phys = PhysAddr::new(frame.start_address() as u32);
-    map_page(virt, phys, flags)?;
-    Ok(phys)
-}
-
-// Unmap a single 4 KB virtual page.
-//
-// Returns the physical address the page was mapped to.
-pub fn unmap_page(virt: VirtAddr) -> Result<PhysAddr, UnmapError> {
-    let pde_idx = virt.pde_index();
-    let pte_idx = virt.pte_index();
-
-    unsafe {
-        let pde = read_pde(pde_idx);
-        if !pde.present() {
-            return Err(UnmapError::PageTableNotPresent);
-        }
+// Allocate, zero and install the page table behind the empty PDE[pde_idx].
+unsafe fn alloc_table_at(t: Tables, pde_idx: usize, user: bool) -> Result<(), MapError> {
+    let frame = alloc_frame(FrameOwner::PageTable)?;
+    let pt_phys = frame.start_address() as u32;
 
-        let pte = read_pte(pde_idx, pte_idx);
-        if !pte.present() {
-            return Err(UnmapError::NotMapped);
-        }
+    // PDE flags: permissive - the PTE is the real gatekeeper.
+    let mut pde_flags = PageFlags::PRESENT | PageFlags::WRITABLE;
+    if user {
+        pde_flags = pde_flags | PageFlags::USER;
+    }
 
-        let phys = PhysAddr::new(pte.address());
+    write_pde_at(t, pde_idx, PageEntry::new(pt_phys, pde_flags));
 
-        write_pte(pde_idx, pte_idx, PageEntry::empty());
-        flush_tlb_entry(virt);
+    // Full TLB flush so the recursive mapping exposes the new PT
+    flush_tlb_all();
 
-        dbg_println!("VMM: unmapped virt {:#x} (was phys {:#x})", virt.0, phys.0);
+    // Zero the fresh page table - all its PTEs become non-present
+    let pt_virt = t.pt_virt(pde_idx) as *mut u8;
+    core::ptr::write_bytes(pt_virt, 0, PAGE_SIZE);
 
-        Ok(phys)
+    // Kernel page tables are shared: record the new one in the
+    // boot directory so every address space picks it up on its
+    // next activate().
+    if pde_idx >= layout().kernel_pde_start {
+        super::paging().set_entry(pde_idx, pt_phys, pde_flags);
     }
+
+    dbg_println!(
+        "VMM: allocated PT frame {:#x} for PDE[{}]",
+        pt_phys,
+        pde_idx
+    );
+    Ok(())
 }
 
-// Translate a virtual address to its physical address by walking
-// PDE -> PTE.  Returns `None` if any level is not present.
-pub fn translate(virt: VirtAddr) -> Option<PhysAddr> {
+// Make sure the kernel page table covering `virt` exists, without
+// mapping anything.  Used by windows that write their PTEs directly
+// (kmap).
+pub(crate) fn ensure_page_table(virt: VirtAddr) -> Result<(), MapError> {
     let pde_idx = virt.pde_index();
-    let pte_idx = virt.pte_index();
-
+    if pde_idx >= layout().foreign_index {
+        return Err(MapError::InvalidAddress);
+    }
     unsafe {
         let pde = read_pde(pde_idx);
         if !pde.present() {
-            return None;
+            alloc_table_at(Tables::active(), pde_idx, false)?;
+        } else if pde.page_size_4mb() {
+            return Err(MapError::AlreadyMapped);
         }
+    }
+    Ok(())
+}
 
-        let pte = read_pte(pde_idx, pte_idx);
-        if !pte.present() {
+// Map a single large page with one PDE (PS=1) - no page table involved.
+// That is 4 MB, or 2 MB under PAE (see Layout::large_page_size).
+//
+// Both addresses must be aligned to it and the PDE must be empty.
+pub fn map_large_page(virt: VirtAddr, phys: PhysAddr, flags: PageFlags) -> Result<(), MapError> {
+    map_large_page_at(Tables::active(), virt, phys, flags)
+}
+
+fn map_large_page_at(
+    t: Tables,
+    virt: VirtAddr,
+    phys: PhysAddr,
+    flags: PageFlags,
+) -> Result<(), MapError> {
+    assert!(
+        virt.is_large_page_aligned() && phys.is_large_page_aligned(),
+        "map_large_page: {:#x} -> {:#x} not large-page aligned",
+        virt.0,
+        phys.0
+    );
+    if !pse_enabled() {
+        return Err(MapError::InvalidAddress);
+    }
+
+    let pde_idx = virt.pde_index();
+    if pde_idx >= layout().foreign_index {
+        return Err(MapError::InvalidAddress);
+    }
+
+    let flags = flags | PageFlags::HUGE_PAGE;
+    unsafe {
+        if read_pde_at(t, pde_idx).present() {
+            return Err(MapError::AlreadyMapped);
+        }
+        write_pde_at(t, pde_idx, PageEntry::new(phys.0, flags));
+        if t.active {
+            flush_tlb_entry(virt);
+        }
+    }
+
+    // Shared kernel mapping: mirror it like a kernel page table
+    if pde_idx >= layout().kernel_pde_start {
+        super::paging().set_entry(pde_idx, phys.0, flags);
+    }
+
+    dbg_println!(
+        "VMM: mapped large page virt {:#x} -> phys {:#x} (flags {})",
+        virt.0,
+        phys.0,
+        flags
+    );
+
+    Ok(())
+}
+
+// Remove a large page.  Returns the physical base it mapped; the frames
+// are not freed.
+pub fn unmap_large_page(virt: VirtAddr) -> Result<PhysAddr, UnmapError> {
+    unmap_large_page_at(Tables::active(), virt)
+}
+
+fn unmap_large_page_at(t: Tables, virt: VirtAddr) -> Result<PhysAddr, UnmapError> {
+    let pde_idx = virt.pde_index();
+
+    unsafe {
+        let pde = read_pde_at(t, pde_idx);
+        if !pde.present() {
+            return Err(UnmapError::NotMapped);
+        }
+        if !pde.page_size_4mb() {
+            return Err(UnmapError::PageTableNotPresent);
+        }
+
+        write_pde_at(t, pde_idx, PageEntry::empty());
+        if t.active {
+            flush_tlb_entry(virt);
+        }
+        if pde_idx >= layout().kernel_pde_start {
+            super::paging().clear_entry(pde_idx);
+        }
+
+        dbg_println!(
+            "VMM: unmapped large page virt {:#x} (was phys {:#x})",
+            virt.0,
+            pde.address()
+        );
+
+        Ok(PhysAddr::new(pde.address()))
+    }
+}
+
+// Can [virt, virt + remaining) start with a whole large page?
+fn fits_large_page(t: Tables, virt: VirtAddr, remaining: usize) -> bool {
+    pse_enabled()
+        && virt.is_large_page_aligned()
+        && remaining >= layout().large_page_size()
+        && unsafe { !read_pde_at(t, virt.pde_index()).present() }
+}
+
+// Convenience: allocate a physical frame *and* map it at `virt`.
+pub fn map_alloc(virt: VirtAddr, flags: PageFlags) -> Result<PhysAddr, MapError> {
+    map_alloc_at(Tables::active(), virt, flags)
+}
+
+fn map_alloc_at(t: Tables, virt: VirtAddr, flags: PageFlags) -> Result<PhysAddr, MapError> {
+    let owner = if flags.is_user() {
+        FrameOwner::User
+    } else {
+        FrameOwner::Kernel
+    };
+    let frame = alloc_frame(owner)?;
+    let phys = PhysAddr::new(frame.start_address() as u32);
+    if let Err(e) = map_page_at(t, virt, phys, flags) {
+        free_frame(phys);
+        return Err(e);
+    }
+    Ok(phys)
+}
+
+// Unmap a single 4 KB virtual page.  The page table goes too if this
+// was its last entry.
+//
+// Returns the physical address the page was mapped to.
+pub fn unmap_page(virt: VirtAddr) -> Result<PhysAddr, UnmapError> {
+    let phys = unmap_page_at(Tables::active(), virt)?;
+    unsafe { release_table_if_empty(Tables::active(), virt.pde_index()) };
+    Ok(phys)
+}
+
+fn unmap_page_at(t: Tables, virt: VirtAddr) -> Result<PhysAddr, UnmapError> {
+    let pde_idx = virt.pde_index();
+    let pte_idx = virt.pte_index();
+
+    unsafe {
+        let pde = read_pde_at(t, pde_idx);
+        if !pde.present() {
+            return Err(UnmapError::PageTableNotPresent);
+        }
+        if pde.page_size_4mb() {
+            return Err(UnmapError::LargePage);
+        }
+
+        let pte = read_pte_at(t, pde_idx, pte_idx);
+        if !pte.present() {
+            return Err(UnmapError::NotMapped);
+        }
+
+        let phys = PhysAddr::new(pte.address());
+
+        write_pte_at(t, pde_idx, pte_idx, PageEntry::empty());
+        if t.active {
+            flush_tlb_entry(virt);
+        }
+
+        dbg_println!("VMM: unmapped virt {:#x} (was phys {:#x})", virt.0, phys.0);
+
+        Ok(phys)
+    }
+}
+
+// Translate a virtual address to its physical address by walking
+// PDE -> PTE.  Returns `None` if any level is not present.
+pub fn translate(virt: VirtAddr) -> Option<PhysAddr> {
+    translate_at(Tables::active(), virt)
+}
+
+fn translate_at(t: Tables, virt: VirtAddr) -> Option<PhysAddr> {
+    let pde_idx = virt.pde_index();
+    let pte_idx = virt.pte_index();
+
+    unsafe {
+        let pde = read_pde_at(t, pde_idx);
+        if !pde.present() {
+            return None;
+        }
+        if pde.page_size_4mb() {
+            // PS=1: bits [31:22] of the PDE are the frame, the low 22 bits
+            // of the address are the offset into it
+            let mask = layout().large_page_size() as u32 - 1;
+            let offset = virt.0 & mask;
+            return Some(PhysAddr::new((pde.address() & !mask) | offset));
+        }
+
+        let pte = read_pte_at(t, pde_idx, pte_idx);
+        if !pte.present() {
             return None;
         }
 
@@ -455,11 +1198,175 @@ pub fn translate(virt: VirtAddr) -> Option<PhysAddr> {
     }
 }
 
+// Flags of the entry that maps `virt` - the PTE, or the PDE of a large
+// page.  `None` if it is not mapped.
+pub fn page_flags(virt: VirtAddr) -> Option<PageFlags> {
+    let pde_idx = virt.pde_index();
+    unsafe {
+        let pde = read_pde(pde_idx);
+        if !pde.present() {
+            return None;
+        }
+        if pde.page_size_4mb() {
+            return Some(pde.flags());
+        }
+        let pte = read_pte_at(Tables::active(), pde_idx, virt.pte_index());
+        pte.present().then(|| pte.flags())
+    }
+}
+
 // Quick predicate - is this virtual page currently mapped?
 pub fn is_mapped(virt: VirtAddr) -> bool {
     translate(VirtAddr::new(virt.0 & !0xFFF)).is_some()
 }
 
+// Is any page of [start, start + size) mapped?  Empty page tables are
+// skipped whole.
+pub fn any_mapped(start: VirtAddr, size: usize) -> bool {
+    let end = start.0 as usize + size;
+    let span = layout().large_page_size();
+    let mut addr = start.0 as usize & !(PAGE_SIZE - 1);
+    while addr < end {
+        let virt = VirtAddr::new(addr as u32);
+        if !unsafe { read_pde(virt.pde_index()) }.present() {
+            addr = (addr & !(span - 1)) + span;
+            continue;
+        }
+        if is_mapped(virt) {
+            return true;
+        }
+        addr += PAGE_SIZE;
+    }
+    false
+}
+
+// ---------------------------------------------------------------------------
+// Inspection
+// ---------------------------------------------------------------------------
+
+// Every level of the walk for one address, as the MMU sees it.
+pub struct Walk {
+    // PAE only: PDPT index and entry
+    pub pdpt: Option<(usize, PageEntry)>,
+    pub pde_index: usize,
+    pub pde: PageEntry,
+    // None when the PDE is not present or maps a large page
+    pub pte: Option<(usize, PageEntry)>,
+    pub phys: Option<PhysAddr>,
+}
+
+pub fn walk(virt: VirtAddr) -> Walk {
+    let pde_index = virt.pde_index();
+    let pdpt = paging::pae_enabled().then(|| {
+        let pdpt_phys = current_pd_phys();
+        let index = pde_index / PAE_ENTRIES;
+        let view =
+            kmap(PhysFrame::containing_address(pdpt_phys as usize)).expect("VMM: cannot map PDPT");
+        let table = unsafe { view.as_ptr().add(pdpt_phys as usize & 0xFFF) };
+        (index, unsafe { paging::read_entry(table, index) })
+    });
+
+    let pde = unsafe { read_pde(pde_index) };
+    let pte = (pde.present() && !pde.page_size_4mb()).then(|| {
+        let index = virt.pte_index();
+        (index, unsafe {
+            read_pte_at(Tables::active(), pde_index, index)
+        })
+    });
+
+    Walk {
+        pdpt,
+        pde_index,
+        pde,
+        pte,
+        phys: translate(virt),
+    }
+}
+
+// A run of mapped pages with contiguous frames and the same flags.
+#[derive(Clone, Copy)]
+pub struct MappedRange {
+    pub virt: u32,
+    pub phys: u64,
+    pub size: usize,
+    // Accessed/dirty are left out: they differ from page to page
+    pub flags: PageFlags,
+    // Mapped by large pages rather than page tables
+    pub large: bool,
+}
+
+impl MappedRange {
+    pub fn end(&self) -> u64 {
+        self.virt as u64 + self.size as u64
+    }
+}
+
+// Call `f` for every run of mapped pages in [start, end), lowest first.
+// The reserved slots at the top (foreign window, recursive mapping) are
+// never reported - they are the page tables themselves.
+pub fn for_each_mapping(start: VirtAddr, end: u64, mut f: impl FnMut(&MappedRange)) {
+    let l = layout();
+    let end = end.min(l.foreign_base() as u64);
+    let ignored = PageFlags::ACCESSED | PageFlags::DIRTY;
+    let mut current: Option<MappedRange> = None;
+    let mut push = |page: MappedRange| match current.as_mut() {
+        Some(run)
+            if run.end() == page.virt as u64
+                && run.phys + run.size as u64 == page.phys
+                && run.flags == page.flags
+                && run.large == page.large =>
+        {
+            run.size += page.size;
+        }
+        _ => {
+            if let Some(run) = current.replace(page) {
+                f(&run);
+            }
+        }
+    };
+
+    let mut addr = (start.0 & !0xFFF) as u64;
+    while addr < end {
+        let virt = VirtAddr::new(addr as u32);
+        let pde_idx = virt.pde_index();
+        let next_pde = ((pde_idx as u64) + 1) << l.dir_shift;
+        let pde = unsafe { read_pde(pde_idx) };
+        if !pde.present() {
+            addr = next_pde;
+            continue;
+        }
+        if pde.page_size_4mb() {
+            let offset = addr & (l.large_page_size() as u64 - 1);
+            push(MappedRange {
+                virt: addr as u32,
+                phys: pde.address_wide() + offset,
+                size: (next_pde.min(end) - addr) as usize,
+                flags: pde.flags() & !ignored,
+                large: true,
+            });
+            addr = next_pde;
+            continue;
+        }
+        while addr < next_pde.min(end) {
+            let virt = VirtAddr::new(addr as u32);
+            let pte = unsafe { read_pte_at(Tables::active(), pde_idx, virt.pte_index()) };
+            if pte.present() {
+                push(MappedRange {
+                    virt: addr as u32,
+                    phys: pte.address_wide(),
+                    size: PAGE_SIZE,
+                    flags: pte.flags() & !ignored,
+                    large: false,
+                });
+            }
+            addr += PAGE_SIZE as u64;
+        }
+    }
+    if let Some(run) = current {
+        f(&run);
+    }
+}
+
 // ---------------------------------------------------------------------------
 // Range operations
 // ---------------------------------------------------------------------------
@@ -467,6 +1374,22 @@ pub fn is_mapped(virt: VirtAddr) -> bool {
 // Map a contiguous range of virtual pages, allocating a fresh physical
 // frame for each one.
 pub fn map_range(start: VirtAddr, size: usize, flags: PageFlags) -> Result<usize, MapError> {
+    map_range_at(Tables::active(), start, size, flags, true)
+}
+
+// Like map_range(), but with 4 KB pages only, so that any part of the
+// range can be unmapped again later.
+pub fn map_range_4k(start: VirtAddr, size: usize, flags: PageFlags) -> Result<usize, MapError> {
+    map_range_at(Tables::active(), start, size, flags, false)
+}
+
+fn map_range_at(
+    t: Tables,
+    start: VirtAddr,
+    size: usize,
+    flags: PageFlags,
+    large_pages: bool,
+) -> Result<usize, MapError> {
     assert!(
         start.is_page_aligned(),
         "map_range: start {:#x} not page-aligned",
@@ -478,10 +1401,25 @@ pub fn map_range(start: VirtAddr, size: usize, flags: PageFlags) -> Result<usize
         size
     );
 
+    // Use a large page wherever a whole aligned, empty PDE is covered and
+    // enough contiguous frames can be found; 4 KB pages otherwise.
+    let large = layout().large_page_size();
     let pages = size / PAGE_SIZE;
-    for i in 0..pages {
-        let virt = VirtAddr::new(start.0 + (i * PAGE_SIZE) as u32);
-        map_alloc(virt, flags)?;
+    let mut offset = 0;
+    while offset < size {
+        let virt = VirtAddr::new(start.0 + offset as u32);
+        if large_pages && fits_large_page(t, virt, size - offset) {
+            if let Ok(phys) = alloc_large_frame(flags) {
+                if let Err(e) = map_large_page_at(t, virt, phys, flags) {
+                    free_frames(phys, large / PAGE_SIZE);
+                    return Err(e);
+                }
+                offset += large;
+                continue;
+            }
+        }
+        map_alloc_at(t, virt, flags)?;
+        offset += PAGE_SIZE;
     }
 
     dbg_println!(
@@ -518,10 +1456,17 @@ pub fn map_range_to(
     );
 
     let pages = size / PAGE_SIZE;
-    for i in 0..pages {
-        let virt = VirtAddr::new(virt_start.0 + (i * PAGE_SIZE) as u32);
-        let phys = PhysAddr::new(phys_start.0 + (i * PAGE_SIZE) as u32);
-        map_page(virt, phys, flags)?;
+    let mut offset = 0;
+    while offset < size {
+        let virt = VirtAddr::new(virt_start.0 + offset as u32);
+        let phys = PhysAddr::new(phys_start.0 + offset as u32);
+        if phys.is_large_page_aligned() && fits_large_page(Tables::active(), virt, size - offset) {
+            map_large_page(virt, phys, flags)?;
+            offset += layout().large_page_size();
+        } else {
+            map_page(virt, phys, flags)?;
+            offset += PAGE_SIZE;
+        }
     }
 
     dbg_println!(
@@ -537,8 +1482,22 @@ pub fn map_range_to(
 }
 
 // Unmap a contiguous range and free their physical frames.
-// Already-unmapped pages are silently skipped.
-pub fn unmap_range(start: VirtAddr, size: usize) -> usize {
+// Already-unmapped pages are silently skipped, and page tables left
+// empty are released.  Returns the number of pages freed, or
+// UnmapError::LargePage (with nothing unmapped) if the range covers
+// only part of a large page.
+pub fn unmap_range(start: VirtAddr, size: usize) -> Result<usize, UnmapError> {
+    unmap_range_at(Tables::active(), start, size, true).map(|(pages, _)| pages)
+}
+
+// Returns (pages unmapped, page tables released).  The frames behind the
+// pages are freed only with `release` - never for device memory.
+fn unmap_range_at(
+    t: Tables,
+    start: VirtAddr,
+    size: usize,
+    release: bool,
+) -> Result<(usize, usize), UnmapError> {
     assert!(
         start.is_page_aligned(),
         "unmap_range: start {:#x} not page-aligned",
@@ -551,41 +1510,613 @@ pub fn unmap_range(start: VirtAddr, size: usize) -> usize {
     );
 
     let pages = size / PAGE_SIZE;
+    let large = layout().large_page_size();
+    if size > 0 && splits_large_page(t, start, size) {
+        return Err(UnmapError::LargePage);
+    }
+
     let mut unmapped = 0;
-    for i in 0..pages {
-        let virt = VirtAddr::new(start.0 + (i * PAGE_SIZE) as u32);
-        if let Ok(phys) = unmap_page(virt) {
-            free_frame(phys);
-            unmapped += 1;
+    let mut offset = 0;
+    while offset < size {
+        let virt = VirtAddr::new(start.0 + offset as u32);
+        match unmap_page_at(t, virt) {
+            Ok(phys) => {
+                if release {
+                    free_frame(phys);
+                }
+                unmapped += 1;
+            }
+            // Covered whole: splits_large_page() checked the ends
+            Err(UnmapError::LargePage) => {
+                if let Ok(phys) = unmap_large_page_at(t, virt) {
+                    if release {
+                        free_frames(phys, large / PAGE_SIZE);
+                    }
+                    unmapped += large / PAGE_SIZE;
+                }
+                offset += large;
+                continue;
+            }
+            Err(_) => {}
+        }
+        offset += PAGE_SIZE;
+    }
+
+    // Each table the range touched is checked once, not once per page
+    let mut tables = 0;
+    if size > 0 {
+        let last = VirtAddr::new(start.0 + (size - PAGE_SIZE) as u32);
+        for pde_idx in start.pde_index()..=last.pde_index() {
+            if unsafe { release_table_if_empty(t, pde_idx) } {
+                tables += 1;
+            }
         }
     }
 
     dbg_println!(
-        "VMM: unmapped range {:#x}..{:#x} ({}/{} pages freed)",
+        "VMM: unmapped range {:#x}..{:#x} ({}/{} pages freed, {} page tables)",
         start.0,
         start.0 as usize + size,
         unmapped,
-        pages
+        pages,
+        tables
     );
 
-    unmapped
+    Ok((unmapped, tables))
+}
+
+// Does [start, start + size) cover only part of a large page?  Only the
+// pages at either end can be partly covered.
+fn splits_large_page(t: Tables, start: VirtAddr, size: usize) -> bool {
+    let large = layout().large_page_size();
+    let end = start.0 as usize + size;
+    let last = VirtAddr::new((end - PAGE_SIZE) as u32);
+    [start, last].iter().any(|virt| {
+        let pde = unsafe { read_pde_at(t, virt.pde_index()) };
+        let base = virt.0 as usize & !(large - 1);
+        pde.present() && pde.page_size_4mb() && (base < start.0 as usize || base + large > end)
+    })
+}
+
+// Unlink and free the page table behind PDE[pde_idx] once none of its
+// entries is present.  Returns true if it was released.
+//
+// Kept regardless: the boot tables (they live in the kernel image, not
+// in PageTable frames), the kmap window's table (its PTEs come and go
+// behind our back), and kernel tables seen through the foreign window
+// (the active directory shares them).  A released kernel table is also
+// cleared in the boot directory; other address spaces pick that up in
+// activate() before they can use the stale PDE.
+unsafe fn release_table_if_empty(t: Tables, pde_idx: usize) -> bool {
+    let l = layout();
+    let pde = read_pde_at(t, pde_idx);
+    if !pde.present() || pde.page_size_4mb() || pde_idx >= l.foreign_index {
+        return false;
+    }
+    let kernel = pde_idx >= l.kernel_pde_start;
+    if kernel && !t.active {
+        return false;
+    }
+    if pde_idx == VirtAddr::new(l.kmap_base()).pde_index() {
+        return false;
+    }
+    let frame = PhysFrame::containing_address(pde.address() as usize);
+    let is_table = FRAME_ALLOCATOR
+        .as_ref()
+        .and_then(|a| a.frame_info(frame))
+        .is_some_and(|p| p.owner == FrameOwner::PageTable);
+    if !is_table || (0..l.table_entries).any(|i| read_pte_at(t, pde_idx, i).present()) {
+        return false;
+    }
+
+    write_pde_at(t, pde_idx, PageEntry::empty());
+    if kernel {
+        super::paging().clear_entry(pde_idx);
+    }
+    // Drops the table's recursive view and any cached walk through it
+    if t.active {
+        flush_tlb_all();
+    }
+    free_frame(PhysAddr::new(pde.address()));
+
+    dbg_println!(
+        "VMM: released empty page table {:#x} (PDE[{}])",
+        pde.address(),
+        pde_idx
+    );
+    true
 }
 
 // ---------------------------------------------------------------------------
-// Internal helpers
+// Changing protection
+//
+// protect_range() rewrites the PageFlags::PROTECTION bits (writable,
+// user, write-through, cache-disable, no-execute) of every page in a
+// range.  Frames, PRESENT and the accessed/dirty bits stay as they are.
+// The whole range is checked before anything changes, so an error
+// leaves every entry untouched.
+//
+// Shared frames stay safe: a page whose frame has other users becomes
+// COW instead of writable, and a read-only request drops COW so a
+// write faults for real instead of being resolved by a copy.
 // ---------------------------------------------------------------------------
 
-fn alloc_frame() -> Result<PhysFrame, MapError> {
+// Change the protection of [start, start + size).  Both must be page
+// aligned.  Returns the number of 4 KB pages changed.
+pub fn protect_range(
+    start: VirtAddr,
+    size: usize,
+    flags: PageFlags,
+) -> Result<usize, ProtectError> {
+    let pages = protect_range_at(Tables::active(), start, size, flags)?;
+
+    // Keep vmaps in step for regions covered as a whole
+    let end = start.0 as usize + size;
+    unsafe {
+        for region in VM_REGIONS.iter_mut() {
+            if region.start >= start.0 && region.end() as usize <= end {
+                region.flags = region.flags.with_protection(flags);
+            }
+        }
+    }
+    Ok(pages)
+}
+
+fn protect_range_at(
+    t: Tables,
+    start: VirtAddr,
+    size: usize,
+    flags: PageFlags,
+) -> Result<usize, ProtectError> {
+    assert!(
+        start.is_page_aligned(),
+        "protect_range: {:#x} not aligned",
+        start.0
+    );
+    assert!(
+        size & 0xFFF == 0,
+        "protect_range: size {:#x} not aligned",
+        size
+    );
+    let end = (start.0 as usize)
+        .checked_add(size)
+        .ok_or(ProtectError::InvalidAddress)?;
+    if end > layout().kmap_base() as usize {
+        return Err(ProtectError::InvalidAddress);
+    }
+    let large = layout().large_page_size();
+
+    // Pass 1: everything mapped, large pages covered whole
+    let mut offset = 0;
+    while offset < size {
+        let virt = VirtAddr::new(start.0 + offset as u32);
+        let pde = unsafe { read_pde_at(t, virt.pde_index()) };
+        if !pde.present() {
+            return Err(ProtectError::NotMapped);
+        }
+        if pde.page_size_4mb() {
+            if !virt.is_large_page_aligned() || size - offset < large {
+                return Err(ProtectError::LargePage);
+            }
+            offset += large;
+            continue;
+        }
+        if !unsafe { read_pte_at(t, virt.pde_index(), virt.pte_index()) }.present() {
+            return Err(ProtectError::NotMapped);
+        }
+        offset += PAGE_SIZE;
+    }
+
+    // Pass 2: rewrite the entries
+    let mut changed = 0;
+    let mut promoted = false;
+    offset = 0;
+    while offset < size {
+        let virt = VirtAddr::new(start.0 + offset as u32);
+        let (pde_idx, pte_idx) = (virt.pde_index(), virt.pte_index());
+        unsafe {
+            let mut pde = read_pde_at(t, pde_idx);
+            if flags.is_user() && !pde.user() && !pde.page_size_4mb() {
+                // Same promotion as map_page: the PTE stays the gatekeeper
+                pde = PageEntry::new_wide(pde.address_wide(), pde.flags() | PageFlags::USER);
+                update_pde_at(t, pde_idx, pde);
+                promoted = true;
+            }
+
+            if pde.page_size_4mb() {
+                let entry =
+                    PageEntry::new_wide(pde.address_wide(), pde.flags().with_protection(flags));
+                update_pde_at(t, pde_idx, entry);
+                if t.active {
+                    flush_tlb_entry(virt);
+                }
+                changed += layout().large_page_frames();
+                offset += large;
+                continue;
+            }
+
+            let pte = read_pte_at(t, pde_idx, pte_idx);
+            let mut new = pte.flags().with_protection(flags);
+            if new.is_writable() && (pte.flags().is_cow() || frame_shared(pte.address())) {
+                new = (new & !PageFlags::WRITABLE) | PageFlags::COW;
+            } else {
+                new = new & !PageFlags::COW;
+            }
+            write_pte_at(
+                t,
+                pde_idx,
+                pte_idx,
+                PageEntry::new_wide(pte.address_wide(), new),
+            );
+            if t.active {
+                flush_tlb_entry(virt);
+            }
+        }
+        changed += 1;
+        offset += PAGE_SIZE;
+    }
+    if promoted && t.active {
+        flush_tlb_all();
+    }
+
+    dbg_println!(
+        "VMM: protected range {:#x}..{:#x} ({} pages, flags {})",
+        start.0,
+        end,
+        changed,
+        flags
+    );
+
+    Ok(changed)
+}
+
+// Does the frame at `phys` have more than one user?
+fn frame_shared(phys: u32) -> bool {
     unsafe {
         FRAME_ALLOCATOR
+            .as_ref()
+            .and_then(|a| a.frame_info(PhysFrame::containing_address(phys as usize)))
+            .is_some_and(|p| p.refcount > 1)
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Variants for a non-active address space
+//
+// Same semantics as the functions above, but they operate on the page
+// directory of `space` whether or not it is the one loaded in CR3.
+// ---------------------------------------------------------------------------
+
+pub fn map_page_in(
+    space: &AddressSpace,
+    virt: VirtAddr,
+    phys: PhysAddr,
+    flags: PageFlags,
+) -> Result<(), MapError> {
+    with_tables(space.pd_phys(), |t| map_page_at(t, virt, phys, flags))
+}
+
+pub fn map_alloc_in(
+    space: &AddressSpace,
+    virt: VirtAddr,
+    flags: PageFlags,
+) -> Result<PhysAddr, MapError> {
+    with_tables(space.pd_phys(), |t| map_alloc_at(t, virt, flags))
+}
+
+pub fn map_range_in(
+    space: &AddressSpace,
+    start: VirtAddr,
+    size: usize,
+    flags: PageFlags,
+) -> Result<usize, MapError> {
+    with_tables(space.pd_phys(), |t| {
+        map_range_at(t, start, size, flags, true)
+    })
+}
+
+pub fn unmap_page_in(space: &AddressSpace, virt: VirtAddr) -> Result<PhysAddr, UnmapError> {
+    with_tables(space.pd_phys(), |t| {
+        let phys = unmap_page_at(t, virt)?;
+        unsafe { release_table_if_empty(t, virt.pde_index()) };
+        Ok(phys)
+    })
+}
+
+pub fn unmap_range_in(
+    space: &AddressSpace,
+    start: VirtAddr,
+    size: usize,
+) -> Result<usize, UnmapError> {
+    with_tables(space.pd_phys(), |t| {
+        unmap_range_at(t, start, size, true).map(|(pages, _)| pages)
+    })
+}
+
+pub fn protect_range_in(
+    space: &AddressSpace,
+    start: VirtAddr,
+    size: usize,
+    flags: PageFlags,
+) -> Result<usize, ProtectError> {
+    with_tables(space.pd_phys(), |t| protect_range_at(t, start, size, flags))
+}
+
+pub fn translate_in(space: &AddressSpace, virt: VirtAddr) -> Option<PhysAddr> {
+    with_tables(space.pd_phys(), |t| translate_at(t, virt))
+}
+
+// ---------------------------------------------------------------------------
+// Copy-on-write
+//
+// cow_clone_range() shares every mapped page of a user range between the
+// active address space and `dst`.  Writable pages are downgraded to
+// read-only + COW on both sides and their frame gains a reference.  The
+// first write from either side faults (present + write) and
+// handle_cow_fault() gives the writer a private copy - or, if it is the
+// last user of the frame, simply makes the page writable again.
+// ---------------------------------------------------------------------------
+
+// Share the mapped pages of [start, start + size) with `dst`.
+// Returns the number of pages shared.  On error neither address space
+// nor any frame refcount is left changed.
+pub fn cow_clone_range(
+    dst: &AddressSpace,
+    start: VirtAddr,
+    size: usize,
+) -> Result<usize, MapError> {
+    assert!(
+        start.is_page_aligned() && size & 0xFFF == 0,
+        "cow_clone_range: range {:#x}+{:#x} not page-aligned",
+        start.0,
+        size
+    );
+    assert!(
+        !dst.is_active(),
+        "cow_clone_range: destination is the active address space"
+    );
+    // Kernel page tables are shared already - COW only makes sense for
+    // the private user half.
+    if (start.0 as usize).saturating_add(size) > KERNEL_OFFSET {
+        return Err(MapError::InvalidAddress);
+    }
+
+    let pages = size / PAGE_SIZE;
+    let page_at = |i: usize| VirtAddr::new(start.0 + (i * PAGE_SIZE) as u32);
+
+    // Pass 1: map every page into `dst` and take a reference on its
+    // frame.  The source is not touched yet, so a failure only has to
+    // undo what `dst` gained.
+    let shared = with_tables(dst.pd_phys(), |t| {
+        let mut shared = 0;
+        for i in 0..pages {
+            let virt = page_at(i);
+            let cloned = match unsafe { cow_source_pte(virt) } {
+                Ok(Some(pte)) => cow_share_page(t, virt, pte),
+                Ok(None) => continue,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = cloned {
+                for j in 0..i {
+                    cow_unshare_page(t, page_at(j));
+                }
+                return Err(e);
+            }
+            shared += 1;
+        }
+        Ok(shared)
+    })?;
+
+    // Pass 2: cannot fail - downgrade the writable source pages
+    for i in 0..pages {
+        let virt = page_at(i);
+        if let Ok(Some(pte)) = unsafe { cow_source_pte(virt) } {
+            if pte.flags().is_writable() {
+                unsafe {
+                    write_pte_at(
+                        Tables::active(),
+                        virt.pde_index(),
+                        virt.pte_index(),
+                        PageEntry::new(pte.address(), cow_flags(pte.flags())),
+                    );
+                }
+                flush_tlb_entry(virt);
+            }
+        }
+    }
+
+    dbg_println!(
+        "VMM: COW-shared {} page(s) of {:#x}..{:#x} with {:#x}",
+        shared,
+        start.0,
+        start.0 as usize + size,
+        dst.pd_phys()
+    );
+
+    Ok(shared)
+}
+
+// PTE of `virt` in the active address space, if a 4 KB page is mapped
+// there.  Large pages can't be shared page by page.
+unsafe fn cow_source_pte(virt: VirtAddr) -> Result<Option<PageEntry>, MapError> {
+    let pde = read_pde(virt.pde_index());
+    if !pde.present() {
+        return Ok(None);
+    }
+    if pde.page_size_4mb() {
+        return Err(MapError::LargePage);
+    }
+    let pte = read_pte_at(Tables::active(), virt.pde_index(), virt.pte_index());
+    Ok(pte.present().then_some(pte))
+}
+
+// Flags of a COW-shared page: writable pages become read-only + COW.
+fn cow_flags(flags: PageFlags) -> PageFlags {
+    if flags.is_writable() {
+        (flags & !PageFlags::WRITABLE) | PageFlags::COW
+    } else {
+        flags
+    }
+}
+
+// Map the frame behind source entry `pte` at `virt` in `t`, holding a
+// reference for the new mapping.
+fn cow_share_page(t: Tables, virt: VirtAddr, pte: PageEntry) -> Result<(), MapError> {
+    let frame = PhysFrame::containing_address(pte.address() as usize);
+    unsafe {
+        if let Some(alloc) = FRAME_ALLOCATOR.as_mut() {
+            alloc
+                .get_frame(frame)
+                .map_err(|_| MapError::FrameAllocationFailed)?;
+        }
+    }
+    map_page_at(
+        t,
+        virt,
+        PhysAddr::new(pte.address()),
+        cow_flags(pte.flags()),
+    )
+    .inspect_err(|_| {
+        put_frame(frame);
+    })
+}
+
+// Undo cow_share_page() for `virt`, if the source maps it.
+fn cow_unshare_page(t: Tables, virt: VirtAddr) {
+    if !matches!(unsafe { cow_source_pte(virt) }, Ok(Some(_))) {
+        return;
+    }
+    if let Ok(phys) = unmap_page_at(t, virt) {
+        put_frame(PhysFrame::containing_address(phys.0 as usize));
+    }
+    unsafe { release_table_if_empty(t, virt.pde_index()) };
+}
+
+fn put_frame(frame: PhysFrame) {
+    unsafe {
+        if let Some(alloc) = FRAME_ALLOCATOR.as_mut() {
+            let _ = alloc.put_frame(frame);
+        }
+    }
+}
+
+// Resolve a write fault on a COW page of the active address space.
+//
+// Returns true if the page is now writable and the instruction can be
+// retried, false if this was not a COW fault.
+pub fn handle_cow_fault(addr: u32, error_code: u32) -> bool {
+    // COW faults are protection violations (bit 0) on a write (bit 1)
+    if error_code & 0b11 != 0b11 {
+        return false;
+    }
+
+    let virt = VirtAddr::new(addr & !0xFFF);
+    let (pde_idx, pte_idx) = (virt.pde_index(), virt.pte_index());
+
+    unsafe {
+        let pde = read_pde(pde_idx);
+        if !pde.present() || pde.page_size_4mb() {
+            return false;
+        }
+        let pte = read_pte_at(Tables::active(), pde_idx, pte_idx);
+        if !pte.present() || !pte.flags().is_cow() {
+            return false;
+        }
+
+        let old = PhysFrame::containing_address(pte.address() as usize);
+        let flags = (pte.flags() & !PageFlags::COW) | PageFlags::WRITABLE;
+        let refcount = FRAME_ALLOCATOR
+            .as_ref()
+            .and_then(|a| a.frame_info(old))
+            .map_or(1, |p| p.refcount);
+
+        if refcount <= 1 {
+            // Last user: no copy needed
+            write_pte_at(
+                Tables::active(),
+                pde_idx,
+                pte_idx,
+                PageEntry::new(pte.address(), flags),
+            );
+            flush_tlb_entry(virt);
+            return true;
+        }
+
+        let owner = if flags.is_user() {
+            FrameOwner::User
+        } else {
+            FrameOwner::Kernel
+        };
+        let frame = match alloc_frame(owner) {
+            Ok(frame) => frame,
+            Err(_) => return false,
+        };
+        let new = PhysAddr::new(frame.start_address() as u32);
+        match kmap(frame) {
+            Ok(copy) => {
+                core::ptr::copy_nonoverlapping(virt.0 as *const u8, copy.as_ptr(), PAGE_SIZE)
+            }
+            Err(_) => {
+                free_frame(new);
+                return false;
+            }
+        }
+
+        write_pte_at(
+            Tables::active(),
+            pde_idx,
+            pte_idx,
+            PageEntry::new(new.0, flags),
+        );
+        flush_tlb_entry(virt);
+        free_frame(PhysAddr::new(pte.address()));
+
+        dbg_println!(
+            "VMM: COW copy of {:#x} (frame {:#x} -> {:#x})",
+            virt.0,
+            pte.address(),
+            new.0
+        );
+        true
+    }
+}
+
+// Tag the frames behind every mapped page of a range with `owner`.
+// Used by subsystems that map through map_range() but want their frames
+// attributed to them (e.g. the heap).
+pub fn set_range_owner(start: VirtAddr, size: usize, owner: FrameOwner) {
+    let pages = size / PAGE_SIZE;
+    for i in 0..pages {
+        let virt = VirtAddr::new(start.0 + (i * PAGE_SIZE) as u32);
+        if let Some(phys) = translate(virt) {
+            unsafe {
+                if let Some(alloc) = FRAME_ALLOCATOR.as_mut() {
+                    let frame = PhysFrame::containing_address(phys.0 as usize);
+                    let _ = alloc.set_owner(frame, owner);
+                }
+            }
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Internal helpers
+// ---------------------------------------------------------------------------
+
+pub(crate) fn alloc_frame(owner: FrameOwner) -> Result<PhysFrame, MapError> {
+    unsafe {
+        let allocator = FRAME_ALLOCATOR
             .as_mut()
-            .ok_or(MapError::FrameAllocationFailed)?
+            .ok_or(MapError::FrameAllocationFailed)?;
+        let frame = allocator
             .allocate_frame()
-            .map_err(|_| MapError::FrameAllocationFailed)
+            .map_err(|_| MapError::FrameAllocationFailed)?;
+        let _ = allocator.set_owner(frame, owner);
+        Ok(frame)
     }
 }
 
-fn free_frame(phys: PhysAddr) {
+pub(crate) fn free_frame(phys: PhysAddr) {
     unsafe {
         if let Some(alloc) = FRAME_ALLOCATOR.as_mut() {
             let frame = PhysFrame::containing_address(phys.0 as usize);
@@ -594,6 +2125,39 @@ fn free_frame(phys: PhysAddr) {
     }
 }
 
+// Allocate the contiguous, naturally aligned frames behind a large page.
+fn alloc_large_frame(flags: PageFlags) -> Result<PhysAddr, MapError> {
+    let owner = if flags.is_user() {
+        FrameOwner::User
+    } else {
+        FrameOwner::Kernel
+    };
+    let frames = layout().large_page_frames();
+    unsafe {
+        let allocator = FRAME_ALLOCATOR
+            .as_mut()
+            .ok_or(MapError::FrameAllocationFailed)?;
+        let first = allocator
+            .allocate_frames(frames, frames)
+            .map_err(|_| MapError::FrameAllocationFailed)?;
+        for n in 0..frames {
+            let _ = allocator.set_owner(
+                PhysFrame::containing_address(first.start_address() + n * PAGE_SIZE),
+                owner,
+            );
+        }
+        Ok(PhysAddr::new(first.start_address() as u32))
+    }
+}
+
+// Release `count` frames starting at `phys` one by one, so shared or
+// pinned frames keep their own reference counting.
+pub(crate) fn free_frames(phys: PhysAddr, count: usize) {
+    for n in 0..count {
+        free_frame(PhysAddr::new(phys.0 + (n * PAGE_SIZE) as u32));
+    }
+}
+
 // ---------------------------------------------------------------------------
 // Self-test suite
 // ---------------------------------------------------------------------------
@@ -607,23 +2171,49 @@ pub fn test_virtual_memory() {
     test_multi_page();
     test_already_mapped_error();
     test_map_range();
+    test_copy_on_write();
+    test_large_pages();
+    test_entry_format();
+    test_protect_range();
+    test_table_reclaim();
+    test_cow_rollback();
     m_println!("\n=== VMM Self-Test PASSED ===\n");
 }
 
+// `pages` unused pages of the vmalloc window, so a test never lands on
+// a live vmalloc region or its guard pages.
+fn test_range(pages: usize) -> VirtAddr {
+    VirtAddr::new(find_free_range(pages).expect("VMM test: no free virtual range"))
+}
+
+// A whole unused large-page span of the vmalloc window, with two more
+// unused pages after it.
+fn test_large_span() -> VirtAddr {
+    let large = layout().large_page_size() as u32;
+    let start = test_range(2 * large as usize / PAGE_SIZE + 2);
+    VirtAddr::new((start.0 + large - 1) & !(large - 1))
+}
+
 fn test_recursive_mapping_reads() {
     m_print!("[VMM test 1] Recursive mapping reads ... ");
     unsafe {
-        let pde_768 = read_pde(768);
+        let pde_768 = read_pde(layout().kernel_pde_start);
         assert!(pde_768.present(), "PDE[768] should be present");
 
-        let pde_rec = read_pde(RECURSIVE_INDEX);
+        let pde_rec = read_pde(layout().recursive_index);
         assert!(pde_rec.present(), "PDE[1023] (recursive) should be present");
 
+        // Under PAE the first recursive slot points to directory 0
         let pd = super::PAGING.as_mut().unwrap();
         let pd_phys = pd.physical_address();
+        let first_dir = if paging::pae_enabled() {
+            pdpt_dirs(pd_phys)[0]
+        } else {
+            pd_phys
+        };
         assert_eq!(
             pde_rec.address(),
-            pd_phys,
+            first_dir,
             "Recursive PDE should point to page directory"
         );
     }
@@ -633,7 +2223,7 @@ fn test_recursive_mapping_reads() {
 fn test_map_write_read_unmap() {
     m_print!("[VMM test 2] Map -> write -> read -> unmap ... ");
 
-    let test_virt = VirtAddr::new(0xD000_0000);
+    let test_virt = test_range(1);
     let flags = PageFlags::PRESENT | PageFlags::WRITABLE;
 
     let phys = map_alloc(test_virt, flags).expect("map_alloc failed");
@@ -663,7 +2253,7 @@ fn test_map_write_read_unmap() {
 fn test_translate_accuracy() {
     m_print!("[VMM test 3] translate() accuracy ... ");
 
-    let test_virt = VirtAddr::new(0xD000_1000);
+    let test_virt = test_range(1);
     let flags = PageFlags::PRESENT | PageFlags::WRITABLE;
 
     let phys = map_alloc(test_virt, flags).expect("map_alloc failed");
@@ -675,7 +2265,7 @@ fn test_translate_accuracy() {
         translated.0, phys.0
     );
 
-    let with_offset = translate(VirtAddr::new(0xD000_1ABC)).expect("translate+offset None");
+    let with_offset = translate(VirtAddr::new(test_virt.0 + 0xABC)).expect("translate+offset None");
     assert_eq!(
         with_offset.0,
         phys.0 | 0xABC,
@@ -691,7 +2281,7 @@ fn test_translate_accuracy() {
 fn test_multi_page() {
     m_print!("[VMM test 4] Multi-page mapping ... ");
 
-    let base: u32 = 0xD010_0000;
+    let base: u32 = test_range(4).0;
     let flags = PageFlags::PRESENT | PageFlags::WRITABLE;
     let count: u32 = 4;
     let mut phys_addrs: [PhysAddr; 4] = [PhysAddr(0); 4];
@@ -730,7 +2320,7 @@ fn test_multi_page() {
 fn test_already_mapped_error() {
     m_print!("[VMM test 5] AlreadyMapped error ... ");
 
-    let test_virt = VirtAddr::new(0xD020_0000);
+    let test_virt = test_range(1);
     let flags = PageFlags::PRESENT | PageFlags::WRITABLE;
 
     let phys = map_alloc(test_virt, flags).expect("first map failed");
@@ -751,7 +2341,7 @@ fn test_already_mapped_error() {
 fn test_map_range() {
     m_print!("[VMM test 6] map_range / unmap_range ... ");
 
-    let base = VirtAddr::new(0xD200_0000);
+    let base = test_range(8);
     let size = PAGE_SIZE * 8;
     let flags = PageFlags::PRESENT | PageFlags::WRITABLE;
 
@@ -783,7 +2373,7 @@ fn test_map_range() {
     }
 
     let freed = unmap_range(base, size);
-    assert_eq!(freed, 8, "Should have unmapped 8 pages");
+    assert_eq!(freed, Ok(8), "Should have unmapped 8 pages");
 
     for i in 0..8u32 {
         let virt = VirtAddr::new(base.0 + i * PAGE_SIZE as u32);
@@ -796,3 +2386,320 @@ fn test_map_range() {
 
     m_println!("OK");
 }
+
+fn test_copy_on_write() {
+    m_print!("[VMM test 7] Copy-on-write isolation ... ");
+
+    use super::addrspace::activate_kernel;
+
+    let virt = VirtAddr::new(0x4000_0000);
+    let flags = PageFlags::PRESENT | PageFlags::WRITABLE | PageFlags::USER;
+    let ptr = virt.0 as *mut u32;
+
+    let phys = map_alloc(virt, flags).expect("map_alloc failed");
+    unsafe { ptr.write_volatile(0x1111_1111) };
+
+    let child = AddressSpace::new().expect("AddressSpace::new failed");
+    let shared = cow_clone_range(&child, virt, PAGE_SIZE).expect("cow_clone_range failed");
+    assert_eq!(shared, 1);
+    assert_eq!(translate_in(&child, virt), Some(phys), "frame not shared");
+
+    // Parent writes: gets a private copy, child keeps the original
+    unsafe {
+        ptr.write_volatile(0x2222_2222);
+        assert_eq!(ptr.read_volatile(), 0x2222_2222);
+    }
+    assert_ne!(translate(virt), Some(phys), "parent should own a copy now");
+
+    // Child reads the original, then writes as the last user (no copy)
+    child.activate();
+    unsafe {
+        assert_eq!(ptr.read_volatile(), 0x1111_1111, "parent write leaked");
+        ptr.write_volatile(0x3333_3333);
+        assert_eq!(ptr.read_volatile(), 0x3333_3333);
+    }
+    assert_eq!(
+        translate(virt),
+        Some(phys),
+        "last user should keep its frame"
+    );
+    activate_kernel();
+
+    unsafe {
+        assert_eq!(ptr.read_volatile(), 0x2222_2222, "child write leaked");
+    }
+
+    let _ = unmap_range(virt, PAGE_SIZE);
+    child.destroy();
+
+    m_println!("OK");
+}
+
+fn test_large_pages() {
+    m_print!("[VMM test 8] Large pages ... ");
+    if !pse_enabled() {
+        m_println!("skipped (no PSE)");
+        return;
+    }
+
+    // One aligned large page plus two trailing 4 KB pages
+    let large = layout().large_page_size();
+    let frames = layout().large_page_frames();
+    let base = test_large_span();
+    let size = large + 2 * PAGE_SIZE;
+    let flags = PageFlags::PRESENT | PageFlags::WRITABLE;
+    let tail = VirtAddr::new(base.0 + large as u32);
+    let last = VirtAddr::new(tail.0 - 4);
+
+    let mapped = map_range(base, size, flags).expect("map_range failed");
+    assert_eq!(mapped, frames + 2);
+
+    // Without enough free contiguous frames map_range falls back to 4 KB
+    // pages; everything below must hold either way.
+    if unsafe { read_pde(base.pde_index()).page_size_4mb() } {
+        let phys = translate(base).expect("large page not translated");
+        assert_eq!(
+            translate(last),
+            Some(PhysAddr::new(phys.0 + large as u32 - 4)),
+            "large page not physically contiguous"
+        );
+        assert!(matches!(
+            unmap_page(VirtAddr::new(base.0 + PAGE_SIZE as u32)),
+            Err(UnmapError::LargePage)
+        ));
+        assert!(matches!(
+            map_page(base, phys, flags),
+            Err(MapError::AlreadyMapped)
+        ));
+
+        // A range ending inside the large page leaves it alone
+        assert_eq!(unmap_range(base, PAGE_SIZE), Err(UnmapError::LargePage));
+        assert_eq!(translate(base), Some(phys));
+    }
+
+    unsafe {
+        (base.0 as *mut u32).write_volatile(0x4D4D_0001);
+        (last.0 as *mut u32).write_volatile(0x4D4D_0002);
+        (tail.0 as *mut u32).write_volatile(0x4D4D_0003);
+        assert_eq!((base.0 as *const u32).read_volatile(), 0x4D4D_0001);
+        assert_eq!((last.0 as *const u32).read_volatile(), 0x4D4D_0002);
+        assert_eq!((tail.0 as *const u32).read_volatile(), 0x4D4D_0003);
+    }
+
+    assert_eq!(unmap_range(base, size), Ok(frames + 2));
+    assert!(!is_mapped(base) && !is_mapped(last) && !is_mapped(tail));
+
+    m_println!("OK");
+}
+
+fn test_entry_format() {
+    m_print!("[VMM test 9] Entry format (PAE / NX) ... ");
+
+    // PageEntry carries 64-bit values whatever the mode
+    let wide = PageEntry::new_wide(0x1_2345_6000, PageFlags::PRESENT | PageFlags::NO_EXECUTE);
+    assert_eq!(wide.address_wide(), 0x1_2345_6000);
+    assert!(wide.no_execute());
+
+    // What reaches the hardware depends on it: NX only sticks with NXE
+    let virt = test_range(1);
+    let flags = PageFlags::PRESENT | PageFlags::WRITABLE | PageFlags::NO_EXECUTE;
+    let phys = map_alloc(virt, flags).expect("map_alloc failed");
+    let pte = unsafe { read_pte_at(Tables::active(), virt.pde_index(), virt.pte_index()) };
+    assert_eq!(pte.address(), phys.0);
+    assert_eq!(
+        pte.no_execute(),
+        paging::nx_enabled(),
+        "NX bit stored in the wrong mode"
+    );
+    unsafe {
+        (virt.0 as *mut u32).write_volatile(0x0DA7_A000);
+        assert_eq!((virt.0 as *const u32).read_volatile(), 0x0DA7_A000);
+    }
+    let _ = unmap_range(virt, PAGE_SIZE);
+
+    m_println!("OK");
+}
+
+fn test_protect_range() {
+    m_print!("[VMM test 10] protect_range ... ");
+
+    let base = test_range(2);
+    let second = VirtAddr::new(base.0 + PAGE_SIZE as u32);
+    let rw = PageFlags::PRESENT | PageFlags::WRITABLE | PageFlags::NO_EXECUTE;
+    let flags_of = |virt: VirtAddr| unsafe {
+        read_pte_at(Tables::active(), virt.pde_index(), virt.pte_index()).flags()
+    };
+
+    map_range(base, 2 * PAGE_SIZE, rw).expect("map_range failed");
+    let phys = translate(base).expect("translate failed");
+    unsafe { (base.0 as *mut u32).write_volatile(0x0000_F00D) };
+
+    // Read-only + cache-disable: same frame, same data
+    let ro_uc = PageFlags::CACHE_DISABLE | PageFlags::NO_EXECUTE;
+    assert_eq!(protect_range(base, 2 * PAGE_SIZE, ro_uc).unwrap(), 2);
+    let flags = flags_of(base);
+    assert!(flags.is_present() && !flags.is_writable() && flags.is_cache_disabled());
+    assert_eq!(translate(base), Some(phys));
+    assert_eq!(
+        unsafe { (base.0 as *const u32).read_volatile() },
+        0x0000_F00D
+    );
+
+    // A shared frame turns COW instead of writable
+    let frame = PhysFrame::containing_address(phys.0 as usize);
+    unsafe { FRAME_ALLOCATOR.as_mut().unwrap().get_frame(frame).unwrap() };
+    protect_range(base, PAGE_SIZE, rw).unwrap();
+    assert!(flags_of(base).is_cow() && !flags_of(base).is_writable());
+    unsafe { FRAME_ALLOCATOR.as_mut().unwrap().put_frame(frame).unwrap() };
+    protect_range(base, PAGE_SIZE, rw).unwrap();
+    assert!(!flags_of(base).is_cow() && flags_of(base).is_writable());
+
+    // USER on a kernel-half range reaches the boot directory as well
+    protect_range(base, PAGE_SIZE, rw | PageFlags::USER).unwrap();
+    assert!(super::paging().entry(base.pde_index()).user());
+    protect_range(base, PAGE_SIZE, rw).unwrap();
+
+    // A hole anywhere in the range rejects it before anything changes
+    free_frame(unmap_page(second).expect("unmap second failed"));
+    assert!(matches!(
+        protect_range(base, 2 * PAGE_SIZE, PageFlags::NONE),
+        Err(ProtectError::NotMapped)
+    ));
+    assert!(flags_of(base).is_writable());
+
+    let _ = unmap_range(base, 2 * PAGE_SIZE);
+    m_println!("OK");
+}
+
+fn test_table_reclaim() {
+    m_print!("[VMM test 11] Empty page tables are released ... ");
+
+    // One large-page span nothing else uses
+    let base = test_large_span();
+    let free_frames = || unsafe { FRAME_ALLOCATOR.as_ref().unwrap().free_frames() };
+    assert!(!unsafe { read_pde(base.pde_index()) }.present());
+    let before = free_frames();
+
+    let flags = PageFlags::PRESENT | PageFlags::WRITABLE;
+    map_range(base, 3 * PAGE_SIZE, flags).expect("map_range failed");
+    assert_eq!(free_frames(), before - 4, "3 pages + 1 page table");
+
+    // Partial unmap keeps the table, the last page takes it along
+    assert_eq!(
+        unmap_range_at(Tables::active(), base, PAGE_SIZE, true),
+        Ok((1, 0))
+    );
+    assert!(unsafe { read_pde(base.pde_index()) }.present());
+    assert_eq!(
+        unmap_range_at(Tables::active(), base, 3 * PAGE_SIZE, true),
+        Ok((2, 1))
+    );
+    assert!(!unsafe { read_pde(base.pde_index()) }.present());
+    assert!(!super::paging().entry(base.pde_index()).present());
+    assert_eq!(free_frames(), before);
+
+    // Same for a single unmap_page
+    map_alloc(base, flags).expect("map_alloc failed");
+    free_frame(unmap_page(base).expect("unmap_page failed"));
+    assert!(!unsafe { read_pde(base.pde_index()) }.present());
+    assert_eq!(free_frames(), before);
+
+    m_println!("OK");
+}
+
+fn test_cow_rollback() {
+    m_print!("[VMM test 12] cow_clone_range rolls back on failure ... ");
+
+    let base = VirtAddr::new(0x4000_0000);
+    let flags = PageFlags::PRESENT | PageFlags::WRITABLE | PageFlags::USER;
+    let refs = |phys: PhysAddr| unsafe {
+        FRAME_ALLOCATOR
+            .as_ref()
+            .and_then(|a| a.frame_info(PhysFrame::containing_address(phys.0 as usize)))
+            .map_or(0, |p| p.refcount)
+    };
+
+    map_range(base, 3 * PAGE_SIZE, flags).expect("map_range failed");
+    let phys: [PhysAddr; 3] = core::array::from_fn(|i| {
+        translate(VirtAddr::new(base.0 + (i * PAGE_SIZE) as u32)).expect("page not mapped")
+    });
+
+    // The child already maps the last page: the clone fails there
+    let child = AddressSpace::new().expect("AddressSpace::new failed");
+    let last = VirtAddr::new(base.0 + 2 * PAGE_SIZE as u32);
+    map_alloc_in(&child, last, flags).expect("map_alloc_in failed");
+    assert!(matches!(
+        cow_clone_range(&child, base, 3 * PAGE_SIZE),
+        Err(MapError::AlreadyMapped)
+    ));
+
+    for (i, &frame) in phys.iter().enumerate() {
+        let virt = VirtAddr::new(base.0 + (i * PAGE_SIZE) as u32);
+        let now = page_flags(virt).expect("source page unmapped");
+        assert!(
+            now.is_writable() && !now.contains(PageFlags::COW),
+            "source page {} left COW",
+            i
+        );
+        assert_eq!(refs(frame), 1, "reference on page {} leaked", i);
+    }
+    assert_eq!(
+        translate_in(&child, base),
+        None,
+        "partial clone left behind"
+    );
+
+    let _ = unmap_range(base, 3 * PAGE_SIZE);
+    child.destroy();
+
+    m_println!("OK");
+}
+
+// vmalloc keeps its own bookkeeping on the kernel heap, so this runs
+// separately, after heap::init().
+pub fn test_vmalloc() {
+    m_print!("[VMM test] vmalloc placement and guard pages ... ");
+
+    let flags = PageFlags::PRESENT | PageFlags::WRITABLE;
+    let a = vmalloc(3 * PAGE_SIZE, flags, "test-a").expect("vmalloc a failed");
+    let b = vmalloc(PAGE_SIZE, flags, "test-b").expect("vmalloc b failed");
+
+    for base in [a, b] {
+        assert!(base as usize >= VMALLOC_START && (base as usize) < VMALLOC_END);
+        assert_eq!(base % PAGE_SIZE as u32, 0, "vmalloc base not aligned");
+    }
+    let a_end = a + 3 * PAGE_SIZE as u32;
+    assert!(b >= a_end + GUARD_SIZE || b + (PAGE_SIZE as u32) + GUARD_SIZE <= a);
+
+    // Guards on both sides stay unmapped and are attributed correctly
+    assert!(
+        !is_mapped(VirtAddr::new(a - GUARD_SIZE)),
+        "guard below mapped"
+    );
+    assert!(!is_mapped(VirtAddr::new(a_end)), "guard above mapped");
+    let (hit, side) = guard_hit(a_end + 0x10).expect("overrun not detected");
+    assert_eq!(
+        (hit.start, hit.owner, side),
+        (a, "test-a", GuardSide::Above)
+    );
+    let (hit, side) = guard_hit(a - 4).expect("underrun not detected");
+    assert_eq!((hit.start, side), (a, GuardSide::Below));
+    assert!(guard_hit(a + 0x10).is_none(), "mapped page is not a guard");
+
+    unsafe {
+        let last = (a_end - 4) as *mut u32;
+        last.write_volatile(0xA5A5_A5A5);
+        assert_eq!(last.read_volatile(), 0xA5A5_A5A5);
+    }
+
+    // A freed range is reused by the next allocation that fits
+    assert_eq!(vfree(a, 3 * PAGE_SIZE).expect("vfree a failed").0, 3);
+    let c = vmalloc(2 * PAGE_SIZE, flags, "test-c").expect("vmalloc c failed");
+    assert_eq!(c, a, "first fit should reuse the freed gap");
+
+    vfree(b, PAGE_SIZE).expect("vfree b failed");
+    vfree(c, 2 * PAGE_SIZE).expect("vfree c failed");
+    assert!(find_region(c).is_none() && find_region(b).is_none());
+
+    m_println!("OK");
+}
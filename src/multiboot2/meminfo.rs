@@ -132,6 +132,17 @@ pub struct MemoryInfoEntry {
     reserved: u32,
 }
 
+impl MemoryInfoEntry {
+    pub const fn new(base_addr: u64, length: u64, typee: u32) -> Self {
+        Self {
+            base_addr,
+            length,
+            typee,
+            reserved: 0,
+        }
+    }
+}
+
 impl Default for MemoryInfoEntry {
     fn default() -> Self {
         Self {
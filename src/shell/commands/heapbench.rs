@@ -0,0 +1,148 @@
+// shell/commands/heapbench.rs
+//
+// Shell command: heapbench [count]
+//
+// Measures kmalloc/kfree throughput on a fragmented heap, once with a
+// first-fit walk over every free block and once with the size-class
+// bins, and prints the average cost of each call in CPU cycles (rdtsc).
+//
+// The first-fit run emulates the search cost of the old heap only: the
+// blocks are still filed in bins, not in the old address-ordered list,
+// so it says nothing about how the old heap fragmented (see
+// heap::set_first_fit()).
+//
+// Workload, identical for both runs (fixed seed):
+//   1. allocate `count` small blocks (16..64 bytes) and free every
+//      other one, leaving count/2 holes that can't coalesce
+//   2. timed: allocate `count` blocks of 64..512 bytes, mostly too big
+//      for the holes
+//   3. timed: free them again
+//   4. free the remaining small blocks
+// kfree() is O(1) in both modes, so only the alloc column shows the
+// difference between the two searches.
+//
+// Examples:
+//   heapbench
+//   heapbench 2000
+
+use super::parse::parse_usize;
+use crate::memory::heap;
+use crate::utils::rdtsc;
+
+const DEFAULT_COUNT: usize = 1000;
+const MAX_COUNT: usize = 2048;
+const SEED: u32 = 0x4EA9_BE7C;
+
+// Pointer tables live outside the heap so the benchmark doesn't
+// allocate behind its own back.
+static mut HOLES: [*mut u8; MAX_COUNT] = [core::ptr::null_mut(); MAX_COUNT];
+static mut BLOCKS: [*mut u8; MAX_COUNT] = [core::ptr::null_mut(); MAX_COUNT];
+
+// Numerical Recipes LCG, as in snake.rs
+struct Rng {
+    state: u32,
+}
+
+impl Rng {
+    fn next(&mut self) -> u32 {
+        self.state = self.state.wrapping_mul(1664525).wrapping_add(1013904223);
+        self.state
+    }
+
+    // Random usize in [lo, hi] inclusive.
+    fn range(&mut self, lo: usize, hi: usize) -> usize {
+        lo + (self.next() as usize % (hi - lo + 1))
+    }
+}
+
+struct Timing {
+    alloc_cycles: u64,
+    free_cycles: u64,
+    holes: usize,
+}
+
+pub fn run(args: &[&str]) {
+    let count = match args {
+        [] => DEFAULT_COUNT,
+        [n] => match parse_usize(n) {
+            Some(n) if (2..=MAX_COUNT).contains(&n) => n,
+            _ => {
+                println!("\nheapbench: count must be 2..{}", MAX_COUNT);
+                return;
+            }
+        },
+        _ => {
+            println!("\nUsage: heapbench [count]");
+            return;
+        }
+    };
+
+    let Some(first_fit) = measure(count, true) else {
+        return;
+    };
+    let Some(binned) = measure(count, false) else {
+        return;
+    };
+
+    println!(
+        "\nheapbench: {} allocs over {} free holes",
+        count, binned.holes
+    );
+    for (name, t) in [("first-fit", &first_fit), ("size bins", &binned)] {
+        println!(
+            "  {:<10}  alloc {:>6} cycles/op   free {:>6} cycles/op",
+            name,
+            t.alloc_cycles / count as u64,
+            t.free_cycles / count as u64
+        );
+    }
+    let speedup = first_fit.alloc_cycles * 10 / binned.alloc_cycles.max(1);
+    println!("  alloc speedup: {}.{}x", speedup / 10, speedup % 10);
+    println!("  (first-fit emulates the old search cost only)");
+}
+
+fn measure(count: usize, first_fit: bool) -> Option<Timing> {
+    let mut rng = Rng { state: SEED };
+    let holes = unsafe { &mut *core::ptr::addr_of_mut!(HOLES) };
+    let blocks = unsafe { &mut *core::ptr::addr_of_mut!(BLOCKS) };
+
+    // Fragment the heap: small blocks, every other one freed
+    for hole in holes.iter_mut().take(count) {
+        *hole = heap::kmalloc(rng.range(16, 64));
+    }
+    for hole in holes.iter_mut().take(count).step_by(2) {
+        heap::kfree(*hole);
+        *hole = core::ptr::null_mut();
+    }
+    let free_holes = heap::free_block_count();
+
+    heap::set_first_fit(first_fit);
+    let start = rdtsc();
+    for block in blocks.iter_mut().take(count) {
+        *block = heap::kmalloc(rng.range(64, 512));
+    }
+    let alloc_cycles = rdtsc() - start;
+    heap::set_first_fit(false);
+    let failed = blocks.iter().take(count).any(|b| b.is_null());
+
+    let start = rdtsc();
+    for block in blocks.iter().take(count) {
+        heap::kfree(*block);
+    }
+    let free_cycles = rdtsc() - start;
+
+    for hole in holes.iter_mut().take(count) {
+        heap::kfree(*hole);
+        *hole = core::ptr::null_mut();
+    }
+
+    if failed {
+        println!("\nheapbench: heap exhausted, try a smaller count");
+        return None;
+    }
+    Some(Timing {
+        alloc_cycles,
+        free_cycles,
+        holes: free_holes,
+    })
+}
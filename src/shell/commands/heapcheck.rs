@@ -0,0 +1,39 @@
+// shell/commands/heapcheck.rs
+//
+// Shell command: heapcheck
+//
+// Walks every kernel heap block and every free bin (heap::verify()).
+// Prints block and byte totals when the heap is consistent, otherwise
+// the first corrupt block, what is wrong with it, and the blocks on
+// either side.  Red zones and header magics are only checked in a
+// kernel built with the `heap_harden` feature.
+
+use crate::memory::heap::{self, BlockInfo};
+
+fn print_block(label: &str, block: Option<BlockInfo>) {
+    match block {
+        Some(b) => println!(
+            "  {:<6} {:#010x}  size {:<8} {}",
+            label,
+            b.addr,
+            b.size,
+            if b.free { "free" } else { "used" }
+        ),
+        None => println!("  {:<6} -", label),
+    }
+}
+
+pub fn run(_args: &[&str]) {
+    match heap::verify() {
+        Ok(report) => println!(
+            "\nheap OK: {} block(s), {} free; {} bytes used, {} bytes free",
+            report.blocks, report.free_blocks, report.used_bytes, report.free_bytes
+        ),
+        Err(bad) => {
+            println!("\nheap CORRUPT: {}", bad.reason);
+            print_block("prev", bad.prev);
+            print_block("block", Some(bad.block));
+            print_block("next", bad.next);
+        }
+    }
+}
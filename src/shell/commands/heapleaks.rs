@@ -0,0 +1,135 @@
+// shell/commands/heapleaks.rs
+//
+// Shell command: heapleaks [mark|diff]
+//
+// Lists live kernel allocations grouped by call site: the return
+// addresses of the frames that called the allocator, innermost first
+// (resolve them with addr2line against the kernel binary).  Each group
+// shows the number of allocations, their total size and the tick of the
+// oldest one.  Needs a kernel built with the `alloc_track` feature.
+//
+//   heapleaks        every live allocation
+//   heapleaks mark   start a leak window
+//   heapleaks diff   allocations made since the mark that are still live
+//
+// Examples:
+//   heapleaks mark
+//   snake
+//   heapleaks diff      - empty if snake freed everything it allocated
+
+#[cfg(feature = "alloc_track")]
+use crate::memory::tracking::{self, CALLER_DEPTH};
+
+// Groups shown; the rest are summed into one line.
+#[cfg(feature = "alloc_track")]
+const MAX_SITES: usize = 16;
+
+#[cfg(feature = "alloc_track")]
+#[derive(Clone, Copy)]
+struct Site {
+    callers: [usize; CALLER_DEPTH],
+    count: usize,
+    bytes: usize,
+    oldest_tick: u32,
+}
+
+#[cfg(feature = "alloc_track")]
+pub fn run(args: &[&str]) {
+    let since = match args {
+        [] => None,
+        ["mark"] => {
+            tracking::mark();
+            let (live, _) = tracking::stats();
+            println!("\nheapleaks: mark set ({} allocation(s) live)", live);
+            return;
+        }
+        ["diff"] => match tracking::last_mark() {
+            Some(mark) => Some(mark),
+            None => {
+                println!("\nheapleaks: no mark set, run `heapleaks mark` first");
+                return;
+            }
+        },
+        _ => {
+            println!("\nUsage: heapleaks [mark|diff]");
+            return;
+        }
+    };
+
+    // Group without allocating: the walk runs over the live table
+    let mut sites = [Site {
+        callers: [0; CALLER_DEPTH],
+        count: 0,
+        bytes: 0,
+        oldest_tick: 0,
+    }; MAX_SITES];
+    let mut used = 0;
+    let (mut other_count, mut other_bytes) = (0, 0);
+    tracking::for_each_live(since, |a| {
+        match sites[..used].iter_mut().find(|s| s.callers == a.callers) {
+            Some(site) => {
+                site.count += 1;
+                site.bytes += a.size;
+                site.oldest_tick = site.oldest_tick.min(a.tick);
+            }
+            None if used < MAX_SITES => {
+                sites[used] = Site {
+                    callers: a.callers,
+                    count: 1,
+                    bytes: a.size,
+                    oldest_tick: a.tick,
+                };
+                used += 1;
+            }
+            None => {
+                other_count += 1;
+                other_bytes += a.size;
+            }
+        }
+    });
+
+    if used == 0 {
+        match since {
+            Some(_) => println!("\nheapleaks: nothing allocated since the mark is still live"),
+            None => println!("\nheapleaks: no live allocations tracked"),
+        }
+        return;
+    }
+
+    let sites = &mut sites[..used];
+    sites.sort_unstable_by_key(|s| core::cmp::Reverse(s.bytes));
+    println!("\nCount   Bytes     Oldest  Call site");
+    let (mut total_count, mut total_bytes) = (other_count, other_bytes);
+    for site in sites.iter() {
+        print!(
+            "{:<6}  {:<8}  {:<6}",
+            site.count, site.bytes, site.oldest_tick
+        );
+        for &ret in site.callers.iter().take_while(|&&r| r != 0) {
+            print!("  {:#010x}", ret);
+        }
+        println!();
+        total_count += site.count;
+        total_bytes += site.bytes;
+    }
+    if other_count != 0 {
+        println!("{:<6}  {:<8}  (other call sites)", other_count, other_bytes);
+    }
+
+    let (_, dropped) = tracking::stats();
+    println!(
+        "{} allocation(s), {} bytes{}",
+        total_count,
+        total_bytes,
+        if dropped != 0 {
+            " (table overflowed, some untracked)"
+        } else {
+            ""
+        }
+    );
+}
+
+#[cfg(not(feature = "alloc_track"))]
+pub fn run(_args: &[&str]) {
+    println!("\nheapleaks: kernel built without the alloc_track feature");
+}
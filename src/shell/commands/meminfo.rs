@@ -1,3 +1,4 @@
+use crate::memory::physical::FRAME_ALLOCATOR;
 use crate::multiboot2;
 
 pub fn run(_args: &[&str]) {
@@ -5,6 +6,11 @@ pub fn run(_args: &[&str]) {
         usage()
     }
     multiboot2::meminfo::print_meminfo();
+    unsafe {
+        if let Some(allocator) = FRAME_ALLOCATOR.as_ref() {
+            allocator.print_zone_stats();
+        }
+    }
     // if  _args.len() == 1 && (_args[0] == "-h" || _args[0] == "--help") || _args.len() != 2 {
     //         usage();
     //         return;
@@ -2,17 +2,24 @@ pub mod clear;
 pub mod colors;
 pub mod credits;
 pub mod echo;
+pub mod heapbench;
+pub mod heapcheck;
+pub mod heapleaks;
 pub mod meminfo;
+pub mod pagetable;
 pub mod paint;
 pub mod parse;
 pub mod print_ft_42;
 pub mod print_stack;
 pub mod setkb;
 pub mod shutdown;
+pub mod slabinfo;
+pub mod snake;
 pub mod timerctrl;
 pub mod vfree;
 pub mod vmalloc; // virtual memory allocation demo
+pub mod vmaps;
+pub mod vprotect;
 pub mod vread;
 pub mod vsize;
 pub mod vwrite;
-pub mod snake;
@@ -0,0 +1,175 @@
+// shell/commands/pagetable.rs
+//
+// Shell command: pagetable [start [end]]
+//                pagetable translate <addr>
+//
+// Walks the active page tables through the recursive mapping and prints
+// one line per run of pages with contiguous frames and the same flags:
+// virtual range -> physical range, flags, size and owner.  Runs are cut
+// where the owner changes (kernel section, heap, kmap, physmap, vmalloc
+// region, VMA), otherwise the owner comes from the frame allocator.
+// Ranges are end-exclusive.  The recursive and foreign slots are not
+// listed.
+//
+// `translate` shows every level of the walk for one address instead.
+//
+// Examples:
+//   pagetable
+//   pagetable 0xC0000000 0xC0400000
+//   pagetable translate 0xC0100000
+
+use super::parse::parse_u32;
+use crate::memory::define::{
+    KERNEL_HEAP_END, KERNEL_HEAP_START, KERNEL_OFFSET, KMAP_SLOTS, PAGE_SIZE, PHYSMAP_SIZE,
+};
+use crate::memory::paging::{self, PageEntry};
+use crate::memory::physical::{PhysFrame, FRAME_ALLOCATOR};
+use crate::memory::{sections, vma, vmm};
+
+pub fn run(args: &[&str]) {
+    match args {
+        ["translate", addr] => match parse_u32(addr) {
+            Some(v) => translate(v),
+            None => println!("\npagetable: invalid address '{}'", addr),
+        },
+        [] => dump(0, 1 << 32),
+        [start] => match parse_u32(start) {
+            Some(v) => dump(v, 1 << 32),
+            None => println!("\npagetable: invalid address '{}'", start),
+        },
+        [start, end] => match (parse_u32(start), parse_u32(end)) {
+            (Some(s), Some(e)) if s < e => dump(s, e as u64),
+            _ => println!("\npagetable: invalid range '{} {}'", start, end),
+        },
+        _ => {
+            println!("\nUsage: pagetable [start [end]]");
+            println!("       pagetable translate <addr>");
+            println!("  start, end  limit the dump to [start, end)");
+            println!("  translate   show each level of the walk for addr");
+            println!("\nExample: pagetable 0xC0000000 0xC0400000");
+        }
+    }
+}
+
+fn dump(start: u32, end: u64) {
+    println!("\nVirtual             Physical            Flags  Size     Owner");
+    let mut runs = 0;
+    vmm::for_each_mapping(vmm::VirtAddr::new(start), end, |run| {
+        // Split the run wherever the owner changes
+        let mut virt = run.virt as u64;
+        while virt < run.end() {
+            let (owner, limit) = owner_of(virt as u32, run.phys + (virt - run.virt as u64));
+            let stop = limit.min(run.end());
+            let phys = run.phys + (virt - run.virt as u64);
+            println!(
+                "{:08x}-{:08x} -> {:08x}-{:08x}  {:<5}  {:>6}K{} {}",
+                virt,
+                stop,
+                phys,
+                phys + (stop - virt),
+                run.flags,
+                (stop - virt) / 1024,
+                if run.large { "L" } else { " " },
+                owner
+            );
+            runs += 1;
+            virt = stop;
+        }
+    });
+    println!("{} range(s)  (L = large pages)", runs);
+}
+
+// Who owns the page at `virt`, and where that owner's area ends.
+fn owner_of(virt: u32, phys: u64) -> (&'static str, u64) {
+    if let Some(region) = vmm::find_region(virt) {
+        return (region.owner, region.end() as u64);
+    }
+    if let Some(area) = vma::find(virt) {
+        return (area.name, area.end as u64);
+    }
+    let sections = sections::sections();
+    if let Some(section) = sections.iter().find(|s| s.contains(virt as usize)) {
+        return (section.name, section.end as u64);
+    }
+
+    let virt = virt as usize;
+    if (KERNEL_HEAP_START..KERNEL_HEAP_END).contains(&virt) {
+        return ("heap", KERNEL_HEAP_END as u64);
+    }
+    let kmap = vmm::layout().kmap_base() as usize;
+    if (kmap..kmap + KMAP_SLOTS * PAGE_SIZE).contains(&virt) {
+        return ("kmap", (kmap + KMAP_SLOTS * PAGE_SIZE) as u64);
+    }
+    if (KERNEL_OFFSET..KERNEL_OFFSET + PHYSMAP_SIZE).contains(&virt) {
+        // Runs up to the kernel image or the end of the physmap
+        let limit = sections
+            .iter()
+            .map(|s| s.start)
+            .filter(|&s| s > virt)
+            .min()
+            .unwrap_or(KERNEL_OFFSET + PHYSMAP_SIZE);
+        return ("physmap", limit as u64);
+    }
+
+    let owner = unsafe {
+        FRAME_ALLOCATOR
+            .as_ref()
+            .and_then(|a| a.frame_info(PhysFrame::containing_address(phys as usize)))
+            .map_or("-", |p| p.owner.name())
+    };
+    (owner, u64::MAX)
+}
+
+fn translate(addr: u32) {
+    let walk = vmm::walk(vmm::VirtAddr::new(addr));
+    println!(
+        "\n{:#010x}  ({} paging, CR3 {:#010x})",
+        addr,
+        if paging::pae_enabled() {
+            "PAE"
+        } else {
+            "legacy"
+        },
+        vmm::current_pd_phys()
+    );
+
+    if let Some((index, entry)) = walk.pdpt {
+        print_level("PDPTE", index, entry);
+        if !entry.present() {
+            return;
+        }
+    }
+    print_level("PDE", walk.pde_index, walk.pde);
+    if !walk.pde.present() {
+        return;
+    }
+    match walk.pte {
+        Some((index, entry)) => print_level("PTE", index, entry),
+        None => println!("  large page, no page table"),
+    }
+
+    match walk.phys {
+        Some(phys) => println!("  -> phys {:#010x}", phys.0),
+        None => println!("  -> not mapped"),
+    }
+}
+
+fn print_level(name: &str, index: usize, entry: PageEntry) {
+    if entry.present() {
+        println!(
+            "  {:<5} [{:>4}] = {:#018x}  frame {:#010x}  {}",
+            name,
+            index,
+            entry.value(),
+            entry.address_wide(),
+            entry.flags()
+        );
+    } else {
+        println!(
+            "  {:<5} [{:>4}] = {:#018x}  not present",
+            name,
+            index,
+            entry.value()
+        );
+    }
+}
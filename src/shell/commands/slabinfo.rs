@@ -0,0 +1,38 @@
+// shell/commands/slabinfo.rs
+//
+// Shell command: slabinfo
+//
+// Lists every registered slab cache: object size, live and total
+// objects, slab pages, objects per slab and utilisation (live object
+// bytes over slab page bytes).  Caches that never allocated show zeros.
+// The summary also counts frees the slab layer refused.
+
+use crate::memory::define::PAGE_SIZE;
+use crate::memory::slab;
+
+pub fn run(_args: &[&str]) {
+    println!("\nName            Size  Active   Total  Slabs  Per  Use");
+    let mut caches = 0;
+    let mut slabs = 0;
+    slab::for_each_cache(|cache| {
+        println!(
+            "{:<14}  {:>4}  {:>6}  {:>6}  {:>5}  {:>3}  {:>2}%",
+            cache.name,
+            cache.object_size,
+            cache.active,
+            cache.total,
+            cache.slabs,
+            cache.per_slab,
+            cache.utilisation()
+        );
+        caches += 1;
+        slabs += cache.slabs;
+    });
+    println!(
+        "{} cache(s), {} slab page(s) ({} KB), {} refused free(s)",
+        caches,
+        slabs,
+        slabs * PAGE_SIZE / 1024,
+        slab::bad_frees()
+    );
+}
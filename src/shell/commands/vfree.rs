@@ -3,7 +3,8 @@
 // Shell command: vfree <addr> <size>
 //
 // Unmaps the virtual region [addr, addr+size). Both arguments are rounded
-// up to page boundaries to match vmalloc's behaviour.
+// up to page boundaries to match vmalloc's behaviour, and must match a
+// region listed by `vmaps` exactly.
 //
 // Examples:
 //   vfree 0xD0000000 4096
@@ -47,8 +48,19 @@ pub fn run(args: &[&str]) {
     println!("\nvfree({:#010x}, {} bytes)...", aligned, size);
 
     match vmm::vfree(addr, size) {
-        Ok(freed) => println!("  OK - {} page(s) freed ({} bytes)", freed, freed * 4096),
+        Ok((freed, tables)) => println!(
+            "  OK - {} page(s) freed ({} bytes), {} page table(s) reclaimed",
+            freed,
+            freed * 4096,
+            tables
+        ),
         Err(vmm::VmError::ZeroSize) => println!("  Error: size must be > 0."),
+        Err(vmm::VmError::NotAllocated) => {
+            println!("  Error: no vmalloc region at this address. See vmaps.")
+        }
+        Err(vmm::VmError::PartialRange) => {
+            println!("  Error: range does not match the vmalloc region. See vmaps.")
+        }
         Err(e) => println!("  Error: {:?}", e),
     }
 }
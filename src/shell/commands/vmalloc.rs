@@ -1,52 +1,69 @@
 // shell/commands/vmalloc.rs
 //
-// Shell command: vmalloc <addr> <size>
+// Shell command: vmalloc [addr] <size>
 //
-// Maps `size` bytes of virtual address space starting at `addr`.
+// Maps `size` bytes of virtual address space.  Without an address the
+// kernel picks a free range in the vmalloc window, fenced by guard pages;
+// with one, mapping starts at `addr` instead.
 // Both addr and size are rounded up to the nearest page boundary.
 //
 // Examples:
+//   vmalloc 0x3000
 //   vmalloc 0x1000 4096
 //   vmalloc 0xD0001234 0x3000   - addr rounded up to 0xD0002000
 
 use super::parse::{page_align_up, parse_u32, parse_usize};
+use crate::memory::pageflags::PageFlags;
 use crate::memory::vmm;
 
 pub fn run(args: &[&str]) {
-    if args.len() != 2 {
-        println!("\nUsage: vmalloc <addr> <size>");
-        println!("  addr  virtual address (hex or decimal, rounded up to page boundary)");
-        println!("  size  bytes to map   (rounded up to page boundary)");
-        println!("\nExample: vmalloc 0xD0000000 4096");
-        return;
-    }
-
-    let addr = match parse_u32(args[0]) {
-        Some(v) => v,
-        None => {
-            println!("\nvmalloc: invalid address '{}'", args[0]);
+    let (addr_arg, size_arg) = match args {
+        [size] => (None, *size),
+        [addr, size] => (Some(*addr), *size),
+        _ => {
+            println!("\nUsage: vmalloc [addr] <size>");
+            println!("  addr  virtual address (optional, rounded up to page boundary)");
+            println!("  size  bytes to map   (rounded up to page boundary)");
+            println!("\nExample: vmalloc 4096");
             return;
         }
     };
-    let size = match parse_usize(args[1]) {
+
+    let size = match parse_usize(size_arg) {
         Some(v) => v,
         None => {
-            println!("\nvmalloc: invalid size '{}'", args[1]);
+            println!("\nvmalloc: invalid size '{}'", size_arg);
             return;
         }
     };
+    let flags = PageFlags::PRESENT | PageFlags::WRITABLE;
 
-    let aligned = page_align_up(addr);
-    if aligned != addr {
-        println!(
-            "\nNote: address rounded up {:#010x} -> {:#010x}",
-            addr, aligned
-        );
-    }
-
-    println!("\nvmalloc({:#010x}, {} bytes)...", aligned, size);
+    let result = match addr_arg {
+        None => {
+            println!("\nvmalloc({} bytes)...", size);
+            vmm::vmalloc(size, flags, "shell").map(|base| (base, size.div_ceil(4096)))
+        }
+        Some(arg) => {
+            let addr = match parse_u32(arg) {
+                Some(v) => v,
+                None => {
+                    println!("\nvmalloc: invalid address '{}'", arg);
+                    return;
+                }
+            };
+            let aligned = page_align_up(addr);
+            if aligned != addr {
+                println!(
+                    "\nNote: address rounded up {:#010x} -> {:#010x}",
+                    addr, aligned
+                );
+            }
+            println!("\nvmalloc({:#010x}, {} bytes)...", aligned, size);
+            vmm::vmalloc_at(addr, size, flags, "shell")
+        }
+    };
 
-    match vmm::vmalloc(addr, size) {
+    match result {
         Ok((base, pages)) => {
             let mapped = pages * 4096;
             println!("  OK - {} page(s) mapped ({} bytes)", pages, mapped);
@@ -64,5 +81,9 @@ pub fn run(args: &[&str]) {
         }
         Err(vmm::VmError::OutOfMemory) => println!("  Error: no free physical frames available."),
         Err(vmm::VmError::ZeroSize) => println!("  Error: size must be > 0."),
+        Err(vmm::VmError::NoVirtualSpace) => {
+            println!("  Error: vmalloc window exhausted (no gap large enough).")
+        }
+        Err(e) => println!("  Error: {:?}", e),
     }
 }
@@ -0,0 +1,31 @@
+// shell/commands/vmaps.rs
+//
+// Shell command: vmaps
+//
+// Lists every live vmalloc region, lowest address first, with its size,
+// page flags and owner.  ioremap regions also show the device address.
+
+use crate::memory::vmm;
+
+pub fn run(_args: &[&str]) {
+    println!("\nStart        End          Pages  Flags  Owner");
+    let mut count = 0;
+    let mut pages = 0;
+    vmm::for_each_region(|region| {
+        print!(
+            "{:#010x}   {:#010x}   {:<5}  {:<5}  {}",
+            region.start,
+            region.end(),
+            region.pages,
+            region.flags,
+            region.owner
+        );
+        match region.io_phys {
+            Some(phys) => println!(" (io {:#010x})", phys),
+            None => println!(),
+        }
+        count += 1;
+        pages += region.pages;
+    });
+    println!("{} region(s), {} page(s) mapped", count, pages);
+}
@@ -0,0 +1,84 @@
+// shell/commands/vprotect.rs
+//
+// Shell command: vprotect <addr> <size> <prot>
+//
+// Changes the protection of an already mapped range without touching its
+// frames.  `prot` is a string of letters, "r" alone meaning read-only:
+//   w  writable
+//   u  user accessible
+//   c  cache disabled
+//   x  executable (everything else is no-execute where NX exists)
+// addr is rounded down and size up to page boundaries.
+//
+// Examples:
+//   vprotect 0xD0000000 4096 r     - then `vwrite` page faults
+//   vprotect 0xD0000000 4096 rw
+//   vprotect 0xD0000000 0x3000 rwc
+
+use super::parse::{parse_u32, parse_usize};
+use crate::memory::pageflags::PageFlags;
+use crate::memory::vmm::{self, ProtectError, VirtAddr};
+
+fn parse_prot(s: &str) -> Option<PageFlags> {
+    let mut flags = PageFlags::NO_EXECUTE;
+    for c in s.chars() {
+        match c {
+            'r' => {}
+            'w' => flags = flags | PageFlags::WRITABLE,
+            'u' => flags = flags | PageFlags::USER,
+            'c' => flags = flags | PageFlags::CACHE_DISABLE,
+            'x' => flags = flags & !PageFlags::NO_EXECUTE,
+            _ => return None,
+        }
+    }
+    Some(flags)
+}
+
+pub fn run(args: &[&str]) {
+    if args.len() != 3 {
+        println!("\nUsage: vprotect <addr> <size> <prot>");
+        println!("  addr  start of a mapped range (rounded down to page boundary)");
+        println!("  size  bytes to change       (rounded up to page boundary)");
+        println!("  prot  r, plus w (writable) u (user) c (uncached) x (exec)");
+        println!("\nExample: vprotect 0xD0000000 4096 r");
+        return;
+    }
+
+    let addr = match parse_u32(args[0]) {
+        Some(v) => v & !0xFFF,
+        None => {
+            println!("\nvprotect: invalid address '{}'", args[0]);
+            return;
+        }
+    };
+    // Sizes within a page of usize::MAX cannot be rounded up
+    let size = match parse_usize(args[1]).and_then(|v| v.checked_add(0xFFF)) {
+        Some(v) if v > 0xFFF => v & !0xFFF,
+        _ => {
+            println!("\nvprotect: invalid size '{}'", args[1]);
+            return;
+        }
+    };
+    let prot = match parse_prot(args[2]) {
+        Some(v) => v,
+        None => {
+            println!("\nvprotect: invalid protection '{}'", args[2]);
+            return;
+        }
+    };
+
+    println!("\nvprotect({:#010x}, {} bytes, {})...", addr, size, args[2]);
+
+    match vmm::protect_range(VirtAddr::new(addr), size, prot) {
+        Ok(pages) => println!("  OK - {} page(s) updated", pages),
+        Err(ProtectError::NotMapped) => {
+            println!("  Error: part of the range is not mapped. Nothing changed.")
+        }
+        Err(ProtectError::LargePage) => {
+            println!("  Error: range splits a large page. Nothing changed.")
+        }
+        Err(ProtectError::InvalidAddress) => {
+            println!("  Error: range reaches the reserved top of memory.")
+        }
+    }
+}
@@ -2,8 +2,8 @@
 //
 // Shell command: vsize <addr>
 //
-// Counts consecutive mapped pages from `addr` (rounded up to page boundary)
-// by walking the live page tables. Returns 0 if not mapped.
+// Looks up the vmalloc region containing `addr` (rounded up to page
+// boundary) and prints its size and bounds.
 //
 // Examples:
 //   vsize 0xD0000000
@@ -36,20 +36,20 @@ pub fn run(args: &[&str]) {
         );
     }
 
-    let bytes = vmm::vsize(addr);
-
-    if bytes == 0 {
-        println!("\nvsize({:#010x}): not mapped", aligned);
-    } else {
-        let pages = bytes / 4096;
-        println!(
-            "\nvsize({:#010x}): {} bytes ({} page(s))",
-            aligned, bytes, pages
-        );
-        println!(
-            "  Range: {:#010x} .. {:#010x}",
-            aligned,
-            aligned as usize + bytes
-        );
+    match vmm::find_region(aligned) {
+        None => println!("\nvsize({:#010x}): no vmalloc region", aligned),
+        Some(region) => {
+            let bytes = region.pages * 4096;
+            println!(
+                "\nvsize({:#010x}): {} bytes ({} page(s))",
+                aligned, bytes, region.pages
+            );
+            println!(
+                "  Range: {:#010x} .. {:#010x}  owner: {}",
+                region.start,
+                region.end(),
+                region.owner
+            );
+        }
     }
 }
@@ -7,7 +7,7 @@ pub mod commands;
 use crate::vga::get_current_colors;
 use commands::{paint, *};
 
-const MAX_COMMANDS: usize = 20;
+const MAX_COMMANDS: usize = 32;
 const _MAX_COMMAND_LENGTH: usize = 20;
 const MAX_ARGS: usize = 10;
 static SHELL_ID: &str = "kernel@ring0:/#";
@@ -101,14 +101,20 @@ pub fn init_shell() {
         SHELL.add_command(
             "vmalloc",
             vmalloc::run,
-            "Map virtual pages:    vmalloc <addr> <size>",
+            "Map virtual pages:    vmalloc [addr] <size>",
         );
         SHELL.add_command(
             "vfree",
             vfree::run,
             "Unmap virtual pages:  vfree <addr> <size>",
         );
-        SHELL.add_command("vsize", vsize::run, "Query mapped size:    vsize <addr>");
+        SHELL.add_command("vsize", vsize::run, "Query region size:    vsize <addr>");
+        SHELL.add_command("vmaps", vmaps::run, "List vmalloc regions: vmaps");
+        SHELL.add_command(
+            "pagetable",
+            pagetable::run,
+            "Dump mappings:        pagetable [start [end]] | translate <addr>",
+        );
         SHELL.add_command(
             "vwrite",
             vwrite::run,
@@ -119,13 +125,38 @@ pub fn init_shell() {
             vread::run,
             "Read from virt addr:  vread <addr> [u8|u32|u64]",
         );
+        SHELL.add_command(
+            "heapcheck",
+            heapcheck::run,
+            "Verify heap blocks:   heapcheck",
+        );
+        SHELL.add_command(
+            "heapleaks",
+            heapleaks::run,
+            "Live allocations:     heapleaks [mark|diff]",
+        );
+        SHELL.add_command(
+            "heapbench",
+            heapbench::run,
+            "Heap alloc benchmark: heapbench [count]",
+        );
+        SHELL.add_command("slabinfo", slabinfo::run, "Slab cache usage:     slabinfo");
+        SHELL.add_command(
+            "vprotect",
+            vprotect::run,
+            "Change page rights:   vprotect <addr> <size> <r|w|u|c|x>",
+        );
         SHELL.add_command("setkb", setkb::run, "Swap from QWERTY to AZERTY and back");
         SHELL.add_command(
             "timer",
             timerctrl::run,
             "Timer display: on|off|counter|uptime|beat|status",
         );
-        SHELL.add_command("snake", snake::run, "Classic game making use of all the kernel features");
+        SHELL.add_command(
+            "snake",
+            snake::run,
+            "Classic game making use of all the kernel features",
+        );
         // SHELL.add_command(
         //     "usermode",
         //     crate::syscall::run,
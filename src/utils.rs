@@ -141,6 +141,83 @@ pub fn inw(port: u16) -> u16 {
     result
 }
 
+// Execute CPUID for `leaf` (sub-leaf 0).  Returns [eax, ebx, ecx, edx].
+// EBX is reserved by LLVM on i386, so it is saved around the instruction.
+pub fn cpuid(leaf: u32) -> [u32; 4] {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    unsafe {
+        core::arch::asm!(
+            "mov {tmp:e}, ebx",
+            "cpuid",
+            "xchg {tmp:e}, ebx",
+            tmp = out(reg) ebx,
+            inout("eax") leaf => eax,
+            inout("ecx") 0 => ecx,
+            out("edx") edx,
+            options(nostack, nomem)
+        );
+    }
+    [eax, ebx, ecx, edx]
+}
+
+// Disable interrupts and return the previous EFLAGS, for a critical
+// section that may run with interrupts already off.
+pub fn interrupts_save() -> u32 {
+    let flags: u32;
+    unsafe {
+        core::arch::asm!("pushfd", "pop {}", "cli", out(reg) flags, options(nomem));
+    }
+    flags
+}
+
+// Were interrupts on when interrupts_save() returned `flags`?
+pub fn interrupts_were_enabled(flags: u32) -> bool {
+    flags & (1 << 9) != 0
+}
+
+// Re-enable interrupts if they were on when interrupts_save() ran.
+pub fn interrupts_restore(flags: u32) {
+    if interrupts_were_enabled(flags) {
+        unsafe { core::arch::asm!("sti", options(nomem, nostack)) };
+    }
+}
+
+// Read the time-stamp counter (CPU cycles since reset).
+pub fn rdtsc() -> u64 {
+    let (lo, hi): (u32, u32);
+    unsafe {
+        core::arch::asm!("rdtsc", out("eax") lo, out("edx") hi, options(nostack, nomem));
+    }
+    ((hi as u64) << 32) | lo as u64
+}
+
+/// Read a model-specific register.
+///
+/// # Safety
+/// `msr` must exist on this CPU (check CPUID first); reading an
+/// unknown MSR raises #GP.
+pub unsafe fn rdmsr(msr: u32) -> u64 {
+    let (lo, hi): (u32, u32);
+    core::arch::asm!("rdmsr", in("ecx") msr, out("eax") lo, out("edx") hi, options(nostack, nomem));
+    ((hi as u64) << 32) | lo as u64
+}
+
+/// Write a model-specific register.
+///
+/// # Safety
+/// `msr` must exist on this CPU and accept `value`, otherwise the write
+/// raises #GP.  MSRs such as EFER change how the CPU runs, so the
+/// caller must know the write is safe for the current state.
+pub unsafe fn wrmsr(msr: u32, value: u64) {
+    core::arch::asm!(
+        "wrmsr",
+        in("ecx") msr,
+        in("eax") value as u32,
+        in("edx") (value >> 32) as u32,
+        options(nostack, nomem)
+    );
+}
+
 pub fn send_eoi(irq: u8) {
     if irq >= 8 {
         outb(0xA0, 0x20);